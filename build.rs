@@ -0,0 +1,22 @@
+//! Embeds the git commit as `GIT_HASH` for build-info logging.
+//!
+//! A `GIT_HASH` env var wins (for builds without a `.git` directory, e.g.
+//! Docker); otherwise `git rev-parse` is used, falling back to `unknown`.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let hash = std::env::var("GIT_HASH")
+        .ok()
+        .filter(|h| !h.trim().is_empty())
+        .or_else(|| {
+            let out = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+            out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+}
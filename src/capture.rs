@@ -0,0 +1,130 @@
+//! Optional JSON-lines record of every forward, enabled by `CAPTURE_FILE`.
+//!
+//! Unlike tracing output this keeps the full request, so forwards can be
+//! inspected or replayed offline.
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use reqwest::header::HeaderMap;
+use serde_json::{json, Map, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+use tracing::warn;
+
+use crate::clock::Clock;
+
+/// The capture file, opened for appending at startup.
+#[derive(Debug)]
+pub struct CaptureFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// What was sent, kept until the response (or error) is known.
+pub struct CapturedRequest {
+    message_id: String,
+    target: String,
+    headers: Map<String, Value>,
+    body_base64: String,
+}
+
+impl CapturedRequest {
+    /// Snapshots a request, replacing headers on the redaction list with `***redacted***`.
+    pub fn new(message_id: &str, target: &str, headers: &HeaderMap, body: &[u8], redact: &[String]) -> Self {
+        let mut captured = Map::new();
+        for (name, value) in headers {
+            let value = if redact.iter().any(|r| r == name.as_str()) {
+                "***redacted***".into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            // Repeated headers become arrays, in the order they are sent
+            match captured.get_mut(name.as_str()) {
+                Some(Value::Array(values)) => values.push(value.into()),
+                Some(first) => *first = json!([first.take(), value]),
+                None => {
+                    captured.insert(name.to_string(), value.into());
+                }
+            }
+        }
+        Self {
+            message_id: message_id.into(),
+            target: target.into(),
+            headers: captured,
+            body_base64: general_purpose::STANDARD.encode(body),
+        }
+    }
+}
+
+impl CaptureFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open CAPTURE_FILE {}", path.display()))?;
+        Ok(Self { path: path.into(), file: Mutex::new(file) })
+    }
+
+    /// Appends one line for a forward; `status` and `response_preview` are
+    /// absent when no response arrived, in which case `error` says why.
+    pub fn record(&self, request: CapturedRequest, status: Option<u16>, response_preview: Option<&str>, error: Option<&str>, clock: &dyn Clock) {
+        let line = json!({
+            "unix_ms": clock.now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default(),
+            "message_id": request.message_id,
+            "target": request.target,
+            "request_headers": request.headers,
+            "body_base64": request.body_base64,
+            "status": status,
+            "response_preview": response_preview,
+            "error": error,
+        });
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{line}") {
+            warn!("Failed to write to CAPTURE_FILE {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn appends_redacted_json_lines() {
+        let clock = MockClock::at(UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123));
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", uuid::Uuid::new_v4()));
+        let capture = CaptureFile::open(&path).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-hub-signature-256", "sha256=abc".parse().unwrap());
+        headers.append("x-tag", "a".parse().unwrap());
+        headers.append("x-tag", "b".parse().unwrap());
+        let redact = vec!["x-hub-signature-256".to_string()];
+
+        let request = CapturedRequest::new("m-1", "http://local/webhook", &headers, b"hi", &redact);
+        capture.record(request, Some(200), Some("ok"), None, &clock);
+        let request = CapturedRequest::new("m-2", "http://local/webhook", &headers, b"", &redact);
+        capture.record(request, None, None, Some("connection refused"), &clock);
+
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["unix_ms"], 1_700_000_000_123u64);
+        assert_eq!(lines[0]["request_headers"]["x-hub-signature-256"], "***redacted***");
+        assert_eq!(lines[0]["request_headers"]["x-tag"], json!(["a", "b"]));
+        assert_eq!(lines[0]["body_base64"], "aGk=");
+        assert_eq!(lines[0]["status"], 200);
+        assert_eq!(lines[1]["status"], Value::Null);
+        assert_eq!(lines[1]["error"], "connection refused");
+    }
+}
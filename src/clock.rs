@@ -0,0 +1,58 @@
+//! Time source for everything the relay reads the time for (message age,
+//! `Retry-After` dates, TTLs, rate limits, latencies, timestamps it reports or
+//! signs), so tests can move time instead of sleeping. Sleeps and deadlines use
+//! `tokio::time`, which tests pause with `start_paused`.
+
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// Wall-clock time, for comparing against timestamps from elsewhere.
+    fn now(&self) -> SystemTime;
+    /// Monotonic time, for comparing against earlier readings of this clock.
+    fn instant(&self) -> Instant;
+}
+
+/// The real clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    start: (SystemTime, Instant),
+    elapsed: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Starts at `now`, as wall-clock time.
+    pub fn at(now: SystemTime) -> Self {
+        Self { start: (now, Instant::now()), elapsed: Default::default() }
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.start.0 + *self.elapsed.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start.1 + *self.elapsed.lock().unwrap()
+    }
+}
@@ -0,0 +1,1223 @@
+//! Relay configuration, loaded from an optional TOML file overlaid by env vars.
+//!
+//! Every setting is named after its env var. In the file the same name is
+//! used in lowercase (`QUEUE_URL` → `queue_url`); lists may be TOML arrays or
+//! comma-separated strings. Env vars always win over file values.
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_sdk_sqs::types::MessageSystemAttributeName;
+use reqwest::header::{HeaderName, HeaderValue};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::capture::CaptureFile;
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::DedupCache;
+use crate::decrypt::PayloadKey;
+use crate::extended::PayloadStore;
+use crate::filter::{FilterMode, MessageFilter};
+use crate::notify::Notifier;
+use crate::policy::StatusPolicy;
+use crate::route::RouteConfig;
+use crate::success::SuccessBodyRule;
+use crate::transform::BodyTransform;
+
+/// Headers masked in debug logs unless `REDACT_HEADERS` says otherwise.
+const DEFAULT_REDACT_HEADERS: &[&str] = &["authorization", "x-hub-signature-256", "cookie", "x-relay-signature", "x-sqs-receipt-handle"];
+
+/// Everything the relay needs, built once at startup by [`Config::load`].
+pub struct Config {
+    /// Empty until resolved from `queue_name` at startup when only that is set,
+    /// and unused with `queue_prefix`.
+    pub queue_url: String,
+    /// `QUEUE_NAME`, looked up with `GetQueueUrl` instead of configuring `QUEUE_URL`.
+    pub queue_name: Option<String>,
+    /// Account that owns `queue_name`, if not the caller's.
+    pub queue_owner_account_id: Option<String>,
+    /// `QUEUE_PREFIX`: poll every queue whose name starts with this, found with `ListQueues`.
+    pub queue_prefix: Option<String>,
+    /// How often `queue_prefix` is listed again for new queues; `None` lists only at startup.
+    pub queue_relist_interval: Option<Duration>,
+    /// First `LOCAL_URL`; the only one unless fanning out.
+    pub local_url: String,
+    /// Further `LOCAL_URL`s every message is also forwarded to.
+    pub fanout_urls: Vec<String>,
+    /// URLs (scheme, host, port and path prefix) an `X-Relay-Target` attribute may name.
+    pub target_allowlist: Vec<reqwest::Url>,
+    pub fanout_policy: FanoutPolicy,
+    /// Where relayed messages go.
+    pub sink: SinkKind,
+    /// `KAFKA_BROKERS`: bootstrap servers for `SinkKind::Kafka`.
+    pub kafka_brokers: Option<String>,
+    pub kafka_topic: Option<String>,
+    /// How long a produce may wait for the brokers' ack before the message is retried.
+    pub kafka_ack_timeout: Duration,
+    /// Built at startup when `sink` is Kafka.
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<crate::sink::KafkaSink>,
+    /// Check queue access and local reachability before polling.
+    pub preflight: bool,
+    /// Treat an unreachable local endpoint at preflight as fatal.
+    pub preflight_require_local: bool,
+    pub client: ClientConfig,
+    /// Per-request forward timeout; `None` uses the client timeout.
+    pub forward_timeout: Option<Duration>,
+    /// Bodies at least this large use `large_forward_timeout` when it is set.
+    pub large_body_bytes: usize,
+    pub large_forward_timeout: Option<Duration>,
+    /// Messages whose decoded body exceeds this are not forwarded.
+    pub max_body_bytes: Option<usize>,
+    pub oversize_action: OversizeAction,
+    /// Queue that rejected messages are moved to.
+    pub dlq_url: Option<String>,
+    /// Lowercased header names whose values are masked in debug logs.
+    pub redact_headers: Vec<String>,
+    /// Consecutive local failures (network errors/5xx) before polling pauses.
+    pub circuit_fail_threshold: Option<u32>,
+    pub circuit_probe_interval: Duration,
+    /// Open the circuit at the first connection failure instead of counting to the threshold.
+    pub connect_error_opens_circuit: bool,
+    pub timeout_action: TimeoutAction,
+    /// Successful forwards (or receives) in a row before the circuit's failure
+    /// count (or the receive backoff) is reset.
+    pub success_reset_threshold: u32,
+    /// Probed while the circuit is open; defaults to the forward URL.
+    pub health_url: Option<String>,
+    pub max_forwards_per_sec: Option<f64>,
+    /// Sleep after an SQS receive error, before jitter; doubles on each
+    /// consecutive error up to `error_backoff_max`.
+    pub error_backoff: Duration,
+    pub error_backoff_max: Duration,
+    /// Sleep after SQS throttles a receive, doubling while it keeps doing so
+    /// up to `throttle_backoff_max`; also the pause between polls until it stops.
+    pub throttle_backoff: Duration,
+    pub throttle_backoff_max: Duration,
+    /// Randomizes backoff sleeps by ±this fraction (0.0–1.0).
+    pub jitter_fraction: f64,
+    /// Fraction of the receive visibility timeout a batch may use before the
+    /// rest of it gets its visibility extended; 0 disables.
+    pub visibility_extend_fraction: f64,
+    /// Independent receive loops polling the queue concurrently.
+    pub poller_count: usize,
+    /// Random 0..=N ms delay before each poll, to desynchronize replicas.
+    pub poll_jitter_ms: u64,
+    /// Pause after an empty receive before polling again.
+    pub idle_sleep: Duration,
+    /// Stop after this many messages have been handled.
+    pub max_messages_total: Option<u64>,
+    /// Stop after running this long.
+    pub max_runtime: Option<Duration>,
+    /// Stop at the first empty receive (short polls) instead of long-polling forever.
+    pub drain_and_exit: bool,
+    /// Content-Type used when no `content-type` attribute is present; `None`
+    /// sniffs it from the body.
+    pub default_content_type: Option<HeaderValue>,
+    /// Lowercased media types allowed to be forwarded; empty allows any.
+    pub expected_content_types: Vec<String>,
+    pub content_type_mismatch_action: OversizeAction,
+    /// Top-level keys a JSON body must have (non-null) to be forwarded; empty disables the check.
+    pub required_json_keys: Vec<String>,
+    pub schema_mismatch_action: OversizeAction,
+    pub attr_headers: AttrHeaderOptions,
+    pub previews: PreviewLimits,
+    pub body_transform: Option<BodyTransform>,
+    /// Dotted JSON paths searched for the source IP before the built-in ones.
+    pub ip_json_paths: Vec<String>,
+    /// Repair attribute headers with invalid names/values instead of dropping them.
+    pub sanitize_headers: bool,
+    /// Never parse or scan the body for metadata or the log summary.
+    pub skip_body_inspection: bool,
+    /// Skip source-IP extraction and leave `X-Forwarded-For` as received.
+    pub disable_xff: bool,
+    /// Port for the health server (`/healthz`, `/status`, ...); `None` disables it.
+    pub health_port: Option<u16>,
+    /// Address the health server listens on.
+    pub health_bind_addr: IpAddr,
+    /// Shared config profile to load credentials and region from.
+    pub aws_profile: Option<String>,
+    /// How often queue depth is read for `/metrics`; `None` disables it.
+    pub queue_depth_interval: Option<Duration>,
+    /// Decode base64-looking bodies without a `BodyIsBase64` attribute.
+    pub autodetect_base64: bool,
+    /// Longest to wait at startup for the local endpoint to accept connections.
+    pub startup_wait: Option<Duration>,
+    /// Processed messages kept in memory for the `/recent` endpoint.
+    pub recent_buffer_size: usize,
+    /// Role assumed for SQS calls on top of the base credentials.
+    pub assume_role: Option<AssumeRole>,
+    /// Consecutive credential-related receive errors before the SQS client is rebuilt.
+    pub credential_reload_threshold: u32,
+    /// Forward a JSON envelope of the whole message instead of the raw body.
+    pub envelope_mode: bool,
+    /// Also send every message attribute as JSON in one `X-SQS-Attributes` header.
+    pub attrs_header: bool,
+    /// Send the receipt handle and queue URL as headers and leave deleting
+    /// delivered messages to the consumer.
+    pub expose_receipt: bool,
+    /// Messages matching this are deleted without being forwarded.
+    pub filter: Option<MessageFilter>,
+    /// Attribute holding an epoch-seconds time before which a message is not forwarded.
+    pub deliver_after_attribute: Option<String>,
+    /// Keys of recent successes; repeats within the TTL are deleted without being forwarded.
+    pub dedup: Option<Arc<DedupCache>>,
+    /// Alerts sent when a message is dead-lettered or dropped.
+    pub notify: Option<Arc<Notifier>>,
+    /// Forward each receive batch as one JSON array instead of per message.
+    pub batch_forward: bool,
+    /// Drop messages the local endpoint answers with 404 instead of retrying them.
+    pub delete_on_404: bool,
+    /// What each local response status does to the message.
+    pub status_policy: StatusPolicy,
+    pub signature_policy: SignaturePolicy,
+    /// GitHub webhook secret `X-Hub-Signature-256` is verified against.
+    pub webhook_secret: Option<String>,
+    /// HMAC key for `X-Relay-Signature` on forwards.
+    pub signing_secret: Option<String>,
+    pub forward_compression: ForwardCompression,
+    /// Bodies smaller than this are sent uncompressed.
+    pub compression_min_bytes: usize,
+    pub empty_body_action: EmptyBodyAction,
+    pub non_utf8_action: NonUtf8Action,
+    /// Extra check a 2xx response body must pass before the message is deleted.
+    pub success_body: Option<SuccessBodyRule>,
+    /// `ROUTES`: per-destination overrides, first match wins.
+    pub routes: Vec<RouteConfig>,
+    /// Every setting read to build this config, as given; compared on reload.
+    pub raw_settings: BTreeMap<String, Option<String>>,
+    /// Key for bodies marked encrypted by their `X-Encryption` attribute.
+    pub encryption_key: Option<PayloadKey>,
+    pub decrypt_failure_action: OversizeAction,
+    /// Failed forwards (counted in `X-Relay-Attempts`) before a message goes to the DLQ.
+    pub max_total_attempts: Option<u32>,
+    /// Where every forward's request and response are appended as JSON lines.
+    pub capture: Option<CaptureFile>,
+    /// Fetch SQS Extended Client bodies from S3 instead of forwarding their pointer.
+    pub extended_client: bool,
+    /// Delete offloaded S3 objects once their message has been delivered.
+    pub extended_client_delete_payload: bool,
+    /// Path-style S3 endpoint for offloaded bodies (e.g. LocalStack or MinIO).
+    pub s3_endpoint_url: Option<String>,
+    /// Built at startup from the AWS config when `extended_client` is on.
+    pub payload_store: Option<PayloadStore>,
+    /// Message attributes appended to the forward URL's query string.
+    pub query_attributes: Vec<QueryAttribute>,
+    /// Extra system attributes to request and forward as headers.
+    pub system_attr_headers: Vec<SystemAttrHeader>,
+    /// Time source for message age, `Retry-After` dates and dedup TTLs.
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Settings for the forward HTTP client.
+pub struct ClientConfig {
+    /// Overall client timeout; caps every per-request timeout.
+    pub timeout: Duration,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub http_version: HttpVersion,
+    /// Send attribute headers spelled as their attributes, over plain HTTP/1.1.
+    pub preserve_header_case: bool,
+    /// PEM bundle of extra trusted CAs.
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM client certificate and key for mTLS.
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+    pub danger_accept_invalid_certs: bool,
+    /// HTTP path requested when forwarding over a Unix socket.
+    pub unix_request_path: String,
+    /// Default `User-Agent`; a `user-agent` attribute overrides it per message.
+    pub user_agent: String,
+}
+
+/// HTTP protocol used for forwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// Let reqwest negotiate.
+    Auto,
+    Http1,
+    /// HTTP/2 with prior knowledge.
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "1" | "1.1" | "http1" => Ok(Self::Http1),
+            "2" | "http2" => Ok(Self::Http2),
+            _ => Err("expected 1.1, 2 or auto".into()),
+        }
+    }
+}
+
+/// What to do with a message whose body exceeds `MAX_BODY_BYTES`, or (as
+/// `DECRYPT_FAILURE_ACTION` / `CONTENT_TYPE_MISMATCH_ACTION`) one that fails to
+/// decrypt or has an unexpected content type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizeAction {
+    Delete,
+    Dlq,
+}
+
+impl FromStr for OversizeAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "delete" => Ok(Self::Delete),
+            "dlq" => Ok(Self::Dlq),
+            _ => Err("expected delete or dlq".into()),
+        }
+    }
+}
+
+/// What happens to a message whose forward timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Leave it for redelivery like any network error.
+    Retry,
+    /// Hide it for the error backoff, treating the endpoint as slow rather than down.
+    Backoff,
+}
+
+impl FromStr for TimeoutAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "retry" => Ok(Self::Retry),
+            "backoff" => Ok(Self::Backoff),
+            _ => Err("expected retry or backoff".into()),
+        }
+    }
+}
+
+/// A `QUERY_ATTRIBUTES` entry: `tenant` or `X-Tenant=tenant` (attribute=parameter).
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryAttribute {
+    pub attribute: String,
+    pub param: String,
+}
+
+impl FromStr for QueryAttribute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (attribute, param) = s.split_once('=').unwrap_or((s, s));
+        let (attribute, param) = (attribute.trim(), param.trim());
+        if attribute.is_empty() || param.is_empty() {
+            return Err(format!("expected Attribute or Attribute=param, got '{s}'"));
+        }
+        Ok(Self { attribute: attribute.into(), param: param.into() })
+    }
+}
+
+/// `ASSUME_ROLE_ARN` and its optional STS parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    /// Defaults to a generated `assume-role-provider-*` name.
+    pub session_name: Option<String>,
+    pub external_id: Option<String>,
+}
+
+/// A `SYSTEM_ATTR_HEADERS` entry: `ApproximateReceiveCount=X-SQS-Receive-Count`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemAttrHeader {
+    pub attribute: MessageSystemAttributeName,
+    pub header: HeaderName,
+}
+
+impl FromStr for SystemAttrHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (attribute, header) = s.split_once('=').ok_or_else(|| format!("expected Attribute=Header, got '{s}'"))?;
+        let attribute = MessageSystemAttributeName::values()
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(attribute.trim()))
+            .ok_or_else(|| format!("unknown system attribute '{}'", attribute.trim()))?;
+        let header = HeaderName::from_bytes(header.trim().as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", header.trim()))?;
+        Ok(Self { attribute: MessageSystemAttributeName::from(*attribute), header })
+    }
+}
+
+/// Which fan-out targets must accept a message before it is deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanoutPolicy {
+    All,
+    Any,
+}
+
+impl FromStr for FanoutPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "any" => Ok(Self::Any),
+            _ => Err("expected all or any".into()),
+        }
+    }
+}
+
+/// Output for relayed messages; each has a [`crate::sink::Sink`] implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    Http,
+    /// Produce to `KAFKA_TOPIC` (needs the `kafka` feature).
+    Kafka,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "http" => Ok(Self::Http),
+            "kafka" => Ok(Self::Kafka),
+            _ => Err("expected http or kafka".into()),
+        }
+    }
+}
+
+/// What to do with a message whose decoded body is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyBodyAction {
+    /// Forward zero bytes like any other body.
+    Forward,
+    /// Leave it in the queue for redelivery.
+    Skip,
+    /// Delete it without forwarding.
+    Drop,
+}
+
+impl FromStr for EmptyBodyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward" => Ok(Self::Forward),
+            "skip" => Ok(Self::Skip),
+            "drop" => Ok(Self::Drop),
+            _ => Err("expected forward, skip or drop".into()),
+        }
+    }
+}
+
+/// What to do with a message whose decoded body is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonUtf8Action {
+    /// Forward the bytes as they are.
+    Forward,
+    /// Delete it without forwarding.
+    Drop,
+    /// Forward `{"data_base64": "..."}` as JSON instead.
+    Base64Wrap,
+}
+
+impl FromStr for NonUtf8Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward" => Ok(Self::Forward),
+            "drop" => Ok(Self::Drop),
+            "base64-wrap" | "base64_wrap" => Ok(Self::Base64Wrap),
+            _ => Err("expected forward, drop or base64-wrap".into()),
+        }
+    }
+}
+
+/// How strictly the producer's `X-Hub-Signature-256` is checked before forwarding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Drop messages without a valid signature.
+    Required,
+    /// Verify signatures that are present; warn about missing ones.
+    Optional,
+    /// No checks at all.
+    Off,
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "required" => Ok(Self::Required),
+            "optional" => Ok(Self::Optional),
+            "off" => Ok(Self::Off),
+            _ => Err("expected required, optional or off".into()),
+        }
+    }
+}
+
+/// `Content-Encoding` applied to forwarded bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardCompression {
+    None,
+    Gzip,
+}
+
+impl FromStr for ForwardCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            _ => Err("expected none or gzip".into()),
+        }
+    }
+}
+
+/// How attributes that normalize to the same header name are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateHeaderPolicy {
+    /// Keep the value of the attribute whose original name sorts last.
+    LastWins,
+    /// Keep the value of the attribute whose original name sorts first.
+    FirstWins,
+    /// Send every value as a repeated header, in attribute-name order.
+    Append,
+}
+
+impl FromStr for DuplicateHeaderPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "last" | "last-wins" => Ok(Self::LastWins),
+            "first" | "first-wins" => Ok(Self::FirstWins),
+            "append" => Ok(Self::Append),
+            _ => Err("expected last, first or append".into()),
+        }
+    }
+}
+
+/// How much of a body or response is shown in log previews.
+#[derive(Debug, Clone, Copy)]
+pub struct PreviewLimits {
+    /// Bytes of a text body shown in the message summary.
+    pub summary_len: usize,
+    /// Bytes of the local response body logged.
+    pub response_len: usize,
+    /// Bytes of a binary body shown as hex in the message summary.
+    pub hex_bytes: usize,
+}
+
+/// How message attribute names are turned into header names.
+#[derive(Debug)]
+pub struct AttrHeaderOptions {
+    pub duplicate_policy: DuplicateHeaderPolicy,
+    /// Lowercased prefix removed from attribute names (applied first).
+    pub strip_prefix: Option<String>,
+    /// Lowercased prefix prepended to attribute names (applied after stripping).
+    pub add_prefix: Option<String>,
+    /// Headers kept per message; the rest (by header name order) are dropped.
+    pub max_headers: usize,
+    /// Add an `X-Attr-Type-<name>` header with each attribute's data type.
+    pub type_headers: bool,
+}
+
+impl AttrHeaderOptions {
+    pub fn header_name(&self, attr_name: &str) -> String {
+        let mut name = attr_name.to_ascii_lowercase();
+        if let Some(stripped) = self.strip_prefix.as_deref().and_then(|p| name.strip_prefix(p)) {
+            // Stripping the whole name would leave an invalid empty header name
+            if !stripped.is_empty() {
+                name = stripped.to_string();
+            }
+        }
+        match &self.add_prefix {
+            Some(prefix) => format!("{prefix}{name}"),
+            None => name,
+        }
+    }
+}
+
+impl Config {
+    pub fn forward_timeout_for(&self, body_len: usize) -> Option<Duration> {
+        match self.large_forward_timeout {
+            Some(t) if body_len >= self.large_body_bytes => Some(t),
+            _ => self.forward_timeout,
+        }
+    }
+
+    /// The first route `url` falls under.
+    pub fn route_for(&self, url: &str) -> Option<&RouteConfig> {
+        self.routes.iter().find(|route| route.matches(url))
+    }
+}
+
+impl Config {
+    /// Builds the config from the TOML file at `path` (if any) and the environment.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let settings = match path {
+            Some(path) => Settings::from_file(path)?,
+            None => Settings::default(),
+        };
+        let mut config = Self::from_settings(&settings)?;
+        settings.warn_unused();
+        config.raw_settings = settings.values.take();
+        Ok(config)
+    }
+
+    /// Loads the config again for a SIGHUP reload. Settings only applied at
+    /// startup keep their current values, and changing them logs that a
+    /// restart is needed.
+    pub fn reload(&self, path: Option<&Path>) -> Result<Self> {
+        let mut config = Self::load(path)?;
+        let names: BTreeSet<&String> = self.raw_settings.keys().chain(config.raw_settings.keys()).collect();
+        let (restart, applied): (Vec<&str>, Vec<&str>) = names
+            .into_iter()
+            .filter(|name| self.raw_settings.get(*name).cloned().flatten() != config.raw_settings.get(*name).cloned().flatten())
+            .map(String::as_str)
+            .partition(|name| RESTART_SETTINGS.contains(name));
+        for name in &restart {
+            warn!("{name} changed but only takes effect after a restart; keeping the current value");
+        }
+        if applied.is_empty() {
+            info!("🔁 Reloaded config: no changes to apply");
+        } else {
+            info!("🔁 Reloaded config: applying {} changed setting(s): {}", applied.len(), applied.join(", "));
+        }
+        config.keep_startup_settings(self);
+        Ok(config)
+    }
+
+    /// Copies what the running relay was started with and still reads.
+    fn keep_startup_settings(&mut self, current: &Config) {
+        self.queue_url.clone_from(&current.queue_url);
+        self.queue_name.clone_from(&current.queue_name);
+        self.queue_owner_account_id.clone_from(&current.queue_owner_account_id);
+        self.queue_prefix.clone_from(&current.queue_prefix);
+        self.queue_relist_interval = current.queue_relist_interval;
+        self.local_url.clone_from(&current.local_url);
+        self.fanout_urls.clone_from(&current.fanout_urls);
+        // Credentials are rebuilt from the live config after repeated auth errors
+        self.aws_profile.clone_from(&current.aws_profile);
+        self.assume_role.clone_from(&current.assume_role);
+        self.health_port = current.health_port;
+        self.health_bind_addr = current.health_bind_addr;
+        self.queue_depth_interval = current.queue_depth_interval;
+        self.recent_buffer_size = current.recent_buffer_size;
+        self.poller_count = current.poller_count;
+        self.circuit_fail_threshold = current.circuit_fail_threshold;
+        self.success_reset_threshold = current.success_reset_threshold;
+        self.health_url.clone_from(&current.health_url);
+        self.max_forwards_per_sec = current.max_forwards_per_sec;
+        self.max_runtime = current.max_runtime;
+        self.drain_and_exit = current.drain_and_exit;
+        self.extended_client = current.extended_client;
+        self.extended_client_delete_payload = current.extended_client_delete_payload;
+        self.dedup.clone_from(&current.dedup);
+        self.notify.clone_from(&current.notify);
+        self.payload_store.clone_from(&current.payload_store);
+        self.sink = current.sink;
+        self.kafka_brokers.clone_from(&current.kafka_brokers);
+        self.kafka_topic.clone_from(&current.kafka_topic);
+        self.kafka_ack_timeout = current.kafka_ack_timeout;
+        #[cfg(feature = "kafka")]
+        self.kafka.clone_from(&current.kafka);
+        self.clock = current.clock.clone();
+    }
+
+    fn from_settings(s: &Settings) -> Result<Self> {
+        let error_backoff = Duration::from_secs_f64(s.parse("ERROR_BACKOFF_SECS")?.unwrap_or(2.0));
+        let error_backoff_max = Duration::from_secs_f64(s.parse("ERROR_BACKOFF_MAX_SECS")?.unwrap_or(60.0));
+        let delete_on_404 = s.flag("DELETE_ON_404", true);
+        let status_policy = StatusPolicy::new(&s.list("STATUS_POLICY").unwrap_or_default(), delete_on_404, (error_backoff, error_backoff_max))
+            .map_err(|e| anyhow!("invalid STATUS_POLICY: {e}"))?;
+        let client_timeout = Duration::from_secs(s.parse("HTTP_CLIENT_TIMEOUT_SECS")?.unwrap_or(20));
+        let client = ClientConfig {
+            timeout: client_timeout,
+            pool_max_idle_per_host: s.parse("HTTP_POOL_MAX_IDLE_PER_HOST")?,
+            pool_idle_timeout: s.parse("HTTP_POOL_IDLE_TIMEOUT_SECS")?.map(Duration::from_secs),
+            http_version: s.parse("HTTP_VERSION")?.unwrap_or(HttpVersion::Auto),
+            preserve_header_case: s.flag("PRESERVE_HEADER_CASE", false),
+            ca_bundle: s.get("LOCAL_CA_BUNDLE").map(PathBuf::from),
+            client_identity: match (s.get("LOCAL_CLIENT_CERT"), s.get("LOCAL_CLIENT_KEY")) {
+                (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+                (None, None) => None,
+                _ => bail!("LOCAL_CLIENT_CERT and LOCAL_CLIENT_KEY must be set together"),
+            },
+            danger_accept_invalid_certs: s.flag("DANGER_ACCEPT_INVALID_CERTS", false),
+            unix_request_path: s.get("LOCAL_UNIX_REQUEST_PATH").unwrap_or_else(|| "/".into()),
+            user_agent: s.get("FORWARD_USER_AGENT")
+                .unwrap_or_else(|| concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).into()),
+        };
+
+        let mut local_urls = s.list("LOCAL_URL").unwrap_or_default().into_iter();
+        let config = Self {
+            queue_url: match (s.get("QUEUE_URL"), s.get("QUEUE_NAME"), s.get("QUEUE_PREFIX")) {
+                (Some(url), None, None) => url,
+                (None, Some(_), None) | (None, None, Some(_)) => String::new(),
+                (None, None, None) => bail!("missing QUEUE_URL (or QUEUE_NAME or QUEUE_PREFIX)"),
+                _ => bail!("set only one of QUEUE_URL, QUEUE_NAME and QUEUE_PREFIX"),
+            },
+            queue_name: s.get("QUEUE_NAME"),
+            queue_owner_account_id: s.get("QUEUE_OWNER_ACCOUNT_ID"),
+            queue_prefix: s.get("QUEUE_PREFIX"),
+            queue_relist_interval: s.parse::<u64>("QUEUE_RELIST_SECS")?.filter(|secs| *secs > 0).map(Duration::from_secs),
+            local_url: local_urls.next().unwrap_or_else(|| "http://127.0.0.1:3000/webhook".into()),
+            fanout_urls: local_urls.collect(),
+            target_allowlist: s.list("TARGET_URL_ALLOWLIST")
+                .unwrap_or_default()
+                .iter()
+                .map(|url| reqwest::Url::parse(url).map_err(|e| anyhow!("invalid TARGET_URL_ALLOWLIST entry '{url}': {e}")))
+                .collect::<Result<_>>()?,
+            raw_settings: BTreeMap::new(),
+            fanout_policy: s.parse("FANOUT_POLICY")?.unwrap_or(FanoutPolicy::All),
+            sink: s.parse("SINK")?.unwrap_or(SinkKind::Http),
+            kafka_brokers: s.get("KAFKA_BROKERS"),
+            kafka_topic: s.get("KAFKA_TOPIC"),
+            kafka_ack_timeout: Duration::from_secs(s.parse("KAFKA_ACK_TIMEOUT_SECS")?.unwrap_or(30)),
+            #[cfg(feature = "kafka")]
+            kafka: None,
+            preflight: s.flag("PREFLIGHT", true),
+            preflight_require_local: s.flag("PREFLIGHT_REQUIRE_LOCAL", false),
+            client,
+            forward_timeout: s.parse("FORWARD_TIMEOUT_SECS")?
+                .map(|secs| Duration::from_secs(secs).min(client_timeout)),
+            large_body_bytes: s.parse("FORWARD_LARGE_BODY_BYTES")?.unwrap_or(1024 * 1024),
+            large_forward_timeout: s.parse("FORWARD_LARGE_TIMEOUT_SECS")?
+                .map(|secs| Duration::from_secs(secs).min(client_timeout)),
+            max_body_bytes: s.parse("MAX_BODY_BYTES")?,
+            oversize_action: s.parse("OVERSIZE_ACTION")?.unwrap_or(OversizeAction::Delete),
+            dlq_url: s.get("DLQ_URL"),
+            redact_headers: s.list("REDACT_HEADERS")
+                .unwrap_or_else(|| DEFAULT_REDACT_HEADERS.iter().map(|h| h.to_string()).collect())
+                .into_iter()
+                .map(|h| h.to_ascii_lowercase())
+                .collect(),
+            circuit_fail_threshold: s.parse("CIRCUIT_FAIL_THRESHOLD")?,
+            circuit_probe_interval: Duration::from_secs(s.parse("CIRCUIT_PROBE_INTERVAL_SECS")?.unwrap_or(5)),
+            connect_error_opens_circuit: s.flag("CONNECT_ERROR_OPENS_CIRCUIT", false),
+            timeout_action: s.parse("TIMEOUT_ACTION")?.unwrap_or(TimeoutAction::Retry),
+            success_reset_threshold: s.parse::<u32>("SUCCESS_RESET_THRESHOLD")?.unwrap_or(1).max(1),
+            health_url: s.get("LOCAL_HEALTH_URL"),
+            max_forwards_per_sec: s.parse::<f64>("MAX_FORWARDS_PER_SEC")?.filter(|r| *r > 0.0),
+            error_backoff,
+            error_backoff_max,
+            throttle_backoff: Duration::from_secs_f64(s.parse("THROTTLE_BACKOFF_SECS")?.unwrap_or(5.0)),
+            throttle_backoff_max: Duration::from_secs_f64(s.parse("THROTTLE_BACKOFF_MAX_SECS")?.unwrap_or(120.0)),
+            jitter_fraction: s.parse::<f64>("JITTER_FRACTION")?.unwrap_or(0.1).clamp(0.0, 1.0),
+            visibility_extend_fraction: s.parse::<f64>("VISIBILITY_EXTEND_FRACTION")?.unwrap_or(0.5).clamp(0.0, 1.0),
+            poller_count: s.parse::<usize>("POLLER_COUNT")?.unwrap_or(1).max(1),
+            poll_jitter_ms: s.parse("POLL_JITTER_MS")?.unwrap_or(0),
+            idle_sleep: Duration::from_millis(s.parse("IDLE_SLEEP_MS")?.unwrap_or(0)),
+            max_messages_total: s.parse("MAX_MESSAGES_TOTAL")?,
+            max_runtime: s.parse("MAX_RUNTIME_SECS")?.map(Duration::from_secs),
+            drain_and_exit: s.flag("DRAIN_AND_EXIT", false),
+            default_content_type: s.parse("DEFAULT_CONTENT_TYPE")?,
+            expected_content_types: s.list("EXPECTED_CONTENT_TYPES")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|t| t.to_ascii_lowercase())
+                .collect(),
+            content_type_mismatch_action: s.parse("CONTENT_TYPE_MISMATCH_ACTION")?.unwrap_or(OversizeAction::Delete),
+            required_json_keys: s.list("REQUIRED_JSON_KEYS").unwrap_or_default(),
+            schema_mismatch_action: s.parse("SCHEMA_MISMATCH_ACTION")?.unwrap_or(OversizeAction::Delete),
+            attr_headers: AttrHeaderOptions {
+                duplicate_policy: s.parse("DUPLICATE_HEADER_POLICY")?.unwrap_or(DuplicateHeaderPolicy::LastWins),
+                strip_prefix: s.header_prefix("HEADER_STRIP_PREFIX")?,
+                add_prefix: s.header_prefix("HEADER_ADD_PREFIX")?,
+                max_headers: s.parse("MAX_HEADERS")?.unwrap_or(100),
+                type_headers: s.flag("ATTR_TYPE_HEADERS", false),
+            },
+            previews: PreviewLimits {
+                summary_len: s.parse("SUMMARY_PREVIEW_LEN")?.unwrap_or(40),
+                response_len: s.parse("RESPONSE_PREVIEW_LEN")?.unwrap_or(200),
+                hex_bytes: s.parse("HEX_PREVIEW_BYTES")?.unwrap_or(24),
+            },
+            body_transform: s.get("BODY_TRANSFORM").map(|e| BodyTransform::compile(&e)).transpose()?,
+            ip_json_paths: s.list("IP_JSON_PATHS").unwrap_or_default(),
+            sanitize_headers: s.flag("SANITIZE_HEADERS", false),
+            skip_body_inspection: s.flag("SKIP_BODY_INSPECTION", false),
+            disable_xff: s.flag("DISABLE_XFF", false),
+            health_port: s.parse("HEALTH_PORT")?,
+            health_bind_addr: s.parse("HEALTH_BIND_ADDR")?.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            aws_profile: s.get("AWS_PROFILE"),
+            queue_depth_interval: Some(s.parse::<u64>("QUEUE_DEPTH_POLL_SECS")?.unwrap_or(30))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            autodetect_base64: s.flag("AUTODETECT_BASE64", false),
+            startup_wait: s.parse::<u64>("STARTUP_WAIT_SECS")?.filter(|secs| *secs > 0).map(Duration::from_secs),
+            recent_buffer_size: s.parse("RECENT_BUFFER_SIZE")?.unwrap_or(0),
+            assume_role: match (s.get("ASSUME_ROLE_ARN"), s.get("ASSUME_ROLE_SESSION_NAME"), s.get("EXTERNAL_ID")) {
+                (Some(role_arn), session_name, external_id) => Some(AssumeRole { role_arn, session_name, external_id }),
+                (None, None, None) => None,
+                _ => bail!("ASSUME_ROLE_SESSION_NAME and EXTERNAL_ID require ASSUME_ROLE_ARN"),
+            },
+            credential_reload_threshold: s.parse::<u32>("CREDENTIAL_RELOAD_AFTER_ERRORS")?.unwrap_or(3).max(1),
+            envelope_mode: s.flag("ENVELOPE_MODE", false),
+            attrs_header: s.flag("ATTRS_HEADER", false),
+            expose_receipt: s.flag("EXPOSE_RECEIPT", false),
+            filter: match (s.get("FILTER_ATTRIBUTE"), s.get("FILTER_VALUE")) {
+                (Some(attribute), Some(value)) => Some(MessageFilter::new(
+                    attribute,
+                    &value,
+                    s.parse("FILTER_MATCH")?.unwrap_or(FilterMode::Equals),
+                )?),
+                (None, None) => None,
+                _ => bail!("FILTER_ATTRIBUTE and FILTER_VALUE must be set together"),
+            },
+            deliver_after_attribute: s.get("DELIVER_AFTER_ATTRIBUTE"),
+            dedup: s
+                .parse::<u64>("DEDUP_TTL_SECS")?
+                .filter(|secs| *secs > 0)
+                .map(|secs| Arc::new(DedupCache::new(Duration::from_secs(secs), s.get("DEDUP_ATTRIBUTE")))),
+            notify: match s.get("NOTIFY_URL") {
+                Some(url) => {
+                    let url = reqwest::Url::parse(&url).with_context(|| format!("invalid NOTIFY_URL '{url}'"))?;
+                    let min_interval = Duration::from_secs(s.parse("NOTIFY_MIN_INTERVAL_SECS")?.unwrap_or(60));
+                    Some(Arc::new(Notifier::new(url, min_interval)))
+                }
+                None => None,
+            },
+            batch_forward: s.flag("BATCH_FORWARD", false),
+            delete_on_404,
+            status_policy,
+            signature_policy: s.parse("SIGNATURE_POLICY")?.unwrap_or(SignaturePolicy::Optional),
+            webhook_secret: s.get("WEBHOOK_SECRET"),
+            signing_secret: s.get("RELAY_SIGNING_SECRET"),
+            forward_compression: s.parse("FORWARD_COMPRESSION")?.unwrap_or(ForwardCompression::None),
+            compression_min_bytes: s.parse("FORWARD_COMPRESSION_MIN_BYTES")?.unwrap_or(1024),
+            empty_body_action: s.parse("EMPTY_BODY_ACTION")?.unwrap_or(EmptyBodyAction::Forward),
+            non_utf8_action: s.parse("NON_UTF8_ACTION")?.unwrap_or(NonUtf8Action::Forward),
+            success_body: s.parse("SUCCESS_BODY_JSONPATH")?,
+            routes: s.json("ROUTES")?
+                .map(|routes| match routes {
+                    serde_json::Value::Array(entries) => Ok(entries),
+                    _ => Err(anyhow!("invalid ROUTES: expected a list of routes")),
+                })
+                .transpose()?
+                .unwrap_or_default()
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    RouteConfig::parse(entry, delete_on_404, (error_backoff, error_backoff_max))
+                        .map_err(|e| anyhow!("invalid ROUTES entry {}: {e}", i + 1))
+                })
+                .collect::<Result<_>>()?,
+            encryption_key: s.parse("ENCRYPTION_KEY")?,
+            decrypt_failure_action: s.parse("DECRYPT_FAILURE_ACTION")?.unwrap_or(OversizeAction::Delete),
+            max_total_attempts: s.parse("MAX_TOTAL_ATTEMPTS")?,
+            capture: s.get("CAPTURE_FILE").map(|path| CaptureFile::open(Path::new(&path))).transpose()?,
+            extended_client: s.flag("EXTENDED_CLIENT", false),
+            extended_client_delete_payload: s.flag("EXTENDED_CLIENT_DELETE_PAYLOAD", false),
+            s3_endpoint_url: s.get("S3_ENDPOINT_URL"),
+            payload_store: None,
+            query_attributes: s.list("QUERY_ATTRIBUTES")
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| entry.parse())
+                .collect::<Result<_, String>>()
+                .map_err(|e| anyhow!("invalid QUERY_ATTRIBUTES: {e}"))?,
+            system_attr_headers: s.list("SYSTEM_ATTR_HEADERS")
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| entry.parse())
+                .collect::<Result<_, String>>()
+                .map_err(|e| anyhow!("invalid SYSTEM_ATTR_HEADERS: {e}"))?,
+            clock: Arc::new(SystemClock),
+        };
+
+        if config.client.preserve_header_case {
+            // HTTP/2 requires lowercase header names on the wire
+            if config.client.http_version == HttpVersion::Http2 {
+                bail!("PRESERVE_HEADER_CASE requires HTTP/1; HTTP_VERSION=2 always sends lowercase names");
+            }
+            let mut urls = std::iter::once(config.local_url.as_str())
+                .chain(config.fanout_urls.iter().map(String::as_str))
+                .chain(config.target_allowlist.iter().map(reqwest::Url::as_str));
+            if let Some(url) = urls.find(|url| !url.starts_with("http://")) {
+                bail!("PRESERVE_HEADER_CASE only forwards to http:// URLs, not {url}");
+            }
+        }
+        if config.max_body_bytes.is_some() && config.oversize_action == OversizeAction::Dlq && config.dlq_url.is_none() {
+            bail!("OVERSIZE_ACTION=dlq requires DLQ_URL");
+        }
+        // Each poller (and each QUEUE_PREFIX queue) keeps its own tally, so a shared total can't be enforced exactly
+        if config.poller_count > 1 && config.max_messages_total.is_some() {
+            bail!("MAX_MESSAGES_TOTAL requires POLLER_COUNT=1");
+        }
+        if config.queue_prefix.is_some() && config.max_messages_total.is_some() {
+            bail!("MAX_MESSAGES_TOTAL cannot be combined with QUEUE_PREFIX");
+        }
+        if config.signature_policy == SignaturePolicy::Required && config.webhook_secret.is_none() {
+            bail!("SIGNATURE_POLICY=required requires WEBHOOK_SECRET");
+        }
+        if config.status_policy.uses_dlq() && config.dlq_url.is_none() {
+            bail!("STATUS_POLICY with a dlq action requires DLQ_URL");
+        }
+        if config.routes.iter().filter_map(|r| r.status_policy.as_ref()).any(StatusPolicy::uses_dlq) && config.dlq_url.is_none() {
+            bail!("a ROUTES status_policy with a dlq action requires DLQ_URL");
+        }
+        if config.decrypt_failure_action == OversizeAction::Dlq && config.dlq_url.is_none() {
+            bail!("DECRYPT_FAILURE_ACTION=dlq requires DLQ_URL");
+        }
+        if !config.expected_content_types.is_empty()
+            && config.content_type_mismatch_action == OversizeAction::Dlq
+            && config.dlq_url.is_none()
+        {
+            bail!("CONTENT_TYPE_MISMATCH_ACTION=dlq requires DLQ_URL");
+        }
+        if !config.required_json_keys.is_empty()
+            && config.schema_mismatch_action == OversizeAction::Dlq
+            && config.dlq_url.is_none()
+        {
+            bail!("SCHEMA_MISMATCH_ACTION=dlq requires DLQ_URL");
+        }
+        if !config.fanout_urls.is_empty() {
+            if config.batch_forward {
+                bail!("BATCH_FORWARD does not support multiple LOCAL_URLs");
+            }
+            if config.local_url.starts_with("unix://") || config.fanout_urls.iter().any(|u| u.starts_with("unix://")) {
+                bail!("unix:// LOCAL_URLs cannot be combined with fan-out");
+            }
+        }
+        if config.sink == SinkKind::Kafka {
+            if !cfg!(feature = "kafka") {
+                bail!("SINK=kafka requires a build with --features kafka");
+            }
+            if config.kafka_brokers.is_none() || config.kafka_topic.is_none() {
+                bail!("SINK=kafka requires KAFKA_BROKERS and KAFKA_TOPIC");
+            }
+            // Each of these posts to, probes, or hands the message to an HTTP endpoint
+            if config.batch_forward
+                || !config.fanout_urls.is_empty()
+                || config.expose_receipt
+                || !config.target_allowlist.is_empty()
+                || config.circuit_fail_threshold.is_some()
+            {
+                bail!("SINK=kafka cannot be combined with BATCH_FORWARD, multiple LOCAL_URLs, EXPOSE_RECEIPT, TARGET_URL_ALLOWLIST or CIRCUIT_FAIL_THRESHOLD");
+            }
+        }
+        if config.queue_prefix.is_some() && config.envelope_mode {
+            // The envelope names a single queue
+            bail!("ENVELOPE_MODE cannot be combined with QUEUE_PREFIX");
+        }
+        if config.expose_receipt {
+            // X-SQS-Queue-Url names a single queue, and a batch has no per-message headers
+            if config.queue_prefix.is_some() {
+                bail!("EXPOSE_RECEIPT cannot be combined with QUEUE_PREFIX");
+            }
+            if config.batch_forward {
+                bail!("EXPOSE_RECEIPT cannot be combined with BATCH_FORWARD");
+            }
+        }
+        if !config.target_allowlist.is_empty() && config.local_url.starts_with("unix://") {
+            bail!("TARGET_URL_ALLOWLIST cannot be combined with a unix:// LOCAL_URL");
+        }
+        if config.max_total_attempts.is_some() {
+            if config.dlq_url.is_none() {
+                bail!("MAX_TOTAL_ATTEMPTS requires DLQ_URL");
+            }
+            // Re-enqueued copies would lose their place in the message group
+            if config.queue_url.ends_with(".fifo") || config.queue_name.as_deref().is_some_and(|n| n.ends_with(".fifo")) {
+                bail!("MAX_TOTAL_ATTEMPTS is not supported for FIFO queues");
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Builds a config from `(ENV_VAR, value)` pairs, ignoring the real environment.
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Result<Self> {
+        let env: std::collections::HashMap<String, String> =
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Self::from_settings(&Settings::new(toml::Table::new(), move |name| env.get(name).cloned()))
+    }
+}
+
+/// Settings read only while starting up: connections, listeners and pollers
+/// built from them, and state that must survive a reload.
+const RESTART_SETTINGS: &[&str] = &[
+    "QUEUE_URL", "QUEUE_NAME", "QUEUE_OWNER_ACCOUNT_ID", "QUEUE_PREFIX", "QUEUE_RELIST_SECS", "LOCAL_URL",
+    "HTTP_CLIENT_TIMEOUT_SECS", "HTTP_POOL_MAX_IDLE_PER_HOST", "HTTP_POOL_IDLE_TIMEOUT_SECS", "HTTP_VERSION",
+    "PRESERVE_HEADER_CASE", "LOCAL_CA_BUNDLE", "LOCAL_CLIENT_CERT", "LOCAL_CLIENT_KEY", "DANGER_ACCEPT_INVALID_CERTS",
+    "LOCAL_UNIX_REQUEST_PATH", "FORWARD_USER_AGENT", "AWS_PROFILE", "ASSUME_ROLE_ARN", "ASSUME_ROLE_SESSION_NAME",
+    "EXTERNAL_ID", "HEALTH_PORT", "HEALTH_BIND_ADDR", "QUEUE_DEPTH_POLL_SECS", "RECENT_BUFFER_SIZE", "POLLER_COUNT",
+    "CIRCUIT_FAIL_THRESHOLD", "SUCCESS_RESET_THRESHOLD", "LOCAL_HEALTH_URL", "MAX_FORWARDS_PER_SEC",
+    "MAX_RUNTIME_SECS", "DRAIN_AND_EXIT", "EXTENDED_CLIENT", "EXTENDED_CLIENT_DELETE_PAYLOAD", "S3_ENDPOINT_URL",
+    "DEDUP_TTL_SECS", "DEDUP_ATTRIBUTE", "NOTIFY_URL", "NOTIFY_MIN_INTERVAL_SECS", "SINK", "KAFKA_BROKERS",
+    "KAFKA_TOPIC", "KAFKA_ACK_TIMEOUT_SECS",
+];
+
+/// The config in effect, replaced whole on reload so each batch is handled
+/// under one version from start to finish.
+pub struct LiveConfig(RwLock<Arc<Config>>);
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        Self(RwLock::new(Arc::new(config)))
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    }
+}
+
+/// Reads an env var by name.
+type EnvLookup = Box<dyn Fn(&str) -> Option<String>>;
+
+/// Raw setting lookup: env vars first, then the (lowercased) key in the file.
+struct Settings {
+    file: toml::Table,
+    env: EnvLookup,
+    /// File keys that were looked up, to report typos.
+    used: RefCell<HashSet<String>>,
+    /// Every setting looked up and its raw value, to tell what a reload changed.
+    values: RefCell<BTreeMap<String, Option<String>>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new(toml::Table::new(), |name| std::env::var(name).ok())
+    }
+}
+
+impl Settings {
+    fn new(file: toml::Table, env: impl Fn(&str) -> Option<String> + 'static) -> Self {
+        Self { file, env: Box::new(env), used: RefCell::default(), values: RefCell::default() }
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let file: toml::Table = toml::from_str(&text)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+        Ok(Self { file, ..Self::default() })
+    }
+
+    /// Returns the raw value; unset or empty yields `None`.
+    fn get(&self, name: &str) -> Option<String> {
+        let value = self.lookup(name);
+        self.values.borrow_mut().insert(name.to_string(), value.clone());
+        value
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        if let Some(v) = (self.env)(name).filter(|v| !v.trim().is_empty()) {
+            return Some(v);
+        }
+        let key = name.to_ascii_lowercase();
+        let value = self.file.get(&key)?;
+        self.used.borrow_mut().insert(key);
+        let text = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        };
+        Some(text).filter(|v| !v.trim().is_empty())
+    }
+
+    /// Parses an optional value.
+    fn parse<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(name)
+            .map(|v| v.trim().parse().map_err(|e| anyhow!("invalid {name} '{}': {e}", v.trim())))
+            .transpose()
+    }
+
+    /// `true`/`1`/`yes` or `false`/`0`/`no` (case-insensitive); anything else yields `default`.
+    fn flag(&self, name: &str, default: bool) -> bool {
+        match self.get(name).map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            Some("true" | "1" | "yes") => true,
+            Some("false" | "0" | "no") => false,
+            _ => default,
+        }
+    }
+
+    /// A structured value: JSON from the environment, or the file's TOML as is
+    /// (e.g. an array of tables).
+    fn json(&self, name: &str) -> Result<Option<serde_json::Value>> {
+        let value = if let Some(v) = (self.env)(name).filter(|v| !v.trim().is_empty()) {
+            serde_json::from_str(&v).map_err(|e| anyhow!("invalid {name}: {e}"))?
+        } else {
+            let key = name.to_ascii_lowercase();
+            let Some(value) = self.file.get(&key) else {
+                self.values.borrow_mut().insert(name.to_string(), None);
+                return Ok(None);
+            };
+            self.used.borrow_mut().insert(key);
+            serde_json::to_value(value)?
+        };
+        self.values.borrow_mut().insert(name.to_string(), Some(value.to_string()));
+        Ok(Some(value))
+    }
+
+    /// Comma-separated list; empty items are skipped.
+    fn list(&self, name: &str) -> Option<Vec<String>> {
+        self.get(name).map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    /// Header-name prefix, lowercased and checked to be valid in a header name.
+    fn header_prefix(&self, name: &str) -> Result<Option<String>> {
+        let Some(prefix) = self.get(name) else { return Ok(None); };
+        HeaderName::from_bytes(prefix.as_bytes())
+            .map_err(|_| anyhow!("invalid {name} '{prefix}': not a valid header name prefix"))?;
+        Ok(Some(prefix.to_ascii_lowercase()))
+    }
+
+    fn warn_unused(&self) {
+        let used = self.used.borrow();
+        for key in self.file.keys().filter(|k| !used.contains(*k)) {
+            warn!("Ignoring unknown config file key '{}'", key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn settings(file: &str, env: &[(&str, &str)]) -> Settings {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Settings::new(toml::from_str(file).unwrap(), move |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn env_overrides_file_values() {
+        let s = settings(
+            "queue_url = \"https://file\"\nforward_timeout_secs = 5\nredact_headers = [\"a\", \"b\"]\n",
+            &[("QUEUE_URL", "https://env")],
+        );
+        let config = Config::from_settings(&s).unwrap();
+        assert_eq!(config.queue_url, "https://env");
+        assert_eq!(config.forward_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.redact_headers, ["a", "b"]);
+        assert!(s.used.borrow().contains("forward_timeout_secs"));
+    }
+
+    #[test]
+    fn routes_come_from_env_json_or_file_tables() {
+        let file = "queue_url = \"q\"\n[[routes]]\nurl = \"http://127.0.0.1:4000/stripe\"\ntimeout_secs = 45\nheaders = { X-Team = \"billing\" }\n";
+        let config = Config::from_settings(&settings(file, &[])).unwrap();
+        assert_eq!(config.routes.len(), 1);
+        let route = config.route_for("http://127.0.0.1:4000/stripe/events").unwrap();
+        assert_eq!(route.timeout, Some(Duration::from_secs(45)));
+        assert!(config.route_for("http://127.0.0.1:4000/internal").is_none());
+
+        let env = [("QUEUE_URL", "q"), ("ROUTES", r#"[{"url": "http://internal/"}, {"url": "http://127.0.0.1:4000/"}]"#)];
+        let config = Config::from_settings(&settings(file, &env)).unwrap();
+        assert_eq!(config.route_for("http://127.0.0.1:4000/stripe").unwrap().timeout, None);
+        assert!(Config::from_settings(&settings("", &[("QUEUE_URL", "q"), ("ROUTES", "{}")])).is_err());
+    }
+
+    #[test]
+    fn reload_applies_changes_but_keeps_startup_settings() {
+        let path = std::env::temp_dir().join(format!("relay-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "queue_url = \"https://sqs.test/a\"\nforward_timeout_secs = 5\ndedup_ttl_secs = 60\n\
+             aws_profile = \"prod\"\nassume_role_arn = \"arn:aws:iam::1:role/a\"\nsuccess_reset_threshold = 3\n",
+        )
+        .unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+
+        std::fs::write(
+            &path,
+            "queue_url = \"https://sqs.test/b\"\nforward_timeout_secs = 10\n\
+             aws_profile = \"dev\"\nassume_role_arn = \"arn:aws:iam::1:role/b\"\nsuccess_reset_threshold = 1\n",
+        )
+        .unwrap();
+        let reloaded = config.reload(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.forward_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(reloaded.queue_url, "https://sqs.test/a");
+        assert_eq!(reloaded.aws_profile.as_deref(), Some("prod"));
+        assert_eq!(reloaded.assume_role.as_ref().map(|r| r.role_arn.as_str()), Some("arn:aws:iam::1:role/a"));
+        assert_eq!(reloaded.success_reset_threshold, 3);
+        assert!(Arc::ptr_eq(reloaded.dedup.as_ref().unwrap(), config.dedup.as_ref().unwrap()));
+        assert_eq!(reloaded.raw_settings["FORWARD_TIMEOUT_SECS"].as_deref(), Some("10"));
+
+        let live = LiveConfig::new(config);
+        let before = live.current();
+        live.replace(reloaded);
+        assert_eq!(before.forward_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(live.current().forward_timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let s = settings("queue_url = \"q\"\nhttp_version = \"3\"\n", &[]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("MAX_BODY_BYTES", "lots")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("QUEUE_NAME", "n")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_NAME", "n"), ("QUEUE_PREFIX", "hooks-")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_PREFIX", "hooks-"), ("ENVELOPE_MODE", "true")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_PREFIX", "hooks-"), ("MAX_MESSAGES_TOTAL", "10")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("EXPOSE_RECEIPT", "true"), ("BATCH_FORWARD", "true")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("ROUTES", r#"[{"url": "http://x/", "status_policy": "5xx=dlq"}]"#)]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("SINK", "nats")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("SINK", "kafka"), ("KAFKA_TOPIC", "hooks")]);
+        assert!(Config::from_settings(&s).is_err());
+        let kafka = [("QUEUE_URL", "q"), ("SINK", "kafka"), ("KAFKA_BROKERS", "b:9092"), ("KAFKA_TOPIC", "hooks")];
+        assert_eq!(Config::from_settings(&settings("", &kafka)).is_ok(), cfg!(feature = "kafka"));
+        let s = settings("", &[&kafka[..], &[("BATCH_FORWARD", "true")]].concat());
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("PRESERVE_HEADER_CASE", "true"), ("LOCAL_URL", "https://x/hook")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("PRESERVE_HEADER_CASE", "true"), ("HTTP_VERSION", "2")]);
+        assert!(Config::from_settings(&s).is_err());
+        assert!(Config::from_settings(&settings("", &[("QUEUE_URL", "q"), ("PRESERVE_HEADER_CASE", "true")])).is_ok());
+        let s = settings("", &[("QUEUE_URL", "q"), ("SYSTEM_ATTR_HEADERS", "SenderId=x-sender,Nope=x-nope")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("POLLER_COUNT", "4"), ("MAX_MESSAGES_TOTAL", "10")]);
+        assert!(Config::from_settings(&s).is_err());
+        let s = settings("", &[("QUEUE_URL", "q"), ("EXTERNAL_ID", "x")]);
+        assert!(Config::from_settings(&s).is_err());
+    }
+
+    #[test]
+    fn parses_system_attr_headers() {
+        let s = settings("", &[("QUEUE_URL", "q"), ("SYSTEM_ATTR_HEADERS", "senderid=X-Sender, SentTimestamp=x-sqs-sent-at")]);
+        let config = Config::from_settings(&s).unwrap();
+        let pairs: Vec<_> = config.system_attr_headers.iter().map(|h| (h.attribute.as_str(), h.header.as_str())).collect();
+        assert_eq!(pairs, [("SenderId", "x-sender"), ("SentTimestamp", "x-sqs-sent-at")]);
+    }
+}
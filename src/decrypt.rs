@@ -0,0 +1,92 @@
+//! Client-side decryption of message bodies the producer encrypted with a shared key.
+
+use aws_sdk_sqs::types::Message;
+use base64::{engine::general_purpose, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use std::str::FromStr;
+
+/// Attribute naming the scheme a body was encrypted with; absent or `none` means plaintext.
+pub const ENCRYPTION_ATTRIBUTE: &str = "X-Encryption";
+/// The only scheme understood: AES-256-GCM over `nonce (12 bytes) || ciphertext || tag`.
+pub const AES_256_GCM_SCHEME: &str = "aes-256-gcm";
+
+/// The `ENCRYPTION_KEY`: 32 bytes, base64-encoded in the config.
+pub struct PayloadKey(LessSafeKey);
+
+impl std::fmt::Debug for PayloadKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PayloadKey(***)")
+    }
+}
+
+impl FromStr for PayloadKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = general_purpose::STANDARD.decode(s.trim()).map_err(|_| "expected base64".to_string())?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes)
+            .map_err(|_| format!("expected a {}-byte key, got {} bytes", AES_256_GCM.key_len(), bytes.len()))?;
+        Ok(Self(LessSafeKey::new(key)))
+    }
+}
+
+impl PayloadKey {
+    /// Opens a sealed body. Errors never include any of the body.
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+            return Err(format!("body of {} bytes is too short to be sealed", sealed.len()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "invalid nonce".to_string())?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext_len = self
+            .0
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| "authentication failed (wrong key or tampered body)".to_string())?
+            .len();
+        in_out.truncate(plaintext_len);
+        Ok(in_out)
+    }
+}
+
+/// The message's encryption scheme, if its `X-Encryption` attribute says it is
+/// encrypted. Attribute names are compared case-insensitively, like header names.
+pub fn encryption_scheme(m: &Message) -> Option<&str> {
+    m.message_attributes()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(ENCRYPTION_ATTRIBUTE))
+        .and_then(|(_, v)| v.string_value())
+        .map(str::trim)
+        .filter(|scheme| !scheme.is_empty() && !scheme.eq_ignore_ascii_case("none"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn seal(plaintext: &[u8]) -> Vec<u8> {
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &KEY).unwrap());
+        let nonce = [1u8; NONCE_LEN];
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out).unwrap();
+        [nonce.as_slice(), &in_out].concat()
+    }
+
+    #[test]
+    fn decrypts_sealed_bodies() {
+        let key: PayloadKey = general_purpose::STANDARD.encode(KEY).parse().unwrap();
+        let sealed = seal(br#"{"secret":true}"#);
+        assert_eq!(key.decrypt(&sealed).unwrap(), br#"{"secret":true}"#);
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.decrypt(&tampered).is_err());
+        assert!(key.decrypt(b"short").is_err());
+
+        let other: PayloadKey = general_purpose::STANDARD.encode([8u8; 32]).parse().unwrap();
+        assert!(other.decrypt(&sealed).is_err());
+        assert!(general_purpose::STANDARD.encode([7u8; 16]).parse::<PayloadKey>().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Suppression of messages whose key was forwarded successfully a moment ago,
+//! enabled by `DEDUP_TTL_SECS`.
+
+use aws_sdk_sqs::types::Message;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+/// Expired keys are swept every this many recorded successes.
+const SWEEP_EVERY: usize = 256;
+
+/// Last successful forward per key; keys older than the TTL count as new.
+#[derive(Debug)]
+pub struct DedupCache {
+    ttl: Duration,
+    /// Message attribute holding the key; the SQS message id when `None`.
+    attribute: Option<String>,
+    succeeded: Mutex<HashMap<String, Instant>>,
+}
+
+impl DedupCache {
+    pub fn new(ttl: Duration, attribute: Option<String>) -> Self {
+        Self { ttl, attribute, succeeded: Mutex::default() }
+    }
+
+    /// The message's key, if it has one.
+    pub fn key(&self, m: &Message) -> Option<String> {
+        match &self.attribute {
+            Some(attribute) => m
+                .message_attributes()?
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+                .and_then(|(_, v)| v.string_value())
+                .map(String::from),
+            None => m.message_id().map(String::from),
+        }
+    }
+
+    /// The message's key when it succeeded within the TTL.
+    pub fn recent(&self, m: &Message, clock: &dyn Clock) -> Option<String> {
+        let key = self.key(m)?;
+        let succeeded = self.succeeded.lock().unwrap();
+        let now = clock.instant();
+        succeeded.get(&key).filter(|at| now.duration_since(**at) < self.ttl)?;
+        Some(key)
+    }
+
+    /// Remembers that the message was forwarded successfully.
+    pub fn record(&self, m: &Message, clock: &dyn Clock) {
+        let Some(key) = self.key(m) else {
+            return;
+        };
+        let now = clock.instant();
+        let mut succeeded = self.succeeded.lock().unwrap();
+        succeeded.insert(key, now);
+        if succeeded.len().is_multiple_of(SWEEP_EVERY) {
+            succeeded.retain(|_, at| now.duration_since(*at) < self.ttl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{MockClock, SystemClock};
+    use aws_sdk_sqs::types::MessageAttributeValue;
+
+    fn message(id: &str, delivery: &str) -> Message {
+        let attr = MessageAttributeValue::builder().data_type("String").string_value(delivery).build().unwrap();
+        Message::builder().message_id(id).message_attributes("X-GitHub-Delivery", attr).build()
+    }
+
+    #[test]
+    fn suppresses_recent_successes_by_key() {
+        let clock = SystemClock;
+        let by_id = DedupCache::new(Duration::from_secs(60), None);
+        by_id.record(&message("m-1", "d-1"), &clock);
+        assert_eq!(by_id.recent(&message("m-1", "d-2"), &clock).as_deref(), Some("m-1"));
+        assert_eq!(by_id.recent(&message("m-2", "d-1"), &clock), None);
+
+        let by_delivery = DedupCache::new(Duration::from_secs(60), Some("x-github-delivery".into()));
+        by_delivery.record(&message("m-1", "d-1"), &clock);
+        assert_eq!(by_delivery.recent(&message("m-2", "d-1"), &clock).as_deref(), Some("d-1"));
+        assert_eq!(by_delivery.recent(&Message::builder().message_id("m-3").build(), &clock), None);
+    }
+
+    #[test]
+    fn keys_expire_after_the_ttl() {
+        let clock = MockClock::at(std::time::SystemTime::now());
+        let cache = DedupCache::new(Duration::from_secs(60), None);
+        cache.record(&message("m-1", "d-1"), &clock);
+        clock.advance(Duration::from_secs(59));
+        assert!(cache.recent(&message("m-1", "d-1"), &clock).is_some());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.recent(&message("m-1", "d-1"), &clock), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Errors from handling a single message, and exit codes for fatal ones.
+
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// Why the relay exited early, attached to the error as context and turned into
+/// the process exit code by `main`. Every startup error carries one; an error
+/// without one exits with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ExitKind {
+    #[error("invalid configuration")]
+    Config = 2,
+    #[error("AWS error")]
+    Aws = 3,
+    #[error("local endpoint unreachable")]
+    LocalUnreachable = 4,
+}
+
+#[derive(Debug, Error)]
+pub enum RelayError {
+    /// An SQS call made on behalf of a message failed.
+    #[error("SQS request failed: {0}")]
+    Sqs(String),
+    /// The forward did not get a response (connect error, timeout, ...).
+    #[error("forward failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// A `PRESERVE_HEADER_CASE` forward did not get a response.
+    #[error("forward failed: {1}")]
+    Forward(ForwardErrorKind, String),
+    /// The local endpoint answered with a server error.
+    #[error("local endpoint returned {0}")]
+    Status(StatusCode),
+    /// The message is malformed and cannot be handled at all.
+    #[error("malformed message: {0}")]
+    Decode(String),
+    /// The decoded body exceeds `MAX_BODY_BYTES`.
+    #[error("body of {size} bytes exceeds MAX_BODY_BYTES={max}")]
+    Oversize { size: usize, max: usize },
+    /// The message has already failed `MAX_TOTAL_ATTEMPTS` forwards.
+    #[error("already failed {attempts} forwards (MAX_TOTAL_ATTEMPTS={max})")]
+    AttemptsExceeded { attempts: u32, max: u32 },
+    /// The effective content type is not in `EXPECTED_CONTENT_TYPES`.
+    #[error("unexpected content type '{0}'")]
+    UnexpectedContentType(String),
+    /// The JSON body lacks keys listed in `REQUIRED_JSON_KEYS`.
+    #[error("JSON body is missing required keys: {}", .0.join(", "))]
+    MissingJsonKeys(Vec<String>),
+    /// `X-Hub-Signature-256` is missing or does not match under `SIGNATURE_POLICY`.
+    #[error("signature check failed: {0}")]
+    Signature(&'static str),
+    /// The `X-Relay-Target` attribute names a URL outside `TARGET_URL_ALLOWLIST`.
+    #[error("X-Relay-Target '{0}' is not on TARGET_URL_ALLOWLIST")]
+    TargetNotAllowed(String),
+    /// An SQS Extended Client body could not be fetched from S3.
+    #[error("offloaded payload unavailable: {0}")]
+    Payload(String),
+    /// The body is marked encrypted but could not be decrypted.
+    #[error("decryption failed: {0}")]
+    Decrypt(String),
+    /// The decoded body is empty and `EMPTY_BODY_ACTION` says not to forward it.
+    #[error("empty body")]
+    EmptyBody,
+    /// The decoded body is not UTF-8 and `NON_UTF8_ACTION=drop`.
+    #[error("body is not valid UTF-8")]
+    NonUtf8Body,
+    /// `SINK=kafka` got no produce-ack from the brokers.
+    #[error("Kafka produce failed: {0}")]
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    Produce(String),
+}
+
+/// Why a forward got no response, from the underlying reqwest error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardErrorKind {
+    /// Nothing accepted the connection (refused, unreachable, DNS, TLS, connect timeout).
+    Connect,
+    /// Connected, but no complete response within the timeout.
+    Timeout,
+    /// The request could not be sent (e.g. the connection dropped mid-request).
+    Request,
+    Other,
+}
+
+impl ForwardErrorKind {
+    pub fn of(e: &reqwest::Error) -> Self {
+        // A connect timeout is also a timeout, but means nothing is listening
+        if e.is_connect() {
+            Self::Connect
+        } else if e.is_timeout() {
+            Self::Timeout
+        } else if e.is_request() {
+            Self::Request
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Timeout => "timeout",
+            Self::Request => "request",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl RelayError {
+    /// How the forward failed, if it got no response at all.
+    pub fn forward_error_kind(&self) -> Option<ForwardErrorKind> {
+        match self {
+            Self::Http(e) if e.status().is_none() => Some(ForwardErrorKind::of(e)),
+            Self::Forward(kind, _) => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// Whether the error means the local endpoint looks down.
+    pub fn local_down(&self) -> bool {
+        matches!(self, Self::Http(_) | Self::Forward(..) | Self::Status(_))
+    }
+
+    /// The local endpoint's response status, if it answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Status(status) => Some(*status),
+            Self::Http(e) => e.status(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Bodies the SQS Extended Client offloaded to S3, fetched (and optionally
+//! deleted) with SigV4-signed requests when `EXTENDED_CLIENT` is on.
+
+use anyhow::{anyhow, Result};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_sdk_sqs::config::ProvideCredentials;
+use aws_sdk_sqs::types::Message;
+use aws_sigv4::http_request::{
+    sign, PayloadChecksumKind, PercentEncodingMode, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode,
+};
+use aws_sigv4::sign::v4;
+use reqwest::Method;
+use serde_json::Value;
+use std::fmt;
+use tracing::{debug, warn};
+
+use crate::clock::Clock;
+
+/// Attributes the extended client marks offloaded messages with (current and legacy name).
+const SIZE_ATTRIBUTES: &[&str] = &["ExtendedPayloadSize", "SQSLargePayloadSize"];
+
+/// Where an offloaded body lives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Pointer {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Pointer {
+    /// The pointer an offloaded message carries as its body:
+    /// `["software.amazon.payloadoffloading.PayloadS3Pointer", {"s3BucketName": ..., "s3Key": ...}]`,
+    /// or just the array's object from older clients.
+    pub fn from_message(m: &Message) -> Option<Self> {
+        let attrs = m.message_attributes()?;
+        if !SIZE_ATTRIBUTES.iter().any(|name| attrs.contains_key(*name)) {
+            return None;
+        }
+        let body: Value = serde_json::from_str(m.body()?).ok()?;
+        let pointer = body.as_array()?.iter().find(|v| v.is_object())?;
+        Some(Self {
+            bucket: pointer.get("s3BucketName")?.as_str()?.to_string(),
+            key: pointer.get("s3Key")?.as_str()?.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Reads offloaded bodies from S3 with the relay's own AWS credentials.
+#[derive(Clone)]
+pub struct PayloadStore {
+    http: reqwest::Client,
+    credentials: SharedCredentialsProvider,
+    region: String,
+    /// Path-style endpoint for S3-compatible stores; virtual-hosted AWS S3 when `None`.
+    endpoint: Option<String>,
+    /// Delete the object once its message has been delivered.
+    pub delete_after_forward: bool,
+}
+
+impl PayloadStore {
+    pub fn new(
+        credentials: SharedCredentialsProvider,
+        region: impl Into<String>,
+        endpoint: Option<String>,
+        delete_after_forward: bool,
+    ) -> Self {
+        Self { http: reqwest::Client::new(), credentials, region: region.into(), endpoint, delete_after_forward }
+    }
+
+    /// Uses the credentials and region the SQS client was built with.
+    pub fn from_sdk_config(sdk: &SdkConfig, endpoint: Option<String>, delete_after_forward: bool) -> Result<Self> {
+        let credentials = sdk.credentials_provider().ok_or_else(|| anyhow!("EXTENDED_CLIENT needs AWS credentials"))?;
+        let region = sdk.region().ok_or_else(|| anyhow!("EXTENDED_CLIENT needs an AWS region"))?;
+        Ok(Self::new(credentials, region.to_string(), endpoint, delete_after_forward))
+    }
+
+    /// The object's contents: the message body the producer offloaded.
+    pub async fn fetch(&self, pointer: &S3Pointer, clock: &dyn Clock) -> Result<Vec<u8>, String> {
+        let rsp = self.signed(Method::GET, pointer, clock).await?.send().await.map_err(|e| e.to_string())?;
+        let status = rsp.status();
+        if !status.is_success() {
+            return Err(format!("GET {pointer} returned {status}"));
+        }
+        let body = rsp.bytes().await.map_err(|e| e.to_string())?;
+        debug!("Fetched {} bytes from {}", body.len(), pointer);
+        Ok(body.to_vec())
+    }
+
+    /// Deletes a delivered message's object; failures are only logged, since the
+    /// message itself is already gone.
+    pub async fn delete(&self, pointer: &S3Pointer, clock: &dyn Clock) {
+        let result = match self.signed(Method::DELETE, pointer, clock).await {
+            Ok(req) => req.send().await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(rsp) if rsp.status().is_success() => debug!("Deleted offloaded payload {}", pointer),
+            Ok(rsp) => warn!("Failed to delete offloaded payload {}: {}", pointer, rsp.status()),
+            Err(e) => warn!("Failed to delete offloaded payload {}: {}", pointer, e),
+        }
+    }
+
+    fn url(&self, pointer: &S3Pointer) -> String {
+        let key = uri_encode(&pointer.key);
+        match &self.endpoint {
+            Some(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), pointer.bucket, key),
+            None => format!("https://{}.s3.{}.amazonaws.com/{}", pointer.bucket, self.region, key),
+        }
+    }
+
+    async fn signed(&self, method: Method, pointer: &S3Pointer, clock: &dyn Clock) -> Result<reqwest::RequestBuilder, String> {
+        let url = self.url(pointer);
+        let credentials = self.credentials.provide_credentials().await.map_err(|e| e.to_string())?;
+        let identity = credentials.into();
+        // S3 signs the path as sent and wants the payload hash as a header
+        let mut settings = SigningSettings::default();
+        settings.percent_encoding_mode = PercentEncodingMode::Single;
+        settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("s3")
+            .time(clock.now())
+            .settings(settings)
+            .build()
+            .map_err(|e| e.to_string())?
+            .into();
+        let request = SignableRequest::new(method.as_str(), &url, std::iter::empty(), SignableBody::Bytes(&[]))
+            .map_err(|e| e.to_string())?;
+        let (instructions, _) = sign(request, &params).map_err(|e| e.to_string())?.into_parts();
+        let mut req = self.http.request(method, &url);
+        for (name, value) in instructions.headers() {
+            req = req.header(name, value);
+        }
+        Ok(req)
+    }
+}
+
+/// Percent-encodes everything but unreserved characters and `/`, as SigV4 expects.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::types::MessageAttributeValue;
+
+    fn offloaded(body: &str) -> Message {
+        let size = MessageAttributeValue::builder().data_type("Number").string_value("300000").build().unwrap();
+        Message::builder().body(body).message_attributes("ExtendedPayloadSize", size).build()
+    }
+
+    #[test]
+    fn parses_extended_client_pointers() {
+        let pointer = S3Pointer { bucket: "payloads".into(), key: "a/b c.json".into() };
+        let java = r#"["software.amazon.payloadoffloading.PayloadS3Pointer",{"s3BucketName":"payloads","s3Key":"a/b c.json"}]"#;
+        assert_eq!(S3Pointer::from_message(&offloaded(java)), Some(pointer.clone()));
+        let bare = r#"[{"s3BucketName":"payloads","s3Key":"a/b c.json"}]"#;
+        assert_eq!(S3Pointer::from_message(&offloaded(bare)), Some(pointer));
+        // Same body without the marker attribute is an ordinary message
+        assert_eq!(S3Pointer::from_message(&Message::builder().body(bare).build()), None);
+        assert_eq!(S3Pointer::from_message(&offloaded(r#"{"s3BucketName":"payloads"}"#)), None);
+        assert_eq!(uri_encode("a/b c+d~.json"), "a/b%20c%2Bd~.json");
+    }
+}
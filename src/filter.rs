@@ -0,0 +1,102 @@
+//! Optional attribute filter for messages that should be dropped unforwarded.
+
+use anyhow::{anyhow, Result};
+use aws_sdk_sqs::types::Message;
+use regex::Regex;
+use std::str::FromStr;
+
+/// How `FILTER_VALUE` is compared with the attribute value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    Equals,
+    /// `*` matches any run of characters and `?` a single one; the whole value must match.
+    Glob,
+    /// Unanchored regular expression.
+    Regex,
+}
+
+impl FromStr for FilterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "equals" | "eq" => Ok(Self::Equals),
+            "glob" => Ok(Self::Glob),
+            "regex" => Ok(Self::Regex),
+            _ => Err("expected equals, glob or regex".into()),
+        }
+    }
+}
+
+/// Matches messages whose `FILTER_ATTRIBUTE` string attribute matches `FILTER_VALUE`.
+#[derive(Debug)]
+pub struct MessageFilter {
+    attribute: String,
+    matcher: Matcher,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Equals(String),
+    Pattern(Regex),
+}
+
+impl MessageFilter {
+    /// Compiles the filter; an invalid pattern is a startup error.
+    pub fn new(attribute: String, value: &str, mode: FilterMode) -> Result<Self> {
+        let matcher = match mode {
+            FilterMode::Equals => Matcher::Equals(value.to_string()),
+            FilterMode::Glob => {
+                let pattern = regex::escape(value).replace(r"\*", ".*").replace(r"\?", ".");
+                Matcher::Pattern(Regex::new(&format!("^{pattern}$"))?)
+            }
+            FilterMode::Regex => Matcher::Pattern(
+                Regex::new(value).map_err(|e| anyhow!("invalid FILTER_VALUE regex '{value}': {e}"))?,
+            ),
+        };
+        Ok(Self { attribute, matcher })
+    }
+
+    pub fn attribute(&self) -> &str {
+        &self.attribute
+    }
+
+    /// Whether the message carries the attribute with a matching value.
+    /// Attribute names are compared case-insensitively, like header names.
+    pub fn matches(&self, m: &Message) -> bool {
+        let Some(attrs) = m.message_attributes() else { return false; };
+        attrs
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.attribute))
+            .filter_map(|(_, v)| v.string_value())
+            .any(|value| match &self.matcher {
+                Matcher::Equals(expected) => value == expected,
+                Matcher::Pattern(re) => re.is_match(value),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::types::MessageAttributeValue;
+
+    fn message(name: &str, value: &str) -> Message {
+        let attr = MessageAttributeValue::builder().data_type("String").string_value(value).build().unwrap();
+        Message::builder().message_attributes(name, attr).build()
+    }
+
+    #[test]
+    fn matches_by_mode() {
+        let ping = message("X-GitHub-Event", "ping");
+        let filter = |value, mode| MessageFilter::new("x-github-event".into(), value, mode).unwrap();
+
+        assert!(filter("ping", FilterMode::Equals).matches(&ping));
+        assert!(!filter("pin", FilterMode::Equals).matches(&ping));
+        assert!(filter("p?n*", FilterMode::Glob).matches(&ping));
+        assert!(!filter("p.ng", FilterMode::Glob).matches(&ping));
+        assert!(filter("^pi", FilterMode::Regex).matches(&ping));
+        assert!(!filter("ping", FilterMode::Equals).matches(&message("other", "ping")));
+        assert!(MessageFilter::new("a".into(), "(", FilterMode::Regex).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Minimal gzip encoder for `FORWARD_COMPRESSION=gzip`: LZ77 matches coded with
+//! DEFLATE's fixed Huffman tables in a single block. It trades some ratio for
+//! needing no tables in the output, which is plenty for repetitive JSON.
+
+/// Matches are searched this far back (DEFLATE's maximum).
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates tried per position; bounds the worst case on pathological input.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Gzip-compresses `data` (RFC 1952 member around an RFC 1951 stream).
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // ID1 ID2, CM=deflate, no flags, no mtime, no extra flags, OS unknown
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut bits = BitWriter { out: &mut out, acc: 0, len: 0 };
+    bits.write(1, 1); // BFINAL
+    bits.write(1, 2); // BTYPE=01, fixed Huffman
+    deflate(data, &mut bits);
+    write_literal(&mut bits, 256);
+    bits.flush();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn deflate(data: &[u8], bits: &mut BitWriter) {
+    let mut chains = Chains { head: vec![usize::MAX; 1 << HASH_BITS], prev: vec![usize::MAX; WINDOW] };
+    let mut i = 0;
+    while i < data.len() {
+        let (len, dist) = chains.longest_match(data, i);
+        if len >= MIN_MATCH {
+            write_match(bits, len, dist);
+            (i..i + len).for_each(|j| chains.insert(data, j));
+            i += len;
+        } else {
+            write_literal(bits, u16::from(data[i]));
+            chains.insert(data, i);
+            i += 1;
+        }
+    }
+}
+
+/// Earlier positions per 3-byte prefix hash, newest first.
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn hash(data: &[u8], i: usize) -> usize {
+        let v = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+        (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + MIN_MATCH <= data.len() {
+            let h = Self::hash(data, i);
+            self.prev[i % WINDOW] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+
+    /// Length and distance of the longest earlier match for `data[i..]`.
+    fn longest_match(&self, data: &[u8], i: usize) -> (usize, usize) {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + MIN_MATCH > data.len() {
+            return (best_len, best_dist);
+        }
+        let max_len = MAX_MATCH.min(data.len() - i);
+        let mut candidate = self.head[Self::hash(data, i)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || i - candidate >= WINDOW {
+                break;
+            }
+            let len = data[candidate..].iter().zip(&data[i..i + max_len]).take_while(|(a, b)| a == b).count();
+            if len > best_len {
+                (best_len, best_dist) = (len, i - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+            // Slots are reused as the window slides; stop at stale links
+            let next = self.prev[candidate % WINDOW];
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        (best_len, best_dist)
+    }
+}
+
+/// Writes a literal/length symbol with the fixed code (RFC 1951 §3.2.6).
+fn write_literal(bits: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    bits.write_huffman(code, len);
+}
+
+fn write_match(bits: &mut BitWriter, len: usize, dist: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| usize::from(base) <= len).expect("len >= 3");
+    write_literal(bits, 257 + code as u16);
+    bits.write(len as u32 - u32::from(LENGTH_BASE[code]), LENGTH_EXTRA[code]);
+    let code = DIST_BASE.iter().rposition(|&base| usize::from(base) <= dist).expect("dist >= 1");
+    bits.write_huffman(code as u16, 5);
+    bits.write(dist as u32 - u32::from(DIST_BASE[code]), DIST_EXTRA[code]);
+}
+
+/// Packs values LSB-first, as DEFLATE requires.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u64,
+    len: u8,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, len: u8) {
+        self.acc |= u64::from(value) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Huffman codes are defined MSB-first, so they go in bit-reversed.
+    fn write_huffman(&mut self, code: u16, len: u8) {
+        self.write(u32::from(code.reverse_bits() >> (16 - len)), len);
+    }
+
+    fn flush(&mut self) {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+            (self.acc, self.len) = (0, 0);
+        }
+    }
+}
+
+/// CRC-32 (IEEE) of every byte value, built at compile time.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits LSB-first.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, n: usize) -> usize {
+            (0..n).fold(0, |v, i| {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                self.pos += 1;
+                v | usize::from(bit) << i
+            })
+        }
+    }
+
+    /// Canonical Huffman code rebuilt from its code lengths (RFC 1951 §3.2.2).
+    struct Huffman {
+        counts: [usize; 16],
+        symbols: Vec<usize>,
+    }
+
+    impl Huffman {
+        fn new(lengths: &[usize]) -> Self {
+            let mut counts = [0; 16];
+            lengths.iter().for_each(|&len| counts[len] += 1);
+            counts[0] = 0;
+            let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+            symbols.sort_by_key(|&s| lengths[s]);
+            Self { counts, symbols }
+        }
+
+        fn decode(&self, bits: &mut BitReader) -> usize {
+            let (mut code, mut first, mut index) = (0, 0, 0);
+            for count in &self.counts[1..] {
+                code |= bits.bits(1);
+                if code < first + count {
+                    return self.symbols[index + code - first];
+                }
+                index += count;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+            panic!("invalid Huffman code");
+        }
+    }
+
+    /// Inflater written from the RFCs rather than from the encoder's tables,
+    /// so a shared mistake can't cancel out. Checks the gzip header and trailer.
+    fn gunzip(gz: &[u8]) -> Vec<u8> {
+        assert_eq!(gz[..4], [0x1f, 0x8b, 8, 0], "header");
+        let mut bits = BitReader { data: gz, pos: 10 * 8 };
+        let lit_lengths: Vec<usize> = (0..288).map(|s| match s {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        }).collect();
+        let (lit, dist) = (Huffman::new(&lit_lengths), Huffman::new(&[5; 30]));
+        // Base values follow from the extra bit counts (RFC 1951 §3.2.5)
+        let len_extra = |i: usize| if (8..28).contains(&i) { (i - 4) / 4 } else { 0 };
+        let dist_extra = |i: usize| if i >= 4 { (i - 2) / 2 } else { 0 };
+        let len_base = |i: usize| if i == 28 { 258 } else { 3 + (0..i).map(|k| 1 << len_extra(k)).sum::<usize>() };
+        let dist_base = |i: usize| 1 + (0..i).map(|k| 1 << dist_extra(k)).sum::<usize>();
+
+        let mut out = Vec::new();
+        loop {
+            let last = bits.bits(1) == 1;
+            match bits.bits(2) {
+                0 => {
+                    let at = bits.pos.div_ceil(8);
+                    let len = usize::from(u16::from_le_bytes([gz[at], gz[at + 1]]));
+                    out.extend_from_slice(&gz[at + 4..at + 4 + len]);
+                    bits.pos = (at + 4 + len) * 8;
+                }
+                1 => loop {
+                    match lit.decode(&mut bits) {
+                        byte @ 0..=255 => out.push(byte as u8),
+                        256 => break,
+                        symbol => {
+                            let i = symbol - 257;
+                            let len = len_base(i) + bits.bits(len_extra(i));
+                            let d = dist.decode(&mut bits);
+                            let distance = dist_base(d) + bits.bits(dist_extra(d));
+                            assert!(distance <= out.len() && distance <= 32 * 1024, "distance {distance}");
+                            (0..len).for_each(|_| out.push(out[out.len() - distance]));
+                        }
+                    }
+                },
+                btype => panic!("unexpected block type {btype}"),
+            }
+            if last {
+                break;
+            }
+        }
+        let trailer = &gz[bits.pos.div_ceil(8)..];
+        assert_eq!(trailer.len(), 8, "trailer");
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn inflates_back_to_the_input() {
+        // xorshift, so the "random" input is the same on every run
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let random: Vec<u8> = std::iter::repeat_with(|| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .take(70_000)
+        .collect();
+        // Over 32 KiB with repeats both inside and beyond the window
+        let json: Vec<u8> = (0..3000)
+            .flat_map(|n| format!(r#"{{"id":{},"sha":"{:x}","ref":"refs/heads/main"}},"#, n, n * 2_654_435_761u64).into_bytes())
+            .collect();
+        assert!(json.len() > WINDOW * 2);
+        let inputs = [
+            Vec::new(),
+            b"x".to_vec(),
+            random,
+            json,
+            vec![b'a'; 200_000],
+            b"abc".repeat(50_000),
+        ];
+        for input in inputs {
+            assert_eq!(gunzip(&compress(&input)), input, "{} bytes", input.len());
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn compresses_into_a_gzip_member() {
+        // Checked against `gzip -d`
+        assert_eq!(
+            compress(b"abcabcabcabc"),
+            [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 0x4b, 0x4c, 0x4a, 0x86, 0x23, 0, 0x34, 0x2a, 0x6e, 0x5a, 0x0c, 0, 0, 0]
+        );
+        let json = br#"{"action":"opened","number":1,"pull_request":{"title":"x"}}"#.repeat(50);
+        let gz = compress(&json);
+        assert!(gz.len() < json.len() / 10, "{} of {}", gz.len(), json.len());
+        assert_eq!(gz[gz.len() - 4..], (json.len() as u32).to_le_bytes());
+        assert_eq!(compress(b"").len(), 20);
+    }
+}
@@ -0,0 +1,211 @@
+//! Forwards for `PRESERVE_HEADER_CASE`: each attribute header goes out spelled
+//! as its attribute was (`X-GitHub-Event`), for consumers that match names
+//! case-sensitively.
+//!
+//! reqwest only sends lowercase or Title-Case names, so these forwards use a
+//! hyper HTTP/1.1 connection of their own over plain TCP, and the finished
+//! request head is respelled on its way to the socket.
+
+use aws_sdk_sqs::types::Message;
+use http_body_util::{BodyExt as _, Full};
+use hyper::{body::Bytes, client::conn::http1, Request};
+use hyper_util::rt::TokioIo;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, HOST, USER_AGENT};
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::config::AttrHeaderOptions;
+use crate::error::{ForwardErrorKind, RelayError};
+
+/// Each attribute's header name (lowercase) with the spelling to send instead.
+/// Where attributes differ only in case, the first by name wins.
+pub fn spellings(m: &Message, options: &AttrHeaderOptions) -> HashMap<String, String> {
+    let mut names: Vec<&String> = m.message_attributes().into_iter().flat_map(|attrs| attrs.keys()).collect();
+    names.sort();
+    let prefix = options.add_prefix.as_deref().unwrap_or_default();
+    let mut spellings = HashMap::new();
+    for name in names {
+        let header = options.header_name(name);
+        // The prefixes work on lowercased names; what's left of the attribute keeps its case
+        let kept = &name[name.len() - (header.len() - prefix.len())..];
+        spellings.entry(header).or_insert_with(|| format!("{prefix}{kept}"));
+    }
+    spellings
+}
+
+/// POSTs `body` to an `http://` URL over a new connection, with header names
+/// respelled per `spellings`. The timeout covers the whole exchange, body included.
+pub async fn post(
+    url: &str,
+    mut headers: HeaderMap,
+    body: Vec<u8>,
+    spellings: HashMap<String, String>,
+    user_agent: &str,
+    timeout: Duration,
+) -> Result<reqwest::Response, RelayError> {
+    let request_error = |e: &dyn std::fmt::Display| RelayError::Forward(ForwardErrorKind::Request, e.to_string());
+    let url = reqwest::Url::parse(url).map_err(|e| request_error(&e))?;
+    let host = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    // What reqwest would add, so only the spelling differs
+    let authority = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.clone(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&authority) {
+        headers.entry(HOST).or_insert(value);
+    }
+    if let Ok(value) = HeaderValue::from_str(user_agent) {
+        headers.entry(USER_AGENT).or_insert(value);
+    }
+    headers.entry(ACCEPT).or_insert(HeaderValue::from_static("*/*"));
+
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut request = Request::post(path).body(Full::new(Bytes::from(body))).map_err(|e| request_error(&e))?;
+    *request.headers_mut() = headers;
+
+    let exchange = async {
+        let stream = TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| RelayError::Forward(ForwardErrorKind::Connect, format!("connecting to {authority}: {e}")))?;
+        let io = Respelled { stream, spellings, head: Vec::new(), written: None };
+        let (mut sender, connection) = http1::handshake(TokioIo::new(io)).await.map_err(|e| request_error(&e))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("PRESERVE_HEADER_CASE connection ended: {}", e);
+            }
+        });
+        let response = sender.send_request(request).await.map_err(|e| request_error(&e))?;
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map_err(|e| request_error(&e))?.to_bytes();
+        Ok(reqwest::Response::from(hyper::Response::from_parts(parts, body)))
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| RelayError::Forward(ForwardErrorKind::Timeout, format!("no response within {timeout:?}")))?
+}
+
+/// A connection that holds back the request head until it is complete, then
+/// writes it with the names respelled. Everything after goes straight through.
+struct Respelled {
+    stream: TcpStream,
+    spellings: HashMap<String, String>,
+    head: Vec<u8>,
+    /// How much of the complete head has reached the socket.
+    written: Option<usize>,
+}
+
+impl Respelled {
+    fn poll_write_head(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(written) = self.written.filter(|&w| w < self.head.len()) {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.head[written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written = Some(written + n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Respelled {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Respelled {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.written.is_none() {
+            let start = this.head.len().saturating_sub(3);
+            this.head.extend_from_slice(buf);
+            let Some(end) = this.head[start..].windows(4).position(|w| w == b"\r\n\r\n") else {
+                return Poll::Ready(Ok(buf.len()));
+            };
+            // Take the head only; the body is written again after it
+            let end = start + end + 4;
+            let taken = buf.len() - (this.head.len() - end);
+            this.head.truncate(end);
+            respell(&mut this.head, &this.spellings);
+            this.written = Some(0);
+            return Poll::Ready(Ok(taken));
+        }
+        ready!(this.poll_write_head(cx))?;
+        Pin::new(&mut this.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_head(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_head(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Rewrites header names in a request head in place. Spellings only change
+/// case, so every line keeps its length.
+fn respell(head: &mut [u8], spellings: &HashMap<String, String>) {
+    // Skip the request line
+    for line in head.split_mut(|&b| b == b'\n').skip(1) {
+        let Some(colon) = line.iter().position(|&b| b == b':') else { continue };
+        let name = &mut line[..colon];
+        let spelling = std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| spellings.get(&name.to_ascii_lowercase()))
+            .filter(|spelling| spelling.len() == name.len());
+        if let Some(spelling) = spelling {
+            name.copy_from_slice(spelling.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respell_changes_only_listed_names() {
+        let mut head = b"POST /hook?a:b HTTP/1.1\r\nx-github-event: push\r\ncontent-type: a:b\r\n\r\n".to_vec();
+        let spellings = HashMap::from([("x-github-event".to_string(), "X-GitHub-Event".to_string())]);
+        respell(&mut head, &spellings);
+        assert_eq!(head, b"POST /hook?a:b HTTP/1.1\r\nX-GitHub-Event: push\r\ncontent-type: a:b\r\n\r\n");
+    }
+
+    #[test]
+    fn spellings_follow_the_header_prefixes() {
+        let options = AttrHeaderOptions {
+            duplicate_policy: crate::config::DuplicateHeaderPolicy::LastWins,
+            strip_prefix: Some("x-".into()),
+            add_prefix: Some("hook-".into()),
+            max_headers: 50,
+            type_headers: false,
+        };
+        let attr = aws_sdk_sqs::types::MessageAttributeValue::builder().data_type("String").string_value("x").build().unwrap();
+        let m = Message::builder()
+            .message_attributes("X-GitHub-Event", attr.clone())
+            .message_attributes("X-GITHUB-EVENT", attr.clone())
+            .message_attributes("ContentType", attr)
+            .build();
+        let spellings = spellings(&m, &options);
+        assert_eq!(spellings["hook-github-event"], "hook-GITHUB-EVENT");
+        assert_eq!(spellings["hook-contenttype"], "hook-ContentType");
+    }
+}
@@ -0,0 +1,401 @@
+//! Optional HTTP server for operators, enabled by `HEALTH_PORT`.
+//!
+//! `/healthz` answers `ok` while the process is up; `/status` reports what the
+//! relay loop is doing right now, read from [`RelayStatus`], and `/metrics`
+//! exposes the same in Prometheus text format; `/version` reports the build.
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::{body::Bytes, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+use crate::clock::{Clock, SystemClock};
+use crate::{CircuitState, GIT_HASH, VERSION};
+
+/// Live relay loop state, updated by `relay_loop` and read by `/status`.
+#[derive(Debug, Default)]
+pub struct RelayStatus {
+    /// Epoch ms of the last successful receive; 0 until the first one.
+    last_poll_ok_ms: AtomicU64,
+    /// Messages in the batches currently being worked through, across all pollers.
+    pub batch_size: AtomicUsize,
+    /// Messages being forwarded right now.
+    pub in_flight: AtomicUsize,
+    /// SQS receive errors since the last successful poll.
+    pub consecutive_errors: AtomicU32,
+    /// Messages deleted unforwarded because their key recently succeeded (`DEDUP_TTL_SECS`).
+    pub duplicates_suppressed: AtomicU64,
+    /// `CircuitState as u8`, or 0 when no breaker is configured.
+    circuit: AtomicU8,
+    /// Failures the breaker currently counts towards opening.
+    circuit_failures: AtomicU32,
+    /// Delay of the receive backoff in effect, in ms; 0 once it has been reset.
+    receive_backoff_ms: AtomicU64,
+    /// Local responses by HTTP status code.
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Epoch ms and text of the most recent forward or receive error.
+    last_error: Mutex<Option<(u64, String)>>,
+    /// `SentTimestamp` to local response, for answered forwards.
+    queue_latency: Mutex<Histogram>,
+    /// Latest `ApproximateNumberOfMessages*` reading, once one succeeded.
+    queue_depth: Mutex<Option<QueueDepth>>,
+    /// Entries kept for `/recent`; 0 disables it.
+    recent_capacity: usize,
+    /// The last processed messages, oldest first.
+    recent: Mutex<VecDeque<serde_json::Value>>,
+    /// Time source for the epoch-ms readings; the system clock when `None`.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+/// Approximate message counts reported by `GetQueueAttributes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub visible: u64,
+    pub in_flight: u64,
+    pub delayed: u64,
+}
+
+/// Upper bounds (seconds) of the `relay_queue_latency_seconds` buckets.
+const QUEUE_LATENCY_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 21600.0];
+
+/// Cumulative Prometheus histogram over [`QUEUE_LATENCY_BUCKETS`].
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; QUEUE_LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl RelayStatus {
+    /// Status keeping the last `recent_capacity` messages for `/recent`.
+    pub fn new(recent_capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self { recent_capacity, clock: Some(clock), ..Self::default() }
+    }
+
+    fn now_ms(&self) -> u64 {
+        let clock = self.clock.as_deref().unwrap_or(&SystemClock);
+        clock.now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+    }
+
+    /// Whether `/recent` is enabled, so callers can skip building entries.
+    pub fn keeps_recent(&self) -> bool {
+        self.recent_capacity > 0
+    }
+
+    /// The `/recent` entries, newest first.
+    pub fn recent(&self) -> Vec<serde_json::Value> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Adds a processed message to `/recent`, stamped with the current time and
+    /// evicting the oldest entry when full.
+    pub fn record_recent(&self, mut entry: serde_json::Value) {
+        if !self.keeps_recent() {
+            return;
+        }
+        entry["unix_ms"] = self.now_ms().into();
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.recent_capacity {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    pub fn poll_succeeded(&self) {
+        self.last_poll_ok_ms.store(self.now_ms(), Ordering::Relaxed);
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_circuit(&self, state: CircuitState, consecutive_failures: u32) {
+        self.circuit.store(state as u8, Ordering::Relaxed);
+        self.circuit_failures.store(consecutive_failures, Ordering::Relaxed);
+    }
+
+    pub fn set_receive_backoff(&self, delay: Duration) {
+        self.receive_backoff_ms.store(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Tallies a local response status and remembers an error, either of which may be absent.
+    pub fn record(&self, status: Option<u16>, error: Option<&str>) {
+        if let Some(status) = status {
+            *self.responses.lock().unwrap().entry(status).or_default() += 1;
+        }
+        if let Some(error) = error {
+            *self.last_error.lock().unwrap() = Some((self.now_ms(), error.to_string()));
+        }
+    }
+
+    pub fn set_queue_depth(&self, depth: QueueDepth) {
+        *self.queue_depth.lock().unwrap() = Some(depth);
+    }
+
+    pub fn queue_depth(&self) -> Option<QueueDepth> {
+        *self.queue_depth.lock().unwrap()
+    }
+
+    pub fn observe_queue_latency(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let mut histogram = self.queue_latency.lock().unwrap();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(QUEUE_LATENCY_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    fn last_poll_ok_ms(&self) -> Option<u64> {
+        Some(self.last_poll_ok_ms.load(Ordering::Relaxed)).filter(|ms| *ms > 0)
+    }
+
+    fn circuit_label(&self) -> Option<&'static str> {
+        let circuit = self.circuit.load(Ordering::Relaxed);
+        CircuitState::ALL.into_iter().find(|s| *s as u8 == circuit).map(CircuitState::label)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let last_poll_ok_ms = self.last_poll_ok_ms();
+        let responses: serde_json::Map<_, _> = self
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(status, count)| (status.to_string(), (*count).into()))
+            .collect();
+        let last_error = self.last_error.lock().unwrap().clone();
+        let queue_depth = self.queue_depth();
+        json!({
+            "last_poll_ok_unix_ms": last_poll_ok_ms,
+            "last_poll_ok_age_ms": last_poll_ok_ms.map(|ms| self.now_ms().saturating_sub(ms)),
+            "batch_size": self.batch_size.load(Ordering::Relaxed),
+            "in_flight": self.in_flight.load(Ordering::Relaxed),
+            "consecutive_errors": self.consecutive_errors.load(Ordering::Relaxed),
+            "circuit": self.circuit_label(),
+            "circuit_failures": self.circuit_label().map(|_| self.circuit_failures.load(Ordering::Relaxed)),
+            "receive_backoff_ms": self.receive_backoff_ms.load(Ordering::Relaxed),
+            "duplicates_suppressed": self.duplicates_suppressed.load(Ordering::Relaxed),
+            "queue_depth": queue_depth.map(|d| json!({ "visible": d.visible, "in_flight": d.in_flight, "delayed": d.delayed })),
+            "responses": responses,
+            "last_error": last_error.map(|(at_ms, message)| json!({ "unix_ms": at_ms, "message": message })),
+        })
+    }
+
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let responses: Vec<_> = self
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(status, count)| (format!("{{code=\"{status}\"}}"), *count as f64))
+            .collect();
+        metric("relay_forward_responses_total", "counter", "Local endpoint responses by HTTP status code.", &responses);
+        let last_error_secs = self.last_error.lock().unwrap().as_ref().map(|(ms, _)| *ms as f64 / 1000.0);
+        metric(
+            "relay_last_error_timestamp_seconds", "gauge", "When the last forward or receive error happened.",
+            &[(String::new(), last_error_secs.unwrap_or(0.0))],
+        );
+        metric(
+            "relay_last_poll_ok_timestamp_seconds", "gauge", "When SQS was last polled successfully.",
+            &[(String::new(), self.last_poll_ok_ms().unwrap_or(0) as f64 / 1000.0)],
+        );
+        metric(
+            "relay_in_flight", "gauge", "Messages being forwarded right now.",
+            &[(String::new(), self.in_flight.load(Ordering::Relaxed) as f64)],
+        );
+        metric(
+            "relay_consecutive_receive_errors", "gauge", "SQS receive errors since the last successful poll.",
+            &[(String::new(), self.consecutive_errors.load(Ordering::Relaxed) as f64)],
+        );
+        metric(
+            "relay_duplicates_suppressed_total", "counter", "Messages deleted unforwarded as recent duplicates.",
+            &[(String::new(), self.duplicates_suppressed.load(Ordering::Relaxed) as f64)],
+        );
+        if let Some(depth) = self.queue_depth() {
+            let samples: Vec<_> = [("visible", depth.visible), ("in_flight", depth.in_flight), ("delayed", depth.delayed)]
+                .into_iter()
+                .map(|(state, count)| (format!("{{state=\"{state}\"}}"), count as f64))
+                .collect();
+            metric("relay_queue_depth", "gauge", "Approximate messages in the queue, by state.", &samples);
+        }
+        if let Some(state) = self.circuit_label() {
+            let samples: Vec<_> = CircuitState::ALL
+                .into_iter()
+                .map(|s| (format!("{{state=\"{}\"}}", s.label()), if s.label() == state { 1.0 } else { 0.0 }))
+                .collect();
+            metric("relay_circuit_state", "gauge", "Circuit breaker state (1 for the current one).", &samples);
+        }
+        let histogram = self.queue_latency.lock().unwrap();
+        let name = "relay_queue_latency_seconds";
+        let _ = writeln!(out, "# HELP {name} Time from SentTimestamp to the local response.\n# TYPE {name} histogram");
+        for (count, le) in histogram.buckets.iter().zip(QUEUE_LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "{name}_sum {}\n{name}_count {}", histogram.sum, histogram.count);
+        out
+    }
+}
+
+/// Binds the listener up front so a taken port fails startup instead of being
+/// discovered later.
+pub async fn bind(addr: IpAddr, port: u16) -> Result<TcpListener> {
+    let addr = SocketAddr::from((addr, port));
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind health server to {addr} (HEALTH_BIND_ADDR/HEALTH_PORT)"))?;
+    info!("🩺 Health server listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Serves requests until the process exits.
+pub async fn serve(listener: TcpListener, status: Arc<RelayStatus>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Health server accept failed: {}", e);
+                continue;
+            }
+        };
+        let status = status.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let status = status.clone();
+                async move { Ok::<_, Infallible>(route(&req, &status)) }
+            });
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!("Health server connection error: {}", e);
+            }
+        });
+    }
+}
+
+fn route<B>(req: &Request<B>, status: &RelayStatus) -> Response<Full<Bytes>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => respond(StatusCode::OK, "text/plain", "ok"),
+        (&Method::GET, "/status") => respond(StatusCode::OK, "application/json", status.to_json().to_string()),
+        (&Method::GET, "/recent") => {
+            // Newest first, as a browser reader expects
+            respond(StatusCode::OK, "application/json", serde_json::Value::from(status.recent()).to_string())
+        }
+        (&Method::GET, "/metrics") => respond(StatusCode::OK, "text/plain; version=0.0.4", status.to_prometheus()),
+        (&Method::GET, "/version") => {
+            let body = json!({ "version": VERSION, "git_hash": GIT_HASH });
+            respond(StatusCode::OK, "application/json", body.to_string())
+        }
+        _ => respond(StatusCode::NOT_FOUND, "text/plain", "not found"),
+    }
+}
+
+fn respond(status: StatusCode, content_type: &str, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut rsp = Response::new(Full::new(body.into()));
+    *rsp.status_mut() = status;
+    if let Ok(value) = content_type.parse() {
+        rsp.headers_mut().insert(hyper::header::CONTENT_TYPE, value);
+    }
+    rsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use http_body_util::BodyExt;
+
+    async fn get(path: &str, status: &RelayStatus) -> (StatusCode, serde_json::Value) {
+        let rsp = route(&Request::get(path).body(()).unwrap(), status);
+        let code = rsp.status();
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        (code, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn status_reports_loop_state() {
+        let clock = Arc::new(MockClock::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        let status = RelayStatus::new(0, clock.clone());
+        let (code, json) = get("/status", &status).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(json["last_poll_ok_unix_ms"], serde_json::Value::Null);
+        assert_eq!(json["circuit"], serde_json::Value::Null);
+
+        status.consecutive_errors.store(3, Ordering::Relaxed);
+        status.poll_succeeded();
+        status.in_flight.store(1, Ordering::Relaxed);
+        status.set_circuit(CircuitState::HalfOpen, 4);
+        status.set_receive_backoff(Duration::from_secs(8));
+        clock.advance(Duration::from_millis(1500));
+        let (_, json) = get("/status", &status).await;
+        assert_eq!(json["last_poll_ok_unix_ms"], 1_700_000_000_000u64);
+        assert_eq!(json["last_poll_ok_age_ms"], 1500);
+        assert_eq!(json["consecutive_errors"], 0);
+        assert_eq!(json["in_flight"], 1);
+        assert_eq!(json["circuit"], "half-open");
+        assert_eq!(json["circuit_failures"], 4);
+        assert_eq!(json["receive_backoff_ms"], 8000);
+
+        status.record(Some(503), Some("local endpoint returned 503"));
+        status.record(Some(503), None);
+        status.record(Some(200), None);
+        let (_, json) = get("/status", &status).await;
+        assert_eq!(json["responses"]["503"], 2);
+        assert_eq!(json["last_error"]["message"], "local endpoint returned 503");
+        let metrics = status.to_prometheus();
+        assert!(metrics.contains("relay_forward_responses_total{code=\"503\"} 2\n"));
+        assert!(metrics.contains("relay_circuit_state{state=\"half-open\"} 1\n"));
+        assert!(metrics.contains("relay_duplicates_suppressed_total 0\n"));
+        assert!(!metrics.contains("relay_queue_depth"));
+        status.set_queue_depth(QueueDepth { visible: 12, in_flight: 3, delayed: 0 });
+        assert!(status.to_prometheus().contains("relay_queue_depth{state=\"visible\"} 12\n"));
+        assert_eq!(get("/status", &status).await.1["queue_depth"]["in_flight"], 3);
+
+        status.observe_queue_latency(Duration::from_millis(2500));
+        let metrics = status.to_prometheus();
+        assert!(metrics.contains("relay_queue_latency_seconds_bucket{le=\"1\"} 0\n"));
+        assert!(metrics.contains("relay_queue_latency_seconds_bucket{le=\"5\"} 1\n"));
+        assert!(metrics.contains("relay_queue_latency_seconds_count 1\n"));
+
+        assert_eq!(get("/recent", &status).await.1, json!([]));
+        let status = RelayStatus::new(2, clock);
+        for id in ["m-1", "m-2", "m-3"] {
+            status.record_recent(json!({ "message_id": id }));
+        }
+        let recent = get("/recent", &status).await.1;
+        let ids: Vec<_> = recent.as_array().unwrap().iter().map(|e| e["message_id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["m-3", "m-2"]);
+        assert_eq!(recent[0]["unix_ms"], 1_700_000_001_500u64);
+
+        assert_eq!(get("/version", &status).await.1["version"], VERSION);
+        assert_eq!(get("/nope", &status).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn bind_fails_on_taken_address() {
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        let listener = bind(loopback, 0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let err = bind(loopback, port).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("127.0.0.1:{port}")), "{err}");
+    }
+}
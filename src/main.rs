@@ -1,317 +1,2577 @@
 use anyhow::{Context, Result};
-use aws_config::BehaviorVersion;
-use aws_sdk_sqs::{types::MessageAttributeValue, Client as SqsClient};
+use aws_config::{sts::AssumeRoleProvider, BehaviorVersion, SdkConfig};
+use aws_credential_types::provider::{error::CredentialsError, SharedCredentialsProvider};
+use aws_sdk_sqs::config::ProvideCredentials;
+use aws_sdk_sqs::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_sqs::operation::RequestId;
+use aws_sdk_sqs::{
+    types::{Message, MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName},
+    Client as SqsClient,
+};
 use colored::*;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER, TRANSFER_ENCODING,
+    USER_AGENT,
+};
 use serde_json::Value;
-use std::{env, time::Duration};
+use sha2::Sha256;
+use futures_util::{stream::FuturesUnordered, FutureExt as _, StreamExt as _};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashSet,
+    env,
+    fmt::Write as _,
+    future::Future,
+    net::IpAddr,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::signal;
-use tracing::{debug, error, info, warn};
-use tracing_subscriber::EnvFilter;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 use base64::{engine::general_purpose, Engine as _};
 
+#[cfg(feature = "otel")]
+mod otel;
+mod capture;
+mod clock;
+mod config;
+mod decrypt;
+mod dedup;
+mod error;
+mod extended;
+mod filter;
+mod gzip;
+mod header_case;
+mod health;
+mod notify;
+mod policy;
+mod route;
+mod sink;
+mod success;
+#[cfg(test)]
+mod relay_tests;
+mod transform;
+
+use clock::Clock;
+use config::{
+    AssumeRole, AttrHeaderOptions, ClientConfig, Config, DuplicateHeaderPolicy, EmptyBodyAction, ForwardCompression,
+    LiveConfig, NonUtf8Action, SinkKind,
+    HttpVersion, OversizeAction, PreviewLimits, QueryAttribute, SignaturePolicy, SystemAttrHeader, TimeoutAction,
+};
+use error::{ExitKind, ForwardErrorKind, RelayError};
+use extended::{PayloadStore, S3Pointer};
+use health::{QueueDepth, RelayStatus};
+use sink::{Delivery, HttpSink, Sink};
+#[cfg(feature = "kafka")]
+use sink::KafkaSink;
+
+const X_REQUEST_ID: &str = "x-request-id";
+const X_RELAY_SIGNATURE: &str = "x-relay-signature";
+const X_HUB_SIGNATURE_256: &str = "x-hub-signature-256";
+/// Message attribute naming a URL that replaces `LOCAL_URL` for that message.
+const X_RELAY_TARGET: &str = "X-Relay-Target";
+/// Message attribute counting failed forwards across re-enqueues (`MAX_TOTAL_ATTEMPTS`).
+const X_RELAY_ATTEMPTS: &str = "X-Relay-Attempts";
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the binary was built from, set by `build.rs`.
+const GIT_HASH: &str = env!("GIT_HASH");
+
+/// Receive wait in `DRAIN_AND_EXIT` mode; still a long poll so every SQS host is
+/// asked before the queue is declared empty.
+const DRAIN_WAIT_SECS: i32 = 1;
+
+/// SQS caps message visibility timeouts at 12 hours.
+const MAX_VISIBILITY_TIMEOUT_SECS: u64 = 43_200;
+/// How long received messages stay hidden while they are forwarded.
+const RECEIVE_VISIBILITY_TIMEOUT_SECS: i32 = 60;
+/// SQS caps per-message send delays at 15 minutes.
+const MAX_DELAY_SECS: u64 = 900;
+/// First and longest pause between `STARTUP_WAIT_SECS` probes.
+const STARTUP_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const STARTUP_PROBE_INTERVAL_MAX: Duration = Duration::from_secs(5);
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e:?}");
+        std::process::exit(e.downcast_ref::<ExitKind>().map_or(1, |kind| *kind as i32));
+    }
+}
+
+async fn run() -> Result<()> {
+    if env::args().skip(1).any(|arg| arg == "--version" || arg == "-V") {
+        println!("{} {} ({})", env!("CARGO_PKG_NAME"), VERSION, GIT_HASH);
+        return Ok(());
+    }
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
         .with_target(false)
         .with_ansi(true)
         .compact()
-        .init();
+        .with_filter(EnvFilter::from_default_env());
+
+    #[cfg(feature = "otel")]
+    let (otel_layer, otel_provider) = otel::layer().context(ExitKind::Config)?.unzip();
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry().with(fmt_layer).with(otel_layer).init();
+
+    #[cfg(not(feature = "otel"))]
+    if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok_and(|v| !v.is_empty()) {
+        warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set but this build lacks the `otel` feature; traces will not be exported");
+    }
+
+    info!(version = VERSION, git_hash = GIT_HASH, "{} {} ({})", env!("CARGO_PKG_NAME"), VERSION, GIT_HASH);
+
+    let config_path = config_path();
+    let mut config = Config::load(config_path.as_deref()).context(ExitKind::Config)?;
+    if env::args().skip(1).any(|arg| arg == "--once") {
+        config.drain_and_exit = true;
+    }
+
+    let shared_config = load_aws_config(config.aws_profile.as_deref(), config.assume_role.as_ref()).await;
+    log_aws_identity(&shared_config, config.aws_profile.as_deref(), config.assume_role.as_ref()).await;
+    let sqs = SqsClient::new(&shared_config);
+    if config.extended_client {
+        let endpoint = config.s3_endpoint_url.clone();
+        config.payload_store = Some(
+            PayloadStore::from_sdk_config(&shared_config, endpoint, config.extended_client_delete_payload)
+                .context(ExitKind::Config)?,
+        );
+        info!("🪣 Fetching SQS Extended Client payloads from S3");
+    }
+    #[cfg(feature = "kafka")]
+    if config.sink == SinkKind::Kafka {
+        let (brokers, topic) = (config.kafka_brokers.as_deref().unwrap_or_default(), config.kafka_topic.as_deref().unwrap_or_default());
+        config.kafka = Some(KafkaSink::new(brokers, topic, config.kafka_ack_timeout).context(ExitKind::Config)?);
+        info!("📨 Producing to Kafka topic {} on {}", topic, brokers);
+    }
+    if let Some(name) = &config.queue_name {
+        config.queue_url = resolve_queue_url(&sqs, name, config.queue_owner_account_id.as_deref())
+            .await
+            .context(ExitKind::Aws)?;
+    }
+
+    let queue_urls = match &config.queue_prefix {
+        Some(prefix) => {
+            let urls = list_queue_urls(&sqs, prefix).await.context(ExitKind::Aws)?;
+            if urls.is_empty() && config.queue_relist_interval.is_none() {
+                return Err(anyhow::anyhow!("no queues match QUEUE_PREFIX={prefix}").context(ExitKind::Aws));
+            }
+            info!("🔎 QUEUE_PREFIX={} matched {} queue(s): {}", prefix, urls.len(), urls.join(", "));
+            urls
+        }
+        None => vec![config.queue_url.clone()],
+    };
+
+    let (http, local_url) = build_http_client(&config.client, &config.local_url).context(ExitKind::Config)?;
+
+    // Nothing is POSTed to LOCAL_URL with another sink
+    let posts_local = config.sink == SinkKind::Http;
+    if let Some(max_wait) = config.startup_wait.filter(|_| posts_local) {
+        wait_for_local(&http, &local_url, max_wait).await;
+    }
+    if config.preflight {
+        for queue_url in &queue_urls {
+            let local_url = Some(local_url.as_str()).filter(|_| posts_local);
+            preflight(&sqs, &http, queue_url, local_url, config.preflight_require_local).await?;
+        }
+    }
+
+    match config.sink {
+        SinkKind::Http => info!("🚀 Relay starting. Queue={}, Local={}", queue_urls.join(", "), local_url),
+        SinkKind::Kafka => info!("🚀 Relay starting. Queue={}, Kafka topic={}", queue_urls.join(", "), config.kafka_topic.as_deref().unwrap_or_default()),
+    }
+    if !config.fanout_urls.is_empty() {
+        info!("🔀 Fanning out to {} (FANOUT_POLICY={:?})", config.fanout_urls.join(", "), config.fanout_policy);
+    }
+    for route in &config.routes {
+        info!("🧭 Route {}: timeout {:?}, {} extra header(s)", route.url, route.timeout, route.headers.len());
+    }
+    if config.drain_and_exit {
+        info!("🚰 Drain mode: exiting once the queue is empty");
+    }
+    if config.forward_compression == ForwardCompression::Gzip {
+        warn!(
+            "🗜️  Gzipping bodies of {}+ bytes; consumers must verify X-Hub-Signature-256/X-Relay-Signature over the decompressed body",
+            config.compression_min_bytes
+        );
+    }
+    info!(
+        "404 policy: {}",
+        if config.delete_on_404 { "delete (DELETE_ON_404=true)" } else { "retry (DELETE_ON_404=false)" }
+    );
+    info!("🔍 Use RUST_LOG=debug for verbose output");
+    info!("⏹️  Ctrl-C to stop.");
+
+    let status = Arc::new(RelayStatus::new(config.recent_buffer_size, config.clock.clone()));
+    if let Some(port) = config.health_port {
+        let listener = health::bind(config.health_bind_addr, port).await.context(ExitKind::Config)?;
+        tokio::spawn(health::serve(listener, status.clone()));
+        // Only read by /status and /metrics, so not worth the API calls without them;
+        // the gauges describe a single queue
+        if let Some(interval) = config.queue_depth_interval.filter(|_| config.queue_prefix.is_none()) {
+            tokio::spawn(poll_queue_depth(sqs.clone(), config.queue_url.clone(), interval, status.clone()));
+        }
+    }
+
+    if config.poller_count > 1 {
+        info!("🧵 Polling with {} concurrent receivers", config.poller_count);
+    }
+    // Each poller keeps its own tally so they never contend, and adds it here
+    // once it stops or is dropped at Ctrl-C
+    let total = RefCell::new(RelayStats::default());
+    let live = LiveConfig::new(config);
+    let config = live.current();
+    #[cfg(unix)]
+    let mut hangups = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .context("failed to listen for SIGHUP")
+        .context(ExitKind::Config)?;
+    #[cfg(not(unix))]
+    let mut hangups = ();
+    let (sqs, http, local_url, live, status, total) = (&sqs, &http, local_url.as_str(), &live, &*status, &total);
+    let start = |queue_url: String| {
+        (0..config.poller_count).map(move |_| run_poller(sqs, http, queue_url.clone(), local_url, live, status, total))
+    };
+    let mut polled: HashSet<String> = queue_urls.iter().cloned().collect();
+    let mut pollers: FuturesUnordered<_> = queue_urls.into_iter().flat_map(start).collect();
+    let relay = async {
+        let mut relist = config.queue_relist_interval.map(|every| tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+        // Only an ongoing relist keeps the relay up with no queues to poll
+        let keep_listing = relist.is_some() && !config.drain_and_exit && config.max_runtime.is_none();
+        loop {
+            tokio::select! {
+                Some(queue_url) = pollers.next() => {
+                    polled.remove(&queue_url);
+                    if pollers.is_empty() && !keep_listing {
+                        return;
+                    }
+                }
+                _ = async { relist.as_mut().expect("guarded").tick().await }, if relist.is_some() => {
+                    let prefix = config.queue_prefix.as_deref().unwrap_or_default();
+                    match list_queue_urls(sqs, prefix).await {
+                        Ok(urls) => {
+                            for url in urls {
+                                if polled.insert(url.clone()) {
+                                    info!("🆕 Found queue {} matching QUEUE_PREFIX={}; polling it", url, prefix);
+                                    pollers.extend(start(url));
+                                }
+                            }
+                        }
+                        Err(e) => warn!("Failed to re-list queues: {:#}", e),
+                    }
+                }
+                _ = next_hangup(&mut hangups) => {
+                    // Batches already in flight finish under the config they started with
+                    match live.current().reload(config_path.as_deref()) {
+                        Ok(reloaded) => live.replace(reloaded),
+                        Err(e) => error!("Config reload failed; keeping the current config: {:#}", e),
+                    }
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = relay => {},
+        _ = signal::ctrl_c() => {
+            info!("Received Ctrl-C, shutting down.");
+        }
+    }
+    drop(pollers);
+    let stats = total.take();
+    info!(
+        processed = stats.processed,
+        forwarded = stats.forwarded,
+        deleted = stats.deleted,
+        retried = stats.retried,
+        failed = stats.failed,
+        dead_lettered = stats.dead_lettered,
+        filtered = stats.filtered,
+        duplicates = stats.duplicates,
+        handed_off = stats.handed_off,
+        deferred = stats.deferred,
+        "🏁 Relay stopped"
+    );
+
+    #[cfg(feature = "otel")]
+    if let Some(provider) = otel_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!("Failed to flush OpenTelemetry spans: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Config file path from `--config <path>` / `--config=<path>`, else `RELAY_CONFIG`.
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+    env::var_os("RELAY_CONFIG").filter(|p| !p.is_empty()).map(PathBuf::from)
+}
+
+/// Loads the shared AWS config, pinned to `AWS_PROFILE` when one is configured
+/// so the default chain can't silently pick another profile. With
+/// `ASSUME_ROLE_ARN` the resolved credentials are only used to call STS, and
+/// the role's (auto-refreshed) credentials replace them.
+async fn load_aws_config(profile: Option<&str>, assume_role: Option<&AssumeRole>) -> SdkConfig {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(profile) = profile {
+        loader = loader.profile_name(profile);
+    }
+    let base = loader.load().await;
+    let Some(role) = assume_role else {
+        return base;
+    };
+    let mut provider = AssumeRoleProvider::builder(&role.role_arn).configure(&base);
+    if let Some(name) = &role.session_name {
+        provider = provider.session_name(name);
+    }
+    if let Some(id) = &role.external_id {
+        provider = provider.external_id(id);
+    }
+    let provider = provider.build().await;
+    base.into_builder().credentials_provider(SharedCredentialsProvider::new(provider)).build()
+}
+
+/// Logs which profile, region and credential provider are in use. The
+/// credentials' `Debug` output redacts the secret key and session token.
+async fn log_aws_identity(shared_config: &SdkConfig, profile: Option<&str>, assume_role: Option<&AssumeRole>) {
+    let region = shared_config.region().map(|r| r.to_string());
+    info!(
+        "🔑 AWS profile={}, region={}",
+        profile.unwrap_or("(default chain)"),
+        region.as_deref().unwrap_or("(unset)")
+    );
+    if let Some(role) = assume_role {
+        info!(
+            "🎭 Assuming role {} (session {}, external id {})",
+            role.role_arn,
+            role.session_name.as_deref().unwrap_or("(generated)"),
+            if role.external_id.is_some() { "set" } else { "unset" }
+        );
+    }
+    let Some(provider) = shared_config.credentials_provider() else {
+        warn!("No AWS credentials provider configured");
+        return;
+    };
+    match provider.provide_credentials().await {
+        Ok(creds) => info!("🔑 AWS credentials resolved: {:?}", creds),
+        Err(e) => warn!("Could not resolve AWS credentials: {}", DisplayErrorContext(&e)),
+    }
+}
+
+/// Builds the forward client, returning it with the URL requests should target
+/// (rewritten to `http://localhost/...` for Unix sockets).
+fn build_http_client(cfg: &ClientConfig, local_url: &str) -> Result<(reqwest::Client, String)> {
+    let mut local_url = local_url.to_string();
+    let mut http_builder = reqwest::Client::builder()
+        .timeout(cfg.timeout)
+        // Request headers (e.g. a user-agent attribute) take precedence over this
+        .user_agent(&cfg.user_agent);
+
+    // Connection pool / keep-alive tuning; unset settings keep reqwest's defaults
+    if let Some(max_idle) = cfg.pool_max_idle_per_host {
+        http_builder = http_builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle) = cfg.pool_idle_timeout {
+        http_builder = http_builder.pool_idle_timeout(idle);
+    }
+    match cfg.http_version {
+        HttpVersion::Auto => {}
+        HttpVersion::Http1 => http_builder = http_builder.http1_only(),
+        HttpVersion::Http2 => http_builder = http_builder.http2_prior_knowledge(),
+    }
+    // TLS settings for the forward client only; the AWS client is unaffected
+    if let Some(ca_path) = &cfg.ca_bundle {
+        let pem = std::fs::read(ca_path)
+            .with_context(|| format!("failed to read LOCAL_CA_BUNDLE {}", ca_path.display()))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("failed to parse LOCAL_CA_BUNDLE {}", ca_path.display()))?;
+        info!("🔐 Trusting {} certificate(s) from {}", certs.len(), ca_path.display());
+        for cert in certs {
+            http_builder = http_builder.add_root_certificate(cert);
+        }
+    }
+    if let Some((cert_path, key_path)) = &cfg.client_identity {
+        let mut pem = std::fs::read(cert_path)
+            .with_context(|| format!("failed to read LOCAL_CLIENT_CERT {}", cert_path.display()))?;
+        let key = std::fs::read(key_path)
+            .with_context(|| format!("failed to read LOCAL_CLIENT_KEY {}", key_path.display()))?;
+        pem.push(b'\n');
+        pem.extend_from_slice(&key);
+        let identity = reqwest::Identity::from_pem(&pem)
+            .context("failed to parse LOCAL_CLIENT_CERT/LOCAL_CLIENT_KEY as PEM")?;
+        info!("🔐 Using client certificate {} for forwards", cert_path.display());
+        // PEM identities are only supported by the rustls backend
+        http_builder = http_builder.use_rustls_tls().identity(identity);
+    }
+    if cfg.danger_accept_invalid_certs {
+        warn!("{}", "⚠️  DANGER_ACCEPT_INVALID_CERTS=true: TLS certificate verification is DISABLED for forwards".red().bold());
+        http_builder = http_builder.danger_accept_invalid_certs(true);
+    }
+
+    // unix:///path/to.sock forwards over a Unix domain socket; the HTTP request
+    // itself targets localhost with LOCAL_UNIX_REQUEST_PATH.
+    if let Some(socket_path) = local_url.strip_prefix("unix://") {
+        #[cfg(unix)]
+        {
+            let socket_path = socket_path.to_string();
+            info!("🔌 Forwarding over Unix socket {}", socket_path);
+            http_builder = http_builder.unix_socket(socket_path);
+            local_url = format!("http://localhost/{}", cfg.unix_request_path.trim_start_matches('/'));
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("LOCAL_URL unix://{socket_path} uses a Unix socket, which is not supported on this platform");
+    }
+
+    Ok((http_builder.build()?, local_url))
+}
+
+/// Looks up the URL of the queue called `name` in the configured region.
+async fn resolve_queue_url(sqs: &SqsClient, name: &str, owner: Option<&str>) -> Result<String> {
+    let output = sqs
+        .get_queue_url()
+        .queue_name(name)
+        .set_queue_owner_aws_account_id(owner.map(String::from))
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(&e)))
+        .with_context(|| format!("cannot resolve QUEUE_NAME={name}; check AWS_REGION, QUEUE_OWNER_ACCOUNT_ID and credentials (sqs:GetQueueUrl)"))?;
+    let url = output.queue_url().context("GetQueueUrl returned no URL")?.to_string();
+    info!("🔎 Resolved QUEUE_NAME={} to {}", name, url);
+    Ok(url)
+}
+
+/// URLs of the queues whose names start with `prefix`.
+async fn list_queue_urls(sqs: &SqsClient, prefix: &str) -> Result<Vec<String>> {
+    let mut urls = Vec::new();
+    let mut next_token = None;
+    loop {
+        let output = sqs
+            .list_queues()
+            .queue_name_prefix(prefix)
+            .max_results(1000)
+            .set_next_token(next_token)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(&e)))
+            .with_context(|| format!("cannot list queues for QUEUE_PREFIX={prefix}; check AWS_REGION and credentials (sqs:ListQueues)"))?;
+        urls.extend_from_slice(output.queue_urls());
+        next_token = output.next_token().map(String::from);
+        if next_token.is_none() {
+            return Ok(urls);
+        }
+    }
+}
+
+/// Reads the queue's approximate message counts every `interval` for the
+/// `relay_queue_depth` gauges, independently of message polling.
+async fn poll_queue_depth(sqs: SqsClient, queue_url: String, interval: Duration, status: Arc<RelayStatus>) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let attrs = match sqs
+            .get_queue_attributes()
+            .queue_url(&queue_url)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesNotVisible)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesDelayed)
+            .send()
+            .await
+        {
+            Ok(out) => out.attributes.unwrap_or_default(),
+            Err(e) => {
+                debug!("Failed to read queue depth: {}", DisplayErrorContext(&e));
+                continue;
+            }
+        };
+        let count = |name| attrs.get(&name).and_then(|v| v.parse().ok()).unwrap_or(0);
+        status.set_queue_depth(QueueDepth {
+            visible: count(QueueAttributeName::ApproximateNumberOfMessages),
+            in_flight: count(QueueAttributeName::ApproximateNumberOfMessagesNotVisible),
+            delayed: count(QueueAttributeName::ApproximateNumberOfMessagesDelayed),
+        });
+    }
+}
+
+/// Waits, with backoff, until the local endpoint accepts connections (any HTTP
+/// response counts) or `max_wait` passes, so a service starting alongside the
+/// relay doesn't fail its first forwards. Returns whether it came up.
+async fn wait_for_local(http: &reqwest::Client, local_url: &str, max_wait: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + max_wait;
+    for attempt in 1.. {
+        match http.head(local_url).send().await {
+            Ok(_) if attempt == 1 => return true,
+            Ok(rsp) => {
+                info!("✅ Local endpoint is up (HEAD answered {}) after {} probe(s)", rsp.status(), attempt);
+                return true;
+            }
+            Err(e) => debug!("Startup probe {} to {} failed: {}", attempt, local_url, e),
+        }
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if attempt == 1 {
+            info!("⏳ Waiting up to {:?} for {} to accept connections", max_wait, local_url);
+        }
+        let delay = error_backoff(STARTUP_PROBE_INTERVAL, STARTUP_PROBE_INTERVAL_MAX, attempt);
+        tokio::time::sleep(delay.min(remaining)).await;
+    }
+    warn!("{} Local endpoint {} still not reachable after STARTUP_WAIT_SECS; polling anyway", "⚠️".yellow(), local_url);
+    false
+}
+
+/// Startup checks: the queue must be reachable with the current credentials,
+/// while an unreachable local endpoint only warns since it may start later
+/// (unless `require_local`).
+async fn preflight(sqs: &SqsClient, http: &reqwest::Client, queue_url: &str, local_url: Option<&str>, require_local: bool) -> Result<()> {
+    debug!("🩺 Preflight: checking queue access");
+    sqs.get_queue_attributes()
+        .queue_url(queue_url)
+        .attribute_names(QueueAttributeName::QueueArn)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e.into_service_error()))
+        .with_context(|| {
+            format!("preflight: cannot access queue {queue_url}; check QUEUE_URL, AWS_REGION and credentials (sqs:GetQueueAttributes)")
+        })
+        .context(ExitKind::Aws)?;
+    debug!("✅ Preflight: queue is accessible");
+
+    let Some(local_url) = local_url else {
+        return Ok(());
+    };
+    match http.head(local_url).send().await {
+        Ok(rsp) => debug!("✅ Preflight: local endpoint answered HEAD with {}", rsp.status()),
+        Err(e) if require_local => {
+            return Err(anyhow::Error::new(e))
+                .with_context(|| format!("preflight: local endpoint {local_url} is not reachable"))
+                .context(ExitKind::LocalUnreachable);
+        }
+        Err(e) => warn!("{} Preflight: local endpoint {} is not reachable yet: {}", "⚠️".yellow(), local_url, e),
+    }
+    Ok(())
+}
+
+/// Token bucket limiting forwards per second; callers wait for a token rather than dropping.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: std::time::Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, clock: Arc<dyn Clock>) -> Self {
+        // Allow bursts of up to one second's worth of tokens
+        let capacity = rate.max(1.0);
+        Self { rate, capacity, tokens: capacity, last_refill: clock.instant(), clock }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.instant();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            debug!("Rate limit reached, waiting {:?} for a forward token", wait);
+            tokio::time::sleep(wait).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    // Non-zero so `RelayStatus` can use 0 for "no breaker"
+    Closed = 1,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    const ALL: [Self; 3] = [Self::Closed, Self::Open, Self::HalfOpen];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half-open",
+        }
+    }
+}
+
+/// Pauses SQS polling while the local endpoint is failing.
+struct CircuitBreaker {
+    threshold: u32,
+    consecutive_failures: u32,
+    /// Successes in a row needed before the failure count starts over.
+    reset_after: u32,
+    consecutive_successes: u32,
+    state: CircuitState,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, reset_after: u32) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            consecutive_failures: 0,
+            reset_after: reset_after.max(1),
+            consecutive_successes: 0,
+            state: CircuitState::Closed,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.state == CircuitState::Open
+    }
+
+    fn half_open(&mut self) {
+        debug!("Circuit half-open, probing local endpoint");
+        self.state = CircuitState::HalfOpen;
+    }
+
+    /// Closes the circuit; the failure count is only cleared once `reset_after`
+    /// successes in a row show the endpoint is stable, so a flappy one re-trips fast.
+    fn record_success(&mut self) {
+        if self.state != CircuitState::Closed {
+            info!("{} Circuit closed, local endpoint recovered; resuming polling", "🟢".green());
+        }
+        self.state = CircuitState::Closed;
+        self.consecutive_successes += 1;
+        if self.consecutive_successes >= self.reset_after {
+            self.consecutive_failures = 0;
+        }
+    }
+
+    /// Opens the circuit right away (`CONNECT_ERROR_OPENS_CIRCUIT`).
+    fn trip(&mut self) {
+        self.consecutive_successes = 0;
+        self.consecutive_failures += 1;
+        if self.state != CircuitState::Open {
+            warn!("{} Circuit open: local endpoint refused the connection; pausing polling", "🔴".red());
+            self.state = CircuitState::Open;
+        }
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_successes = 0;
+        self.consecutive_failures += 1;
+        match self.state {
+            CircuitState::HalfOpen => self.state = CircuitState::Open,
+            CircuitState::Closed if self.consecutive_failures >= self.threshold => {
+                warn!("{} Circuit open after {} consecutive local failures; pausing polling", "🔴".red(), self.consecutive_failures);
+                self.state = CircuitState::Open;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Most messages one receive may ask for.
+const MAX_RECEIVE_BATCH: i32 = 10;
+/// Throttled receives in a row beyond which polling slows no further.
+const MAX_THROTTLE_LEVEL: u32 = 4;
+
+/// Slows polling while SQS throttles receives: each throttled call halves the
+/// batch size and doubles the backoff, and each successful one undoes a step.
+#[derive(Debug, Default)]
+struct ReceiveThrottle {
+    level: u32,
+}
+
+impl ReceiveThrottle {
+    fn is_active(&self) -> bool {
+        self.level > 0
+    }
+
+    fn batch_size(&self) -> i32 {
+        (MAX_RECEIVE_BATCH >> self.level).max(1)
+    }
+
+    /// Steps up after a throttled receive, returning how long to back off.
+    fn throttled(&mut self, base: Duration, max: Duration) -> Duration {
+        if !self.is_active() {
+            warn!("{} SQS is throttling receives; slowing down polling", "🐢".yellow());
+        }
+        self.level = (self.level + 1).min(MAX_THROTTLE_LEVEL);
+        error_backoff(base, max, self.level)
+    }
+
+    fn received(&mut self) {
+        if self.is_active() {
+            self.level -= 1;
+            if !self.is_active() {
+                info!("SQS throttling has subsided; resuming normal polling");
+            }
+        }
+    }
+}
+
+/// Waits for the next SIGHUP, which asks for a config reload.
+#[cfg(unix)]
+async fn next_hangup(hangups: &mut signal::unix::Signal) {
+    hangups.recv().await;
+}
+
+#[cfg(not(unix))]
+async fn next_hangup(_: &mut ()) {
+    std::future::pending().await
+}
+
+/// Adds a poller's tally to the relay's total when the poller ends, however it ends.
+struct Tally<'a> {
+    stats: RelayStats,
+    total: &'a RefCell<RelayStats>,
+}
+
+impl Drop for Tally<'_> {
+    fn drop(&mut self) {
+        self.total.borrow_mut().add(&self.stats);
+    }
+}
+
+/// Runs one [`relay_loop`] on `queue_url`, returning the URL once it stops.
+async fn run_poller(
+    sqs: &SqsClient,
+    http: &reqwest::Client,
+    queue_url: String,
+    local_url: &str,
+    live: &LiveConfig,
+    status: &RelayStatus,
+    total: &RefCell<RelayStats>,
+) -> String {
+    let mut tally = Tally { stats: RelayStats::default(), total };
+    relay_loop(sqs, http, &queue_url, local_url, live, &mut tally.stats, status).await;
+    queue_url
+}
+
+/// Polls and forwards until a configured limit is reached (or forever). Each
+/// batch is handled under the config current when it was received; what the
+/// loop itself is built from (breaker, rate limit, deadline) stays as it started.
+async fn relay_loop(
+    sqs: &SqsClient,
+    http: &reqwest::Client,
+    queue_url: &str,
+    local_url: &str,
+    live: &LiveConfig,
+    stats: &mut RelayStats,
+    status: &RelayStatus,
+) {
+    let startup = live.current();
+    let opts: &Config = &startup;
+    debug!("🔄 Starting relay loop, polling SQS every 20 seconds...");
+
+    // Rebuilt from a fresh AWS config if credentials keep failing
+    let mut sqs = sqs.clone();
+    let mut credential_errors: u32 = 0;
+    let mut receive_errors: u32 = 0;
+    let mut receive_successes: u32 = 0;
+    let mut throttle = ReceiveThrottle::default();
+
+    let deadline = opts.max_runtime.map(|d| tokio::time::Instant::now() + d);
+    let fifo = queue_url.ends_with(".fifo");
+    if fifo {
+        info!("📚 FIFO queue detected; preserving per-group ordering");
+    }
+
+    let mut breaker = opts.circuit_fail_threshold.map(|threshold| CircuitBreaker::new(threshold, opts.success_reset_threshold));
+    if let Some(breaker) = &breaker {
+        status.set_circuit(breaker.state, breaker.consecutive_failures);
+    }
+    // Pollers each get an equal share of the forward budget
+    let mut limiter = opts.max_forwards_per_sec.map(|rate| TokenBucket::new(rate / opts.poller_count as f64, opts.clock.clone()));
+    let health_url = opts.health_url.as_deref().unwrap_or(local_url);
+    // Draining only needs to see the queue empty once, so don't hold the poll open
+    let wait_time_seconds = if opts.drain_and_exit { DRAIN_WAIT_SECS } else { 20 };
+
+    loop {
+        if deadline.is_some_and(|d| tokio::time::Instant::now() >= d) {
+            info!("Reached MAX_RUNTIME_SECS, stopping");
+            return;
+        }
+        let current = live.current();
+        let opts: &Config = &current;
+
+        if let Some(breaker) = breaker.as_mut() {
+            while breaker.is_open() {
+                tokio::time::sleep(opts.circuit_probe_interval).await;
+                breaker.half_open();
+                status.set_circuit(breaker.state, breaker.consecutive_failures);
+                match http.get(health_url).send().await {
+                    Ok(rsp) if !rsp.status().is_server_error() => breaker.record_success(),
+                    Ok(rsp) => {
+                        debug!("Health probe to {} returned {}", health_url, rsp.status());
+                        breaker.record_failure();
+                    }
+                    Err(e) => {
+                        debug!("Health probe to {} failed: {}", health_url, e);
+                        breaker.record_failure();
+                    }
+                }
+                status.set_circuit(breaker.state, breaker.consecutive_failures);
+            }
+        }
+
+        if opts.poll_jitter_ms > 0 {
+            let delay = rand::rng().random_range(0..=opts.poll_jitter_ms);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        // Space polls out until SQS stops throttling
+        if throttle.is_active() {
+            tokio::time::sleep(jittered(opts.throttle_backoff, opts.jitter_fraction)).await;
+        }
+
+        debug!("📡 Polling SQS for messages...");
+        let receive = sqs
+            .receive_message()
+            .queue_url(queue_url)
+            .max_number_of_messages(throttle.batch_size())
+            .wait_time_seconds(wait_time_seconds)   // long polling
+            .visibility_timeout(RECEIVE_VISIBILITY_TIMEOUT_SECS)  // time to process locally
+            .message_attribute_names("All")
+            .set_message_system_attribute_names(Some(system_attribute_names(opts)))
+            .send()
+            .instrument(info_span!("sqs.receive", queue = queue_url));
+        let received = match deadline {
+            Some(d) => match tokio::time::timeout_at(d, receive).await {
+                Ok(r) => r,
+                Err(_) => {
+                    info!("Reached MAX_RUNTIME_SECS, stopping");
+                    return;
+                }
+            },
+            None => receive.await,
+        };
+        let resp = match received {
+            Ok(r) => {
+                debug!(aws_request_id = r.request_id(), "✅ SQS poll successful: {} message(s)", r.messages().len());
+                status.poll_succeeded();
+                credential_errors = 0;
+                throttle.received();
+                // The backoff only narrows once receives have been healthy for a while
+                receive_successes = receive_successes.saturating_add(1);
+                if receive_successes >= opts.success_reset_threshold && receive_errors > 0 {
+                    receive_errors = 0;
+                    status.set_receive_backoff(Duration::ZERO);
+                }
+                r
+            },
+            Err(e) => {
+                error!(aws_request_id = e.request_id(), "❌ SQS receive error: {e}");
+                status.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+                status.record(None, Some(&format!("SQS receive error: {e}")));
+                // Matching queues come and go; a deleted one is simply no longer polled
+                if opts.queue_prefix.is_some() && is_missing_queue_error(&e) {
+                    warn!("Queue {} no longer exists; stopping its poller", queue_url);
+                    return;
+                }
+                if is_throttling_error(&e) {
+                    credential_errors = 0;
+                    receive_successes = 0;
+                    let delay = throttle.throttled(opts.throttle_backoff, opts.throttle_backoff_max);
+                    status.set_receive_backoff(delay);
+                    debug!("Backing off {:?} while throttled; receiving up to {} message(s) per poll", delay, throttle.batch_size());
+                    tokio::time::sleep(jittered(delay, opts.jitter_fraction)).await;
+                    continue;
+                }
+                if is_credential_error(&e) {
+                    credential_errors += 1;
+                    if credential_errors >= opts.credential_reload_threshold {
+                        warn!(
+                            "{} {} consecutive credential errors; reloading AWS config and rebuilding the SQS client",
+                            "🔑".yellow(),
+                            credential_errors
+                        );
+                        sqs = SqsClient::new(&load_aws_config(opts.aws_profile.as_deref(), opts.assume_role.as_ref()).await);
+                        credential_errors = 0;
+                    }
+                } else {
+                    credential_errors = 0;
+                }
+                receive_successes = 0;
+                receive_errors = receive_errors.saturating_add(1);
+                let delay = error_backoff(opts.error_backoff, opts.error_backoff_max, receive_errors);
+                status.set_receive_backoff(delay);
+                debug!("Backing off {:?} after {} consecutive receive error(s)", delay, receive_errors);
+                tokio::time::sleep(jittered(delay, opts.jitter_fraction)).await;
+                continue;
+            }
+        };
+
+        let msgs = resp.messages();
+        if msgs.is_empty() {
+            debug!("No messages received from SQS");
+            if opts.drain_and_exit {
+                info!("Queue is empty; drained {} message(s), stopping", stats.processed);
+                return;
+            }
+            if !opts.idle_sleep.is_zero() {
+                debug!("Idle, sleeping {:?} before the next poll", opts.idle_sleep);
+                tokio::time::sleep(opts.idle_sleep).await;
+            }
+            continue;
+        }
+
+        info!("📥 Received {} message(s) from SQS", msgs.len());
+        status.batch_size.fetch_add(msgs.len(), Ordering::Relaxed);
+
+        if opts.batch_forward {
+            let span = info_span!("batch", queue = queue_url, size = msgs.len(), status = tracing::field::Empty);
+            status.in_flight.fetch_add(msgs.len(), Ordering::Relaxed);
+            let batch = relay_batch(&sqs, http, queue_url, local_url, opts, limiter.as_mut(), msgs).instrument(span);
+            let outcomes = catch_panic(batch, |panic| {
+                error!("{} Panic while relaying a batch of {} message(s); leaving them for redelivery: {}", "💥".red(), msgs.len(), panic);
+                msgs.iter().map(|m| (m, MessageOutcome::panicked(&panic))).collect()
+            })
+            .await;
+            status.in_flight.fetch_sub(msgs.len(), Ordering::Relaxed);
+            status.batch_size.fetch_sub(msgs.len(), Ordering::Relaxed);
+
+            for (m, outcome) in &outcomes {
+                record_outcome(m, queue_url, opts, outcome, stats, status);
+            }
+            if let (Some(breaker), Some(local_down)) = (breaker.as_mut(), outcomes.iter().find_map(|(_, o)| o.local_down)) {
+                if opts.connect_error_opens_circuit && outcomes.iter().any(|(_, o)| o.error_kind == Some(ForwardErrorKind::Connect)) {
+                    breaker.trip();
+                } else if local_down {
+                    breaker.record_failure();
+                } else {
+                    breaker.record_success();
+                }
+                status.set_circuit(breaker.state, breaker.consecutive_failures);
+            }
+            if opts.max_messages_total.is_some_and(|max| stats.processed >= max) {
+                info!("Reached MAX_MESSAGES_TOTAL={}, stopping", stats.processed);
+                return;
+            }
+            continue;
+        }
+
+        // FIFO groups whose earlier message in this batch was not deleted; later
+        // messages in the same group must wait so ordering is preserved.
+        let mut blocked_groups: HashSet<&str> = HashSet::new();
+        // Messages still waiting are hidden for the receive visibility timeout from
+        // this point; extend them before it runs out so a slow batch isn't redelivered
+        let mut visible_from = opts.clock.instant();
+        let extend_after = Duration::from_secs(RECEIVE_VISIBILITY_TIMEOUT_SECS as u64).mul_f64(opts.visibility_extend_fraction);
+
+        for (i, m) in msgs.iter().enumerate() {
+            if !extend_after.is_zero() && opts.clock.instant().duration_since(visible_from) >= extend_after {
+                extend_visibility(&sqs, queue_url, &msgs[i..]).await;
+                visible_from = opts.clock.instant();
+            }
+            let message_id = m.message_id().unwrap_or("unknown");
+            let group_id = m.attributes()
+                .and_then(|attrs| attrs.get(&MessageSystemAttributeName::MessageGroupId))
+                .map(String::as_str);
+            if let Some(group) = group_id.filter(|g| fifo && blocked_groups.contains(g)) {
+                debug!("Skipping message {} (group {}): an earlier message in the group was not deleted", message_id, group);
+                continue;
+            }
+
+            let span = info_span!(
+                "message",
+                message_id,
+                group_id,
+                request_id = tracing::field::Empty,
+                queue = queue_url,
+                attempt = tracing::field::Empty,
+                body_size = tracing::field::Empty,
+                status = tracing::field::Empty,
+            );
+            status.in_flight.fetch_add(1, Ordering::Relaxed);
+            let handle = async {
+                if let Some(filter) = opts.filter.as_ref().filter(|f| f.matches(m)) {
+                    return Some(drop_filtered(&sqs, queue_url, m, filter.attribute()).await);
+                }
+                if let Some(key) = opts.dedup.as_ref().and_then(|d| d.recent(m, opts.clock.as_ref())) {
+                    return Some(drop_duplicate(&sqs, queue_url, m, &key).await);
+                }
+                if let Some(deliver_at) = opts.deliver_after_attribute.as_deref().and_then(|a| scheduled_delivery(m, a, opts.clock.as_ref())) {
+                    return Some(defer_scheduled(&sqs, queue_url, m, deliver_at, opts.clock.as_ref()).await);
+                }
+                let result = process_message(http, local_url, opts, limiter.as_mut(), m).await;
+                apply_outcome(&sqs, queue_url, opts, m, result).await
+            }
+            .instrument(span);
+            let outcome = catch_panic(handle, |panic| {
+                error!("{} Panic while relaying message {}; leaving it for redelivery: {}", "💥".red(), message_id, panic);
+                Some(MessageOutcome::panicked(&panic))
+            })
+            .await;
+            status.in_flight.fetch_sub(1, Ordering::Relaxed);
+            let Some(outcome) = outcome else {
+                continue;
+            };
+
+            record_outcome(m, queue_url, opts, &outcome, stats, status);
+            if let Some(group) = group_id.filter(|_| fifo && matches!(outcome.disposition, Disposition::Retried | Disposition::Deferred)) {
+                blocked_groups.insert(group);
+            }
+            if opts.max_messages_total.is_some_and(|max| stats.processed >= max) {
+                info!("Reached MAX_MESSAGES_TOTAL={}, stopping", stats.processed);
+                status.batch_size.fetch_sub(msgs.len(), Ordering::Relaxed);
+                return;
+            }
+
+            if let (Some(breaker), Some(local_down)) = (breaker.as_mut(), outcome.local_down) {
+                if opts.connect_error_opens_circuit && outcome.error_kind == Some(ForwardErrorKind::Connect) {
+                    breaker.trip();
+                } else if local_down {
+                    breaker.record_failure();
+                } else {
+                    breaker.record_success();
+                }
+                status.set_circuit(breaker.state, breaker.consecutive_failures);
+                if breaker.is_open() {
+                    // Leave the rest of the batch in the queue; it will be redelivered after recovery
+                    break;
+                }
+            }
+        }
+        status.batch_size.fetch_sub(msgs.len(), Ordering::Relaxed);
+    }
+}
+
+/// Runs `fut`, turning a panic inside it into `on_panic(message)` so one bad
+/// message can't take the relay loop down with it.
+async fn catch_panic<T>(fut: impl Future<Output = T>, on_panic: impl FnOnce(String) -> T) -> T {
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".into());
+            on_panic(message)
+        }
+    }
+}
+
+/// Where a message ended up after processing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disposition {
+    /// Deleted from the queue.
+    Deleted,
+    /// Left in (or returned to) the queue for redelivery.
+    Retried,
+    /// Moved to the dead-letter queue.
+    DeadLettered,
+    /// Matched the message filter and deleted without forwarding.
+    Filtered,
+    /// Its dedup key succeeded recently; deleted without forwarding.
+    Duplicate,
+    /// Delivered and left in the queue for the consumer to delete (`EXPOSE_RECEIPT`).
+    HandedOff,
+    /// Hidden until its scheduled delivery time without forwarding.
+    Deferred,
+}
+
+/// What the relay loop should do with a message the local endpoint answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    /// Delivered; delete it from the queue.
+    Delete,
+    /// Leave it for SQS to redeliver when the visibility timeout lapses.
+    RetrySoon,
+    /// Hide it for this long before it is redelivered.
+    RetryBackoff(Duration),
+    /// Undeliverable; delete it without retrying.
+    Drop,
+    /// Move it to the DLQ (a `STATUS_POLICY` `dlq` rule).
+    DeadLetter,
+}
+
+/// A forward the local endpoint answered, and what to do about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Decision {
+    outcome: Outcome,
+    /// HTTP status of the local response; `None` from sinks that don't speak HTTP.
+    status: Option<u16>,
+}
+
+/// What happened to a single message, for circuit breaker and tally bookkeeping.
+struct MessageOutcome {
+    /// Whether the local endpoint looked down (network error or 5xx); `None` if it wasn't contacted.
+    local_down: Option<bool>,
+    /// Forwarded and answered with a 2xx.
+    success: bool,
+    disposition: Disposition,
+    /// HTTP status of the local response, if one arrived.
+    status: Option<u16>,
+    /// Why the forward failed (network error or 5xx).
+    error: Option<String>,
+    /// Time since `SentTimestamp` when the local response arrived.
+    queue_latency: Option<Duration>,
+    /// How the forward failed when no response arrived.
+    error_kind: Option<ForwardErrorKind>,
+}
+
+impl MessageOutcome {
+    /// A message that was handled without contacting the local endpoint.
+    fn not_forwarded(disposition: Disposition) -> Self {
+        Self { local_down: None, success: false, disposition, status: None, error: None, queue_latency: None, error_kind: None }
+    }
+
+    /// Dead-lettered, or deleted without being delivered: it won't be retried.
+    fn is_terminal_failure(&self) -> bool {
+        match self.disposition {
+            Disposition::DeadLettered => true,
+            Disposition::Deleted => !self.success,
+            _ => false,
+        }
+    }
+
+    /// A message whose processing panicked; it is redelivered after its visibility timeout.
+    fn panicked(panic: &str) -> Self {
+        Self { error: Some(format!("panicked: {panic}")), ..Self::not_forwarded(Disposition::Retried) }
+    }
+
+    /// A message whose forward failed and was left for retry.
+    fn failed(e: &RelayError) -> Self {
+        Self {
+            local_down: Some(e.local_down()),
+            success: false,
+            disposition: Disposition::Retried,
+            status: e.status().map(|s| s.as_u16()),
+            error: Some(e.to_string()),
+            queue_latency: None,
+            error_kind: e.forward_error_kind(),
+        }
+    }
+}
+
+/// Running totals for a relay run.
+#[derive(Debug, Default)]
+struct RelayStats {
+    processed: u64,
+    /// Forwards answered with a 2xx.
+    forwarded: u64,
+    /// Forwards that got a non-2xx response or a network error.
+    failed: u64,
+    deleted: u64,
+    retried: u64,
+    dead_lettered: u64,
+    filtered: u64,
+    duplicates: u64,
+    handed_off: u64,
+    deferred: u64,
+}
+
+impl RelayStats {
+    fn add(&mut self, other: &RelayStats) {
+        self.processed += other.processed;
+        self.forwarded += other.forwarded;
+        self.failed += other.failed;
+        self.deleted += other.deleted;
+        self.retried += other.retried;
+        self.dead_lettered += other.dead_lettered;
+        self.filtered += other.filtered;
+        self.duplicates += other.duplicates;
+        self.handed_off += other.handed_off;
+        self.deferred += other.deferred;
+    }
+
+    fn record(&mut self, outcome: &MessageOutcome) {
+        self.processed += 1;
+        if outcome.success {
+            self.forwarded += 1;
+        } else if outcome.local_down.is_some() {
+            self.failed += 1;
+        }
+        match outcome.disposition {
+            Disposition::Deleted => self.deleted += 1,
+            Disposition::Retried => self.retried += 1,
+            Disposition::DeadLettered => self.dead_lettered += 1,
+            Disposition::Filtered => self.filtered += 1,
+            Disposition::Duplicate => self.duplicates += 1,
+            Disposition::HandedOff => self.handed_off += 1,
+            Disposition::Deferred => self.deferred += 1,
+        }
+    }
+}
+
+/// Folds one message's outcome into the run's stats, `/status` and `/recent`,
+/// and alerts `NOTIFY_URL` when it failed for good.
+fn record_outcome(m: &Message, queue_url: &str, opts: &Config, outcome: &MessageOutcome, stats: &mut RelayStats, status: &RelayStatus) {
+    stats.record(outcome);
+    if outcome.disposition == Disposition::Duplicate {
+        status.duplicates_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+    if status.keeps_recent() {
+        status.record_recent(recent_entry(m, opts, outcome));
+    }
+    if let Some(notifier) = opts.notify.as_ref().filter(|_| outcome.is_terminal_failure()) {
+        if let Some(alert) = notifier.alert(&recent_entry(m, opts, outcome), queue_url, opts.clock.as_ref()) {
+            notifier.send(alert);
+        }
+    }
+    status.record(outcome.status, outcome.error.as_deref());
+    if let Some(latency) = outcome.queue_latency {
+        status.observe_queue_latency(latency);
+    }
+}
+
+/// A `/recent` entry for a processed message. The summary is taken from the
+/// body as received, without decryption or `BODY_TRANSFORM`.
+fn recent_entry(m: &Message, opts: &Config, outcome: &MessageOutcome) -> Value {
+    let body = m.body().unwrap_or_default();
+    let is_b64 = m
+        .message_attributes()
+        .and_then(|attrs| attrs.get("BodyIsBase64"))
+        .and_then(|v| v.string_value())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true"));
+    let bytes = match is_b64 {
+        true => general_purpose::STANDARD.decode(body).unwrap_or_else(|_| body.as_bytes().to_vec()),
+        false => body.as_bytes().to_vec(),
+    };
+    let summary = if decrypt::encryption_scheme(m).is_some() {
+        format!("encrypted:{}", bytes.len())
+    } else if opts.skip_body_inspection {
+        format!("bytes:{}", bytes.len())
+    } else {
+        extract_webhook_summary_from_bytes(&bytes, serde_json::from_slice::<Value>(&bytes).ok().as_ref(), &opts.previews)
+    };
+    serde_json::json!({
+        "message_id": m.message_id(),
+        "summary": summary,
+        "status": outcome.status,
+        "disposition": format!("{:?}", outcome.disposition).to_lowercase(),
+        "attempt": attempt(m, opts),
+        "error": outcome.error,
+    })
+}
+
+/// Deletes a message that matched the filter without forwarding it.
+async fn drop_filtered(sqs: &SqsClient, queue_url: &str, m: &Message, attribute: &str) -> MessageOutcome {
+    let message_id = m.message_id().unwrap_or("unknown");
+    info!("{} Message {} filtered by attribute '{}'; deleting without forwarding", "🚫".dimmed(), message_id, attribute);
+    let receipt = m.receipt_handle().unwrap_or_default();
+    let disposition = if delete_message(sqs, queue_url, receipt, message_id).await {
+        Disposition::Filtered
+    } else {
+        Disposition::Retried
+    };
+    MessageOutcome::not_forwarded(disposition)
+}
+
+/// Deletes a message whose dedup key was forwarded successfully within `DEDUP_TTL_SECS`.
+async fn drop_duplicate(sqs: &SqsClient, queue_url: &str, m: &Message, key: &str) -> MessageOutcome {
+    let message_id = m.message_id().unwrap_or("unknown");
+    info!("{} Message {} is a duplicate of recently delivered key '{}'; deleting without forwarding", "♻️".dimmed(), message_id, key);
+    let receipt = m.receipt_handle().unwrap_or_default();
+    let disposition = if delete_message(sqs, queue_url, receipt, message_id).await {
+        Disposition::Duplicate
+    } else {
+        Disposition::Retried
+    };
+    MessageOutcome::not_forwarded(disposition)
+}
+
+/// When the message's `DELIVER_AFTER_ATTRIBUTE` (epoch seconds) schedules it,
+/// if that time is still ahead. Unparseable values are ignored with a warning.
+fn scheduled_delivery(m: &Message, attribute: &str, clock: &dyn Clock) -> Option<SystemTime> {
+    let value = m
+        .message_attributes()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+        .and_then(|(_, v)| v.string_value())?
+        .trim();
+    let Some(secs) = value.parse().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()) else {
+        warn!("Ignoring {} attribute '{}' on message {}: expected epoch seconds", attribute, value, m.message_id().unwrap_or("unknown"));
+        return None;
+    };
+    let deliver_at = UNIX_EPOCH.checked_add(secs)?;
+    (deliver_at > clock.now()).then_some(deliver_at)
+}
+
+/// Hides a scheduled message until its delivery time instead of forwarding it.
+/// Times beyond the SQS visibility limit are reached in several deferrals.
+async fn defer_scheduled(sqs: &SqsClient, queue_url: &str, m: &Message, deliver_at: SystemTime, clock: &dyn Clock) -> MessageOutcome {
+    let message_id = m.message_id().unwrap_or("unknown");
+    let receipt = m.receipt_handle().unwrap_or_default();
+    let wait = deliver_at.duration_since(clock.now()).unwrap_or_default();
+    // Rounded up, so it isn't redelivered a moment early only to be deferred again
+    let secs = (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).min(MAX_VISIBILITY_TIMEOUT_SECS) as i32;
+    match change_visibility(sqs, queue_url, receipt, secs).await {
+        Ok(()) => {
+            info!("{} Message {} scheduled for {}; deferring {}s", "⏰".cyan(), message_id, httpdate::fmt_http_date(deliver_at), secs);
+            MessageOutcome::not_forwarded(Disposition::Deferred)
+        }
+        Err(e) => {
+            error!("Failed to defer SQS message {} until {}: {}", message_id, httpdate::fmt_http_date(deliver_at), e);
+            MessageOutcome::not_forwarded(Disposition::Retried)
+        }
+    }
+}
+
+/// Forwards a whole receive batch as one JSON array (`BATCH_FORWARD`). The
+/// forwarded messages share one outcome: all are deleted when the local
+/// endpoint accepts the batch, and all are left for retry otherwise.
+async fn relay_batch<'m>(
+    sqs: &SqsClient,
+    http: &reqwest::Client,
+    queue_url: &str,
+    local_url: &str,
+    opts: &Config,
+    limiter: Option<&mut TokenBucket>,
+    msgs: &'m [Message],
+) -> Vec<(&'m Message, MessageOutcome)> {
+    let mut outcomes = Vec::new();
+    let mut included = Vec::new();
+    let mut items = Vec::new();
+    for m in msgs {
+        if m.receipt_handle().is_none() {
+            debug!("Skipping message {}: missing receipt handle", m.message_id().unwrap_or("unknown"));
+            continue;
+        }
+        if let Some(filter) = opts.filter.as_ref().filter(|f| f.matches(m)) {
+            outcomes.push((m, drop_filtered(sqs, queue_url, m, filter.attribute()).await));
+            continue;
+        }
+        if let Some(key) = opts.dedup.as_ref().and_then(|d| d.recent(m, opts.clock.as_ref())) {
+            outcomes.push((m, drop_duplicate(sqs, queue_url, m, &key).await));
+            continue;
+        }
+        if let Some(deliver_at) = opts.deliver_after_attribute.as_deref().and_then(|a| scheduled_delivery(m, a, opts.clock.as_ref())) {
+            outcomes.push((m, defer_scheduled(sqs, queue_url, m, deliver_at, opts.clock.as_ref()).await));
+            continue;
+        }
+        let body = message_body(m, opts).await;
+        match body.and_then(|body| decode_body(m, &body, opts)) {
+            Ok(body) => {
+                items.push(batch_item(m, &body, opts));
+                included.push(m);
+            }
+            // Oversized messages are handled on their own, outside the batch
+            Err(e) => outcomes.extend(apply_outcome(sqs, queue_url, opts, m, Err(e)).await.map(|o| (m, o))),
+        }
+    }
+    if included.is_empty() {
+        return outcomes;
+    }
+
+    let receive_count = included.iter().map(|m| attempt(m, opts)).max().unwrap_or(1);
+    match forward_batch(http, local_url, opts, limiter, items, receive_count).await {
+        Ok(decision) => {
+            for m in included {
+                outcomes.extend(apply_outcome(sqs, queue_url, opts, m, Ok(decision)).await.map(|o| (m, o)));
+            }
+        }
+        Err(e) => {
+            debug!("Batch of {} message(s) left in queue for retry: {}", included.len(), e);
+            for m in &included {
+                retry_later(sqs, queue_url, opts, m, None).await;
+            }
+            outcomes.extend(included.iter().map(|m| (*m, MessageOutcome::failed(&e))));
+        }
+    }
+    outcomes
+}
+
+/// One element of a batched forward: the body itself when it is JSON,
+/// otherwise its base64 encoding as a string (or the envelope in `ENVELOPE_MODE`).
+fn batch_item(m: &Message, body: &[u8], opts: &Config) -> Value {
+    if opts.envelope_mode {
+        let envelope = envelope_body(m, body, receive_count(m), &opts.queue_url);
+        return serde_json::from_slice(&envelope).unwrap_or_default();
+    }
+    serde_json::from_slice(body).unwrap_or_else(|_| Value::String(general_purpose::STANDARD.encode(body)))
+}
+
+/// POSTs a batch array and decides its shared outcome, like [`process_message`]
+/// does for a single message.
+async fn forward_batch(
+    http: &reqwest::Client,
+    local_url: &str,
+    opts: &Config,
+    limiter: Option<&mut TokenBucket>,
+    items: Vec<Value>,
+    receive_count: u32,
+) -> Result<Decision, RelayError> {
+    let count = items.len();
+    let body = Value::Array(items).to_string().into_bytes();
+    let request_id = uuid::Uuid::new_v4().to_string();
+
+    let mut hdrs = HeaderMap::new();
+    hdrs.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    hdrs.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    if let Some(secret) = &opts.signing_secret {
+        hdrs.insert(X_RELAY_SIGNATURE, relay_signature(secret, &body));
+    }
+    hdrs.insert("x-sqs-batch-size", HeaderValue::from(count));
+    hdrs.insert("x-sqs-receive-count", HeaderValue::from(receive_count));
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        hdrs.insert(X_REQUEST_ID, value);
+    }
+
+    info!("{} SQS → Local: batch of {} message(s), {} bytes [req: {}]", "📨".cyan(), count, body.len(), request_id.dimmed());
+    if let Some(limiter) = limiter {
+        limiter.acquire().await;
+    }
+
+    let route = opts.route_for(local_url);
+    if let Some(route) = route {
+        for (name, value) in &route.headers {
+            hdrs.insert(name, value.clone());
+        }
+    }
+    let mut req = http.post(local_url).headers(hdrs);
+    if let Some(timeout) = route.and_then(|r| r.timeout).or_else(|| opts.forward_timeout_for(body.len())) {
+        req = req.timeout(timeout);
+    }
+    let res = req
+        .body(body)
+        .send()
+        .instrument(info_span!("http.forward", url = local_url))
+        .await;
+
+    match res {
+        Ok(rsp) => {
+            let status = rsp.status();
+            tracing::Span::current().record("status", status.as_u16());
+            let icon = if status.is_success() { "📤".green() } else { "📤".red() };
+            info!("{} Local → Response: {} (batch of {}, attempt {})", icon, colorize_status(status.as_u16()), count, receive_count);
+
+            let retry_after = rsp.headers().get(RETRY_AFTER).and_then(|v| parse_retry_after(v, opts.clock.as_ref()));
+            let policy = route.and_then(|r| r.status_policy.as_ref()).unwrap_or(&opts.status_policy);
+            let outcome = policy.decide(Some(status.as_u16()), retry_after, receive_count);
+            if outcome != Outcome::Delete {
+                warn!("{} Batch answered {} → {:?}", "🔄".yellow(), colorize_status(status.as_u16()), outcome);
+            }
+            if status.is_server_error() && outcome == Outcome::RetrySoon {
+                return Err(RelayError::Status(status));
+            }
+            Ok(Decision { outcome, status: Some(status.as_u16()) })
+        }
+        Err(e) => {
+            error!("{} Network error forwarding batch → Will retry (attempt {}): {}", "🌐".red(), receive_count, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Acts on the result of [`process_message`] (delete, hide, dead-letter) and
+/// returns the bookkeeping record. `None` means the message was left alone.
+async fn apply_outcome(
+    sqs: &SqsClient,
+    queue_url: &str,
+    opts: &Config,
+    m: &Message,
+    result: Result<Decision, RelayError>,
+) -> Option<MessageOutcome> {
+    let message_id = m.message_id().unwrap_or("unknown");
+    let receipt = m.receipt_handle().unwrap_or_default();
+
+    let Decision { outcome, status } = match result {
+        Ok(decision) => decision,
+        Err(RelayError::Decode(e)) => {
+            debug!("Skipping message {}: {}", message_id, e);
+            return None;
+        }
+        Err(
+            e @ (RelayError::Oversize { .. }
+            | RelayError::Decrypt(_)
+            | RelayError::AttemptsExceeded { .. }
+            | RelayError::UnexpectedContentType(_)
+            | RelayError::MissingJsonKeys(_)
+            | RelayError::NonUtf8Body
+            | RelayError::Signature(_)
+            | RelayError::TargetNotAllowed(_)),
+        ) => {
+            let action = match e {
+                RelayError::TargetNotAllowed(_) if opts.dlq_url.is_some() => OversizeAction::Dlq,
+                RelayError::TargetNotAllowed(_) => OversizeAction::Delete,
+                RelayError::Signature(_) | RelayError::NonUtf8Body => OversizeAction::Delete,
+                RelayError::Decrypt(_) => opts.decrypt_failure_action,
+                RelayError::UnexpectedContentType(_) => opts.content_type_mismatch_action,
+                RelayError::MissingJsonKeys(_) => opts.schema_mismatch_action,
+                RelayError::AttemptsExceeded { .. } => OversizeAction::Dlq,
+                _ => opts.oversize_action,
+            };
+            warn!("{} {} → {:?} (message {})", "📦".yellow(), e, action, message_id);
+            if action == OversizeAction::Dlq {
+                let dlq_url = opts.dlq_url.as_deref().unwrap_or_default();
+                if let Err(e) = send_to_dlq(sqs, dlq_url, m).await {
+                    error!("Failed to move SQS message {} to DLQ: {}", message_id, e);
+                    return Some(MessageOutcome::not_forwarded(Disposition::Retried));
+                }
+                debug!("Message {} copied to DLQ {}", message_id, dlq_url);
+            }
+            let disposition = if delete_message(sqs, queue_url, receipt, message_id).await {
+                debug!("Unforwardable message {} deleted from queue", message_id);
+                match action {
+                    OversizeAction::Delete => Disposition::Deleted,
+                    OversizeAction::Dlq => Disposition::DeadLettered,
+                }
+            } else {
+                Disposition::Retried
+            };
+            return Some(MessageOutcome::not_forwarded(disposition));
+        }
+        Err(RelayError::EmptyBody) => {
+            warn!("{} Message {} has an empty body → {:?} (EMPTY_BODY_ACTION)", "📭".yellow(), message_id, opts.empty_body_action);
+            let disposition = match opts.empty_body_action {
+                EmptyBodyAction::Drop if delete_message(sqs, queue_url, receipt, message_id).await => Disposition::Deleted,
+                _ => Disposition::Retried,
+            };
+            return Some(MessageOutcome::not_forwarded(disposition));
+        }
+        Err(e) => {
+            // Already logged where the forward failed; SQS redelivers after the visibility timeout
+            debug!("Message {} left in queue for retry: {}", message_id, e);
+            // A slow endpoint gets time to catch up instead of counting as down
+            let slow = e.forward_error_kind() == Some(ForwardErrorKind::Timeout) && opts.timeout_action == TimeoutAction::Backoff;
+            let backoff = slow.then(|| error_backoff(opts.error_backoff, opts.error_backoff_max, attempt(m, opts)));
+            retry_later(sqs, queue_url, opts, m, backoff).await;
+            let queue_latency = queue_latency(m, opts.clock.as_ref()).filter(|_| e.status().is_some());
+            let outcome = MessageOutcome::failed(&e);
+            return Some(MessageOutcome { queue_latency, local_down: Some(e.local_down() && !slow), ..outcome });
+        }
+    };
+
+    let disposition = match outcome {
+        Outcome::Delete if opts.expose_receipt => {
+            debug!("Message {} left in queue for the consumer to delete (EXPOSE_RECEIPT)", message_id);
+            Disposition::HandedOff
+        }
+        Outcome::Delete | Outcome::Drop => {
+            if delete_message(sqs, queue_url, receipt, message_id).await {
+                debug!("Message {} deleted from queue", message_id);
+                Disposition::Deleted
+            } else {
+                Disposition::Retried
+            }
+        }
+        Outcome::RetrySoon => {
+            debug!("Message {} left in queue for retry", message_id);
+            retry_later(sqs, queue_url, opts, m, None).await;
+            Disposition::Retried
+        }
+        Outcome::RetryBackoff(delay) => {
+            retry_later(sqs, queue_url, opts, m, Some(delay)).await;
+            Disposition::Retried
+        }
+        Outcome::DeadLetter => {
+            let dlq_url = opts.dlq_url.as_deref().unwrap_or_default();
+            match send_to_dlq(sqs, dlq_url, m).await {
+                Ok(()) if delete_message(sqs, queue_url, receipt, message_id).await => {
+                    debug!("Message {} moved to DLQ {}", message_id, dlq_url);
+                    Disposition::DeadLettered
+                }
+                Ok(()) => Disposition::Retried,
+                Err(e) => {
+                    error!("Failed to move SQS message {} to DLQ: {}", message_id, e);
+                    Disposition::Retried
+                }
+            }
+        }
+    };
+    if let Some(dedup) = opts.dedup.as_ref().filter(|_| outcome == Outcome::Delete) {
+        dedup.record(m, opts.clock.as_ref());
+    }
+    if let Some(store) = opts.payload_store.as_ref().filter(|s| s.delete_after_forward) {
+        if let Some(pointer) = S3Pointer::from_message(m).filter(|_| disposition == Disposition::Deleted && outcome == Outcome::Delete) {
+            store.delete(&pointer, opts.clock.as_ref()).await;
+        }
+    }
+    Some(MessageOutcome {
+        local_down: Some(false),
+        success: outcome == Outcome::Delete,
+        disposition,
+        status,
+        error: None,
+        queue_latency: queue_latency(m, opts.clock.as_ref()),
+        error_kind: None,
+    })
+}
+
+/// Decodes and forwards a single message, deciding what should happen to it.
+/// Performs no SQS calls; [`apply_outcome`] acts on the result.
+async fn process_message(
+    http: &reqwest::Client,
+    local_url: &str,
+    opts: &Config,
+    limiter: Option<&mut TokenBucket>,
+    m: &Message,
+) -> Result<Decision, RelayError> {
+    if m.receipt_handle().is_none() {
+        return Err(RelayError::Decode("missing receipt handle".into()));
+    }
+    let message_id = m.message_id().unwrap_or("unknown");
+    debug!("🔄 Processing message ID: {}", message_id);
+
+    let body = message_body(m, opts).await?;
+    let raw_bytes = decode_body(m, &body, opts)?;
+    let target = relay_target(m, opts)?;
+    let attrs_map = m.message_attributes();
+
+    // Build headers from MessageAttributes (lowercase keys are fine)
+    let mut hdrs = HeaderMap::new();
+    let mut source_ip: Option<String> = None;
+    let mut forwarded_proto: Option<String> = None;
+    let mut forwarded_host: Option<String> = None;
+
+    for (k, v) in attrs_to_headers(attrs_map, &opts.attr_headers) {
+        // Construct header name/value
+        let (name, value) = match header_pair(&k, &v, opts.sanitize_headers) {
+            Ok(pair) => pair,
+            Err(reason) => {
+                warn!("Dropping attribute header '{}': {} (SANITIZE_HEADERS=true repairs it)", k, reason);
+                continue;
+            }
+        };
+        match name.as_str() {
+            "sourceip" | "source-ip" | "clientip" | "client-ip" |
+            "originatingip" | "originating-ip" | "remote-addr" | "x-real-ip" if !opts.disable_xff => {
+                source_ip = Some(v.clone());
+                debug!("Found source IP in attribute '{}': {}", k, v);
+            }
+            "proto" | "scheme" | "forwarded-proto" | "original-proto" => {
+                forwarded_proto = Some(v.clone());
+                debug!("Found original scheme in attribute '{}': {}", k, v);
+            }
+            "host" | "forwarded-host" | "original-host" => {
+                forwarded_host = Some(v.clone());
+                debug!("Found original host in attribute '{}': {}", k, v);
+            }
+            _ => {}
+        }
+        if name == CONTENT_TYPE {
+            // An explicit content-type attribute always replaces the default below
+            if !v.trim().is_empty() {
+                hdrs.insert(name, value);
+            }
+            continue;
+        }
+        hdrs.append(name, value);
+    }
+
+    // Correlation id: reuse the producer's X-Request-Id if present, otherwise mint one
+    let request_id = hdrs
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        hdrs.insert(X_REQUEST_ID, value);
+    }
+    tracing::Span::current().record("request_id", request_id.as_str());
+
+    // SKIP_BODY_INSPECTION leaves the body to the forward alone: no JSON parsing,
+    // metadata extraction or summary
+    let inspect = !opts.skip_body_inspection;
+
+    // Parsed once for content-type sniffing and the log summary
+    let body_json = if inspect { serde_json::from_slice::<Value>(&raw_bytes).ok() } else { None };
+
+    // Ensure Content-Type header exists
+    if !hdrs.contains_key(CONTENT_TYPE) {
+        let content_type = match &opts.default_content_type {
+            Some(content_type) => content_type.clone(),
+            None => sniff_content_type(&raw_bytes, body_json.is_some()),
+        };
+        debug!("No content-type attribute; using {:?}", content_type);
+        hdrs.insert(CONTENT_TYPE, content_type);
+    }
+    if !opts.expected_content_types.is_empty() {
+        let content_type = hdrs.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !opts.expected_content_types.iter().any(|t| *t == media_type(content_type)) {
+            return Err(RelayError::UnexpectedContentType(content_type.to_string()));
+        }
+    }
+
+    // Only JSON bodies are held to REQUIRED_JSON_KEYS; anything else passes through
+    if let Some(json) = body_json.as_ref().filter(|_| !opts.required_json_keys.is_empty()) {
+        let missing: Vec<String> = opts
+            .required_json_keys
+            .iter()
+            .filter(|key| json.get(key.as_str()).is_none_or(Value::is_null))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(RelayError::MissingJsonKeys(missing));
+        }
+    }
+
+    if inspect && !hdrs.contains_key(USER_AGENT) {
+        if let Some(ua) = extract_user_agent_from_json_bytes(&raw_bytes)
+            .and_then(|ua| HeaderValue::from_str(&ua).ok())
+        {
+            debug!("Using User-Agent from JSON body: {:?}", ua);
+            hdrs.insert(USER_AGENT, ua);
+        }
+    }
+
+    // Add/extend X-Forwarded-For from attributes or JSON body (best-effort);
+    // with DISABLE_XFF any x-forwarded-for attribute passes through untouched
+    if inspect && source_ip.is_none() && !opts.disable_xff {
+        source_ip = extract_ip_from_json_bytes(&raw_bytes, &opts.ip_json_paths);
+    }
+    if let Some(ref ip) = source_ip {
+        if let Ok(xff_value) = HeaderValue::from_str(ip) {
+            if let Some(existing_xff) = hdrs.get("x-forwarded-for") {
+                if let Ok(existing_str) = existing_xff.to_str() {
+                    if let Ok(new_xff) = HeaderValue::from_str(&format!("{}, {}", existing_str, ip)) {
+                        hdrs.insert("x-forwarded-for", new_xff);
+                    }
+                }
+            } else {
+                hdrs.insert("x-forwarded-for", xff_value);
+            }
+            debug!("Added X-Forwarded-For header: {}", ip);
+        }
+    }
+
+    // X-Forwarded-Proto/Host from attributes or JSON body; an existing header wins
+    if inspect && (forwarded_proto.is_none() || forwarded_host.is_none()) {
+        let (json_proto, json_host) = extract_forwarded_from_json_bytes(&raw_bytes);
+        forwarded_proto = forwarded_proto.or(json_proto);
+        forwarded_host = forwarded_host.or(json_host);
+    }
+    let forwarded = [
+        ("x-forwarded-proto", forwarded_proto.as_deref().and_then(forwarded_proto_value)),
+        ("x-forwarded-host", forwarded_host.as_deref().filter(|h| is_valid_forwarded_host(h))),
+    ];
+    for (name, value) in forwarded {
+        let Some(value) = value else { continue; };
+        if hdrs.contains_key(name) {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value) {
+            debug!("Added {} header: {:?}", name, value);
+            hdrs.insert(name, value);
+        }
+    }
+
+    // Summary for logs (decode to UTF-8 lossily for display only)
+    let webhook_summary = if inspect {
+        extract_webhook_summary_from_bytes(&raw_bytes, body_json.as_ref(), &opts.previews)
+    } else {
+        format!("bytes:{}", raw_bytes.len())
+    };
+
+    // Attempt number to track retries, counting re-enqueued copies too
+    let attempt = attempt(m, opts);
+    tracing::Span::current().record("attempt", attempt);
+
+    // Delivery metadata so the consumer can make idempotency/staleness decisions
+    hdrs.insert("x-sqs-receive-count", HeaderValue::from(receive_count(m)));
+    if let (Some(sent_ms), Some(age)) = (sent_timestamp_ms(m), queue_latency(m, opts.clock.as_ref())) {
+        hdrs.insert("x-sqs-sent-timestamp", HeaderValue::from(sent_ms));
+        hdrs.insert("x-sqs-message-age-ms", HeaderValue::from(age.as_millis() as u64));
+    }
+    for SystemAttrHeader { attribute, header } in &opts.system_attr_headers {
+        let Some(value) = m.attributes().and_then(|attrs| attrs.get(attribute)) else { continue; };
+        if let Ok(value) = HeaderValue::from_str(value) {
+            hdrs.insert(header.clone(), value);
+        }
+    }
+    if opts.attrs_header {
+        if let Some(value) = attributes_header(m) {
+            hdrs.insert("x-sqs-attributes", value);
+        }
+    }
+    // FIFO context so the consumer can reconcile ordering and dedup with SQS
+    for (attr, header) in [
+        (MessageSystemAttributeName::MessageGroupId, "x-sqs-group-id"),
+        (MessageSystemAttributeName::MessageDeduplicationId, "x-sqs-dedup-id"),
+    ] {
+        let Some(value) = m.attributes().and_then(|attrs| attrs.get(&attr)) else { continue; };
+        debug!("{}: {}", attr.as_str(), value);
+        if let Ok(value) = HeaderValue::from_str(value) {
+            hdrs.insert(header, value);
+        }
+    }
+    // EXPOSE_RECEIPT: the consumer deletes the message itself once it is durable
+    if opts.expose_receipt {
+        let receipt = m.receipt_handle().unwrap_or_default();
+        for (header, value) in [("x-sqs-receipt-handle", receipt), ("x-sqs-queue-url", opts.queue_url.as_str())] {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                hdrs.insert(header, value);
+            }
+        }
+    }
+
+    info!(
+        "{} SQS → Local: {} [req: {}]{}",
+        "📨".cyan(),
+        webhook_summary.bright_white(),
+        request_id.dimmed(),
+        if let Some(ip) = &source_ip {
+            format!(" [IP: {}]", ip.bright_blue())
+        } else {
+            String::new()
+        }
+    );
+
+    // Envelope mode wraps the whole message for inspection tools; the raw body
+    // is still available inside it, base64-encoded
+    let raw_bytes = if opts.envelope_mode {
+        hdrs.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        envelope_body(m, &raw_bytes, receive_count(m), &opts.queue_url)
+    } else if opts.non_utf8_action == NonUtf8Action::Base64Wrap && std::str::from_utf8(&raw_bytes).is_err() {
+        debug!("Wrapping {}-byte non-UTF-8 body as base64 JSON (NON_UTF8_ACTION)", raw_bytes.len());
+        hdrs.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        serde_json::to_vec(&serde_json::json!({ "data_base64": general_purpose::STANDARD.encode(&raw_bytes) }))
+            .expect("a JSON object serializes")
+    } else {
+        raw_bytes
+    };
+
+    // Framing headers must describe the bytes we actually send, not whatever the
+    // producer put on the attributes before any decoding/transform
+    for framing in [CONTENT_LENGTH, TRANSFER_ENCODING] {
+        if hdrs.remove(&framing).is_some() {
+            debug!("Dropped stale {} attribute header", framing);
+        }
+    }
+    // Lets the consumer authenticate the relay itself, independent of any producer signature
+    if let Some(secret) = &opts.signing_secret {
+        hdrs.insert(X_RELAY_SIGNATURE, relay_signature(secret, &raw_bytes));
+    }
+    // Signatures above cover the uncompressed body, as does X-Hub-Signature-256
+    let raw_bytes = compress_body(raw_bytes, &mut hdrs, opts);
+    hdrs.insert(CONTENT_LENGTH, HeaderValue::from(raw_bytes.len()));
+
+    debug!("🚀 Forwarding message {} to {}", message_id, local_url);
+    debug!("Request headers: {:?}", hdrs.keys().collect::<Vec<_>>());
+    for (k, v) in hdrs.iter() {
+        if opts.redact_headers.iter().any(|r| r == k.as_str()) {
+            debug!("  {}: ***redacted***", k);
+        } else {
+            debug!("  {}: {:?}", k, v);
+        }
+    }
+    debug!(body_len = raw_bytes.len(), "Sending {} bytes to local service", raw_bytes.len());
+
+    // POST to local server with the EXACT BYTES (this is the critical part)
+    // Use Vec<u8> directly instead of cloning
+    let delivery = Delivery { message: m, headers: hdrs, body: raw_bytes, attempt, target, limiter };
+    match opts.sink {
+        SinkKind::Http => HttpSink { http, local_url }.deliver(opts, delivery).await,
+        #[cfg(feature = "kafka")]
+        SinkKind::Kafka => opts.kafka.as_ref().expect("built at startup").deliver(opts, delivery).await,
+        #[cfg(not(feature = "kafka"))]
+        SinkKind::Kafka => unreachable!("rejected when the config is loaded"),
+    }
+}
+
+/// One greppable record per forward summarizing its outcome, next to the
+/// human-oriented lines above; `RUST_LOG=info,relay::decision=debug` enables just these.
+fn log_decision(message_id: &str, status: Option<u16>, receive_count: u32, decision: Outcome, reason: &str) {
+    debug!(target: "relay::decision", message_id, status, receive_count, decision = ?decision, reason, "decision");
+}
+
+/// System attributes to request: those the relay uses itself plus any `SYSTEM_ATTR_HEADERS`.
+fn system_attribute_names(opts: &Config) -> Vec<MessageSystemAttributeName> {
+    let mut names = vec![
+        MessageSystemAttributeName::ApproximateReceiveCount,
+        MessageSystemAttributeName::SentTimestamp,
+        MessageSystemAttributeName::MessageGroupId,
+        MessageSystemAttributeName::MessageDeduplicationId,
+    ];
+    for SystemAttrHeader { attribute, .. } in &opts.system_attr_headers {
+        if !names.contains(attribute) {
+            names.push(attribute.clone());
+        }
+    }
+    names
+}
+
+/// SQS `SentTimestamp` (epoch ms), if it was requested and parses.
+fn sent_timestamp_ms(m: &Message) -> Option<u64> {
+    m.attributes()
+        .and_then(|attrs| attrs.get(&MessageSystemAttributeName::SentTimestamp))
+        .and_then(|ts| ts.parse().ok())
+}
+
+/// How long the message has been in the queue: now minus `SentTimestamp`.
+fn queue_latency(m: &Message, clock: &dyn Clock) -> Option<Duration> {
+    let sent = UNIX_EPOCH + Duration::from_millis(sent_timestamp_ms(m)?);
+    // A sender clock ahead of ours reads as zero rather than failing
+    Some(clock.now().duration_since(sent).unwrap_or_default())
+}
+
+/// SQS `ApproximateReceiveCount`, 1 if missing.
+fn receive_count(m: &Message) -> u32 {
+    m.attributes()
+        .and_then(|attrs| attrs.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+        .and_then(|count_str| count_str.parse().ok())
+        .unwrap_or(1)
+}
+
+/// The message body, or with `EXTENDED_CLIENT` the S3 object an offloaded
+/// message's body points at.
+async fn message_body<'a>(m: &'a Message, opts: &Config) -> Result<Cow<'a, str>, RelayError> {
+    let body = m.body().unwrap_or_default();
+    let (Some(store), Some(pointer)) = (&opts.payload_store, S3Pointer::from_message(m)) else {
+        return Ok(Cow::Borrowed(body));
+    };
+    let payload = store.fetch(&pointer, opts.clock.as_ref()).await.map_err(RelayError::Payload)?;
+    info!("🪣 Fetched {} bytes for message {} from {}", payload.len(), m.message_id().unwrap_or("unknown"), pointer);
+    String::from_utf8(payload).map(Cow::Owned).map_err(|_| RelayError::Payload(format!("{pointer} is not UTF-8")))
+}
+
+/// Recovers the bytes the producer originally sent (undoing `BodyIsBase64`),
+/// applies `BODY_TRANSFORM` and enforces `MAX_BODY_BYTES`. Also refuses
+/// messages that have used up `MAX_TOTAL_ATTEMPTS`, before any work is done.
+fn decode_body(m: &Message, body_raw: &str, opts: &Config) -> Result<Vec<u8>, RelayError> {
+    if let Some(max) = opts.max_total_attempts {
+        let attempts = relay_attempts(m);
+        if attempts >= max {
+            return Err(RelayError::AttemptsExceeded { attempts, max });
+        }
+    }
+
+    let message_id = m.message_id().unwrap_or("unknown");
+
+    // Attributes map (String -> MessageAttributeValue)
+    let attrs_map = m.message_attributes();
+    debug!("Message has {} attributes", attrs_map.map(|m| m.len()).unwrap_or(0));
+
+    // Determine if MessageBody is base64 of original bytes (per API GW template)
+    let body_is_b64_attr = attrs_map.and_then(|m| m.get("BodyIsBase64")).and_then(|v| v.string_value());
+    let body_is_b64 = body_is_b64_attr.map(|s| s.eq_ignore_ascii_case("true")).unwrap_or(false);
+    // Producers that forget the attribute still get decoded when the result is unmistakable
+    let detected = body_is_b64_attr.is_none() && opts.autodetect_base64;
+    let detected = detected.then(|| detect_base64(body_raw)).flatten();
+
+    debug!("Body is base64: {}, raw length: {} chars", body_is_b64, body_raw.len());
+
+    // Decode to raw bytes that GitHub originally sent
+    let raw_bytes: Vec<u8> = if body_is_b64 {
+        debug!("Decoding base64 message body");
+        match general_purpose::STANDARD.decode(body_raw) {
+            Ok(b) => {
+                debug!("Successfully decoded {} bytes from base64", b.len());
+                b
+            },
+            Err(e) => {
+                warn!("BodyIsBase64=true but base64 decode failed: {e}. Falling back to UTF-8 bytes.");
+                body_raw.as_bytes().to_vec()
+            }
+        }
+    } else if let Some(decoded) = detected {
+        info!(
+            "🔎 Message {} has no BodyIsBase64 attribute but its body decodes as base64 to {} bytes (AUTODETECT_BASE64)",
+            message_id,
+            decoded.len()
+        );
+        decoded
+    } else {
+        debug!("Using raw UTF-8 bytes (no base64 decoding)");
+        body_raw.as_bytes().to_vec()
+    };
+
+    // Decrypted before anything inspects the body; failures never log it
+    let raw_bytes = match decrypt::encryption_scheme(m) {
+        Some(scheme) if !scheme.eq_ignore_ascii_case(decrypt::AES_256_GCM_SCHEME) => {
+            return Err(RelayError::Decrypt(format!("unsupported {} '{}'", decrypt::ENCRYPTION_ATTRIBUTE, scheme)));
+        }
+        Some(_) => {
+            let key = opts.encryption_key.as_ref()
+                .ok_or_else(|| RelayError::Decrypt("body is encrypted but ENCRYPTION_KEY is not set".into()))?;
+            let plaintext = key.decrypt(&raw_bytes).map_err(RelayError::Decrypt)?;
+            debug!("Decrypted {} → {} bytes", raw_bytes.len(), plaintext.len());
+            plaintext
+        }
+        None => raw_bytes,
+    };
+    check_signature(m, &raw_bytes, opts)?;
+
+    let raw_bytes = match opts.body_transform.as_ref().and_then(|t| t.apply(&raw_bytes)) {
+        Some(Ok(transformed)) => {
+            debug!("Applied BODY_TRANSFORM: {} → {} bytes", raw_bytes.len(), transformed.len());
+            transformed
+        }
+        Some(Err(e)) => {
+            warn!("BODY_TRANSFORM failed for message {}: {}. Forwarding original body.", message_id, e);
+            raw_bytes
+        }
+        None => raw_bytes,
+    };
+
+    debug!("Final raw_bytes length: {} bytes", raw_bytes.len());
+    tracing::Span::current().record("body_size", raw_bytes.len());
+
+    if let Some(max) = opts.max_body_bytes.filter(|max| raw_bytes.len() > *max) {
+        return Err(RelayError::Oversize { size: raw_bytes.len(), max });
+    }
+    if raw_bytes.is_empty() && opts.empty_body_action != EmptyBodyAction::Forward {
+        return Err(RelayError::EmptyBody);
+    }
+    if opts.non_utf8_action == NonUtf8Action::Drop && std::str::from_utf8(&raw_bytes).is_err() {
+        return Err(RelayError::NonUtf8Body);
+    }
+    Ok(raw_bytes)
+}
+
+/// Magic numbers of binary payloads worth decoding a bare base64 body into:
+/// gzip, zip, PNG and PDF.
+const BASE64_MAGIC: [&[u8]; 4] = [b"\x1f\x8b", b"PK\x03\x04", b"\x89PNG", b"%PDF"];
+
+/// `AUTODETECT_BASE64`: the decoded body when `body` is unmistakably base64 (and
+/// not JSON itself) and decodes to JSON or a known binary format. Anything less
+/// certain is left alone so ordinary text bodies are never mangled.
+fn detect_base64(body: &str) -> Option<Vec<u8>> {
+    let body = body.trim();
+    let charset = |b: u8| b.is_ascii_alphanumeric() || b == b'+' || b == b'/';
+    let unpadded = body.trim_end_matches('=');
+    if body.len() < 8
+        || !body.len().is_multiple_of(4)
+        || body.len() - unpadded.len() > 2
+        || !unpadded.bytes().all(charset)
+        || serde_json::from_str::<Value>(body).is_ok()
+    {
+        return None;
+    }
+    let decoded = general_purpose::STANDARD.decode(body).ok()?;
+    let plausible = serde_json::from_slice::<Value>(&decoded).is_ok_and(|v| v.is_object() || v.is_array())
+        || BASE64_MAGIC.iter().any(|magic| decoded.starts_with(magic));
+    plausible.then_some(decoded)
+}
+
+/// Whether an SQS call failed because the credentials are missing, expired or
+/// rejected, which a stale client may never recover from on its own.
+fn is_credential_error<E, R>(e: &SdkError<E, R>) -> bool
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    const CREDENTIAL_CODES: &[&str] = &[
+        "ExpiredToken", "ExpiredTokenException", "InvalidClientTokenId",
+        "UnrecognizedClientException", "InvalidSecurity", "SignatureDoesNotMatch",
+        "IncompleteSignature", "RequestExpired",
+    ];
+    if let SdkError::ServiceError(_) = e {
+        return e.code().is_some_and(|code| CREDENTIAL_CODES.contains(&code));
+    }
+    // Provider failures surface before the request is sent, wrapped in the error chain
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if err.is::<CredentialsError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Whether SQS rejected the call for exceeding its request rate.
+fn is_throttling_error<E, R>(e: &SdkError<E, R>) -> bool
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    const THROTTLING_CODES: &[&str] = &[
+        "RequestThrottled", "AWS.SimpleQueueService.RequestThrottled", "ThrottlingException",
+        "Throttling", "TooManyRequestsException",
+    ];
+    matches!(e, SdkError::ServiceError(_)) && e.code().is_some_and(|code| THROTTLING_CODES.contains(&code))
+}
+
+/// Whether the queue was deleted (or never existed).
+fn is_missing_queue_error<E, R>(e: &SdkError<E, R>) -> bool
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    matches!(e, SdkError::ServiceError(_))
+        && e.code().is_some_and(|code| matches!(code, "AWS.SimpleQueueService.NonExistentQueue" | "QueueDoesNotExist"))
+}
+
+/// Gzips the body per `FORWARD_COMPRESSION` when it is at least
+/// `FORWARD_COMPRESSION_MIN_BYTES` and already unencoded, keeping whichever is smaller.
+fn compress_body(body: Vec<u8>, hdrs: &mut HeaderMap, opts: &Config) -> Vec<u8> {
+    if opts.forward_compression == ForwardCompression::None
+        || body.len() < opts.compression_min_bytes
+        || hdrs.contains_key(CONTENT_ENCODING)
+    {
+        return body;
+    }
+    let compressed = gzip::compress(&body);
+    if compressed.len() >= body.len() {
+        debug!("Gzip would not shrink the {}-byte body; sending it uncompressed", body.len());
+        return body;
+    }
+    debug!("Gzipped body: {} → {} bytes", body.len(), compressed.len());
+    hdrs.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    compressed
+}
+
+/// `sha256=<hex HMAC-SHA256 of the forwarded body bytes, before any gzip>` keyed by
+/// `RELAY_SIGNING_SECRET`, in the same shape as GitHub's `X-Hub-Signature-256`.
+fn relay_signature(secret: &str, body: &[u8]) -> HeaderValue {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    HeaderValue::from_str(&signature).expect("hex is a valid header value")
+}
+
+/// The message's `X-Relay-Target`, which replaces `LOCAL_URL` (and any fan-out)
+/// when it matches a `TARGET_URL_ALLOWLIST` entry. Anything else is refused so
+/// producers can't point the relay at arbitrary hosts. Without an allowlist the
+/// attribute is ignored.
+fn relay_target(m: &Message, opts: &Config) -> Result<Option<reqwest::Url>, RelayError> {
+    if opts.target_allowlist.is_empty() {
+        return Ok(None);
+    }
+    let Some(target) = m
+        .message_attributes()
+        .and_then(|attrs| attrs.iter().find(|(name, _)| name.eq_ignore_ascii_case(X_RELAY_TARGET)))
+        .and_then(|(_, v)| v.string_value())
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+    else {
+        return Ok(None);
+    };
+    match reqwest::Url::parse(target) {
+        Ok(url) if opts.target_allowlist.iter().any(|allowed| target_allowed(&url, allowed)) => Ok(Some(url)),
+        _ => Err(RelayError::TargetNotAllowed(target.to_string())),
+    }
+}
 
-    let queue_url = env::var("QUEUE_URL").context("missing QUEUE_URL")?;
-    let local_url = env::var("LOCAL_URL").unwrap_or_else(|_| "http://127.0.0.1:3000/webhook".into());
+/// Same scheme, host and port, with the path at or below the allowed one
+/// (`/hooks` allows `/hooks/a` but not `/hooksmith`). Paths are already
+/// normalized by parsing, so `..` can't climb out.
+fn target_allowed(url: &reqwest::Url, allowed: &reqwest::Url) -> bool {
+    let (path, prefix) = (url.path(), allowed.path());
+    let under = path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'));
+    url.scheme() == allowed.scheme()
+        && url.host() == allowed.host()
+        && url.port_or_known_default() == allowed.port_or_known_default()
+        && url.username().is_empty()
+        && under
+}
 
-    // Non-deprecated AWS config
-    let shared_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
-    let sqs = SqsClient::new(&shared_config);
+/// `url` with the message's `QUERY_ATTRIBUTES` appended to its query string
+/// (URL-encoded, after any query it already has). Missing attributes are skipped.
+fn with_query_attributes<'a>(url: &'a str, m: &Message, mappings: &[QueryAttribute]) -> Cow<'a, str> {
+    let Some(attrs) = m.message_attributes().filter(|_| !mappings.is_empty()) else {
+        return Cow::Borrowed(url);
+    };
+    let pairs: Vec<(&str, &str)> = mappings
+        .iter()
+        .filter_map(|q| {
+            let (_, value) = attrs.iter().find(|(name, _)| name.eq_ignore_ascii_case(&q.attribute))?;
+            Some((q.param.as_str(), value.string_value()?))
+        })
+        .collect();
+    if pairs.is_empty() {
+        return Cow::Borrowed(url);
+    }
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return Cow::Borrowed(url);
+    };
+    parsed.query_pairs_mut().extend_pairs(pairs);
+    Cow::Owned(parsed.into())
+}
 
-    let http = reqwest::Client::builder()
-        .timeout(Duration::from_secs(20))
-        .build()?;
+/// Applies `SIGNATURE_POLICY` to the producer's `X-Hub-Signature-256` over the
+/// body as received (after decryption, before any transform).
+fn check_signature(m: &Message, body: &[u8], opts: &Config) -> Result<(), RelayError> {
+    if opts.signature_policy == SignaturePolicy::Off {
+        return Ok(());
+    }
+    let signature = m
+        .message_attributes()
+        .and_then(|attrs| attrs.iter().find(|(name, _)| name.eq_ignore_ascii_case(X_HUB_SIGNATURE_256)))
+        .and_then(|(_, v)| v.string_value());
+    match (signature, opts.webhook_secret.as_deref()) {
+        (None, _) if opts.signature_policy == SignaturePolicy::Required => Err(RelayError::Signature("missing X-Hub-Signature-256")),
+        (None, _) => {
+            warn!("SQS message missing X-Hub-Signature-256 attribute; signature verification will fail");
+            Ok(())
+        }
+        (Some(signature), Some(secret)) if !signature_matches(secret, body, signature) => {
+            Err(RelayError::Signature("X-Hub-Signature-256 does not match the body"))
+        }
+        _ => Ok(()),
+    }
+}
 
-    info!("🚀 Relay starting. Queue={}, Local={}", queue_url, local_url);
-    info!("🔍 Use RUST_LOG=debug for verbose output");
-    info!("⏹️  Ctrl-C to stop.");
+/// Constant-time check of a `sha256=<hex>` signature against the body.
+fn signature_matches(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(expected) = signature.trim().strip_prefix("sha256=").and_then(|hex| hex::decode(hex).ok()) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
 
-    tokio::select! {
-        _ = relay_loop(&sqs, &http, &queue_url, &local_url) => {},
-        _ = signal::ctrl_c() => {
-            info!("Received Ctrl-C, shutting down.");
+/// Doubles `base` for each consecutive error after the first, up to `max`.
+fn error_backoff(base: Duration, max: Duration, consecutive_errors: u32) -> Duration {
+    let exponent = consecutive_errors.saturating_sub(1).min(31);
+    base.saturating_mul(1 << exponent).min(max)
+}
+
+/// Scales `base` by a random factor in `1 ± fraction`.
+fn jittered(base: Duration, fraction: f64) -> Duration {
+    if fraction <= 0.0 {
+        return base;
+    }
+    let factor = 1.0 + rand::rng().random_range(-fraction..=fraction);
+    base.mul_f64(factor.max(0.0))
+}
+
+/// Deletes a message, returning whether it is gone. An invalid receipt handle
+/// means the visibility timeout lapsed and SQS already redelivered the message,
+/// which is expected under slow processing, so it is not reported as an error.
+async fn delete_message(sqs: &SqsClient, queue_url: &str, receipt: &str, message_id: &str) -> bool {
+    match sqs.delete_message().queue_url(queue_url).receipt_handle(receipt).send().await {
+        Ok(r) => {
+            debug!(aws_request_id = r.request_id(), "SQS DeleteMessage succeeded for message {}", message_id);
+            true
+        }
+        Err(e) => {
+            // AWS support needs the request id to trace a failed call
+            let aws_request_id = e.request_id();
+            match e.as_service_error() {
+                Some(se) if se.is_receipt_handle_is_invalid() => {
+                    info!(aws_request_id, "Message {} already redelivered (receipt handle expired); it will be processed again", message_id);
+                }
+                _ => error!(aws_request_id, "Failed to delete SQS message {}: {}", message_id, DisplayErrorContext(&e)),
+            }
+            false
+        }
+    }
+}
+
+/// Arranges a failed message's redelivery: after `backoff`, or when its
+/// visibility timeout lapses if `None`. With `MAX_TOTAL_ATTEMPTS` the message is
+/// instead re-sent with `X-Relay-Attempts` bumped and the original deleted, so
+/// the count survives where `ApproximateReceiveCount` would start over.
+async fn retry_later(sqs: &SqsClient, queue_url: &str, opts: &Config, m: &Message, backoff: Option<Duration>) {
+    let message_id = m.message_id().unwrap_or("unknown");
+    let receipt = m.receipt_handle().unwrap_or_default();
+    if opts.max_total_attempts.is_some() {
+        let attempts = relay_attempts(m) + 1;
+        let delay = backoff.unwrap_or(Duration::from_secs(RECEIVE_VISIBILITY_TIMEOUT_SECS as u64));
+        match requeue(sqs, queue_url, m, attempts, delay.as_secs().min(MAX_DELAY_SECS) as i32).await {
+            Ok(()) => {
+                if delete_message(sqs, queue_url, receipt, message_id).await {
+                    debug!("Message {} re-enqueued after attempt {}", message_id, attempts);
+                }
+                return;
+            }
+            Err(e) => error!("Failed to re-enqueue SQS message {}, leaving it for redelivery: {}", message_id, e),
+        }
+    }
+    if let Some(delay) = backoff {
+        let secs = delay.as_secs().min(MAX_VISIBILITY_TIMEOUT_SECS) as i32;
+        match change_visibility(sqs, queue_url, receipt, secs).await {
+            Ok(()) => debug!("Message {} hidden for {}s", message_id, secs),
+            Err(e) => error!("Failed to change visibility for SQS message {}: {}", message_id, e),
         }
     }
+}
+
+/// Failed forwards recorded in the message's `X-Relay-Attempts` attribute, 0 if missing.
+fn relay_attempts(m: &Message) -> u32 {
+    m.message_attributes()
+        .and_then(|attrs| attrs.iter().find(|(name, _)| name.eq_ignore_ascii_case(X_RELAY_ATTEMPTS)))
+        .and_then(|(_, v)| v.string_value())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Which attempt this delivery is: the SQS receive count, plus the failed
+/// forwards of earlier copies when `MAX_TOTAL_ATTEMPTS` re-enqueues them (each
+/// copy's receive count starts over). Status policies decide on this.
+fn attempt(m: &Message, opts: &Config) -> u32 {
+    let carried = if opts.max_total_attempts.is_some() { relay_attempts(m) } else { 0 };
+    receive_count(m).saturating_add(carried)
+}
 
+/// Sends a copy of a message with `X-Relay-Attempts` set to `attempts`, delayed by `delay_secs`.
+async fn requeue(sqs: &SqsClient, queue_url: &str, m: &Message, attempts: u32, delay_secs: i32) -> Result<(), RelayError> {
+    let count = MessageAttributeValue::builder()
+        .data_type("Number")
+        .string_value(attempts.to_string())
+        .build()
+        .map_err(|e| RelayError::Sqs(e.to_string()))?;
+    let mut attrs = m.message_attributes().cloned().unwrap_or_default();
+    attrs.retain(|name, _| !name.eq_ignore_ascii_case(X_RELAY_ATTEMPTS));
+    attrs.insert(X_RELAY_ATTEMPTS.to_string(), count);
+    sqs.send_message()
+        .queue_url(queue_url)
+        .message_body(m.body().unwrap_or_default())
+        .set_message_attributes(Some(attrs))
+        .delay_seconds(delay_secs)
+        .send()
+        .await
+        .map_err(|e| RelayError::Sqs(DisplayErrorContext(&e).to_string()))?;
     Ok(())
 }
 
-async fn relay_loop(sqs: &SqsClient, http: &reqwest::Client, queue_url: &str, local_url: &str) {
-    debug!("🔄 Starting relay loop, polling SQS every 20 seconds...");
-    
-    loop {
-        debug!("📡 Polling SQS for messages...");
-        let resp = match sqs
-            .receive_message()
-            .queue_url(queue_url)
-            .max_number_of_messages(10)
-            .wait_time_seconds(20)   // long polling
-            .visibility_timeout(60)  // time to process locally
-            .message_attribute_names("All")
-            .message_system_attribute_names(aws_sdk_sqs::types::MessageSystemAttributeName::ApproximateReceiveCount)
-            .send()
-            .await
-        {
-            Ok(r) => {
-                debug!("✅ SQS poll successful");
-                r
-            },
-            Err(e) => {
-                error!("❌ SQS receive error: {e}");
-                tokio::time::sleep(Duration::from_secs(2)).await;
-                continue;
-            }
-        };
+/// Copies a message (body and attributes) onto the dead-letter queue.
+/// The caller is responsible for deleting the original.
+async fn send_to_dlq(sqs: &SqsClient, dlq_url: &str, m: &Message) -> Result<(), RelayError> {
+    sqs.send_message()
+        .queue_url(dlq_url)
+        .message_body(m.body().unwrap_or_default())
+        .set_message_attributes(m.message_attributes().cloned())
+        .send()
+        .await
+        .map_err(|e| RelayError::Sqs(DisplayErrorContext(&e).to_string()))?;
+    Ok(())
+}
 
-        let msgs = resp.messages();
-        if msgs.is_empty() {
-            debug!("No messages received from SQS");
-            continue;
+/// Resets the visibility timeout of messages not yet handled in a slow batch.
+async fn extend_visibility(sqs: &SqsClient, queue_url: &str, remaining: &[Message]) {
+    debug!("Batch is slow; extending visibility of {} remaining message(s)", remaining.len());
+    for m in remaining {
+        let Some(receipt) = m.receipt_handle() else { continue; };
+        if let Err(e) = change_visibility(sqs, queue_url, receipt, RECEIVE_VISIBILITY_TIMEOUT_SECS).await {
+            warn!("Failed to extend visibility for SQS message {}: {}", m.message_id().unwrap_or("unknown"), e);
         }
+    }
+}
 
-        info!("📥 Received {} message(s) from SQS", msgs.len());
+/// Hides a message for `secs` before SQS redelivers it.
+async fn change_visibility(sqs: &SqsClient, queue_url: &str, receipt: &str, secs: i32) -> Result<(), RelayError> {
+    sqs.change_message_visibility()
+        .queue_url(queue_url)
+        .receipt_handle(receipt)
+        .visibility_timeout(secs)
+        .send()
+        .await
+        .map_err(|e| RelayError::Sqs(DisplayErrorContext(&e).to_string()))?;
+    Ok(())
+}
 
-        for m in msgs {
-            let Some(receipt) = m.receipt_handle() else { 
-                debug!("Message missing receipt handle, skipping");
-                continue; 
-            };
-            let body_raw = m.body().unwrap_or_default();
+/// Converts message attributes to `(name, value)` header pairs.
+///
+/// String (and Number) attributes are used as-is. Binary attributes are sent
+/// as text when they are valid UTF-8 without control characters, and as
+/// standard base64 otherwise.
+///
+/// Attributes are visited in sorted name order and grouped by lowercased name
+/// according to the duplicate policy, so the same message always yields the
+/// same headers regardless of `HashMap` iteration order.
+fn attrs_to_headers(
+    attrs: Option<&std::collections::HashMap<String, MessageAttributeValue>>,
+    options: &AttrHeaderOptions,
+) -> Vec<(String, String)> {
+    let policy = options.duplicate_policy;
+    let Some(map) = attrs else { return Vec::new(); };
 
-            let message_id = m.message_id().unwrap_or("unknown");
-            debug!("🔄 Processing message ID: {}", message_id);
-
-            // Attributes map (String -> MessageAttributeValue)
-            let attrs_map = m.message_attributes();
-            debug!("Message has {} attributes", attrs_map.map(|m| m.len()).unwrap_or(0));
-
-            // Determine if MessageBody is base64 of original bytes (per API GW template)
-            let body_is_b64 = attrs_map
-                .and_then(|m| m.get("BodyIsBase64"))
-                .and_then(|v| v.string_value())
-                .map(|s| s.eq_ignore_ascii_case("true"))
-                .unwrap_or(false);
-
-            debug!("Body is base64: {}, raw length: {} chars", body_is_b64, body_raw.len());
-
-            // Decode to raw bytes that GitHub originally sent
-            let raw_bytes: Vec<u8> = if body_is_b64 {
-                debug!("Decoding base64 message body");
-                match general_purpose::STANDARD.decode(body_raw) {
-                    Ok(b) => {
-                        debug!("Successfully decoded {} bytes from base64", b.len());
-                        b
-                    },
-                    Err(e) => {
-                        warn!("BodyIsBase64=true but base64 decode failed: {e}. Falling back to UTF-8 bytes.");
-                        body_raw.as_bytes().to_vec()
-                    }
-                }
-            } else {
-                debug!("Using raw UTF-8 bytes (no base64 decoding)");
-                body_raw.as_bytes().to_vec()
-            };
+    let mut sorted: Vec<_> = map.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
 
-            debug!("Final raw_bytes length: {} bytes", raw_bytes.len());
-
-            // Build headers from MessageAttributes (lowercase keys are fine)
-            let mut hdrs = HeaderMap::new();
-            let mut source_ip: Option<String> = None;
-
-            for (k, v) in attrs_to_headers(attrs_map) {
-                // Construct header name/value
-                if let (Ok(name), Ok(value)) =
-                    (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(&v))
-                {
-                    match k.as_str() {
-                        "sourceip" | "source-ip" | "clientip" | "client-ip" |
-                        "originatingip" | "originating-ip" | "remote-addr" | "x-real-ip" => {
-                            source_ip = Some(v.clone());
-                            debug!("Found source IP in attribute '{}': {}", k, v);
-                        }
-                        _ => {}
-                    }
-                    hdrs.append(name, value);
-                }
+    let mut grouped: std::collections::BTreeMap<String, Vec<String>> = Default::default();
+    for (k, v) in sorted {
+        // Instructions to the relay, not part of the producer's request
+        if [X_RELAY_TARGET, X_RELAY_ATTEMPTS].iter().any(|a| k.eq_ignore_ascii_case(a)) {
+            continue;
+        }
+        let Some(value) = attr_header_value(v, options.type_headers) else {
+            continue;
+        };
+        // Send as header; HTTP is case-insensitive, we normalize to lowercase.
+        let name = options.header_name(k);
+        let mut pairs = vec![(name.clone(), value)];
+        if options.type_headers {
+            pairs.push((format!("x-attr-type-{name}"), v.data_type().to_string()));
+        }
+        for (name, value) in pairs {
+            let values = grouped.entry(name.clone()).or_default();
+            if !values.is_empty() {
+                debug!("Attribute '{}' duplicates header '{}' ({:?})", k, name, policy);
             }
-
-            // Ensure Content-Type header exists
-            if !hdrs.contains_key(CONTENT_TYPE) {
-                hdrs.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            match policy {
+                DuplicateHeaderPolicy::LastWins => *values = vec![value],
+                DuplicateHeaderPolicy::FirstWins if !values.is_empty() => {}
+                DuplicateHeaderPolicy::FirstWins | DuplicateHeaderPolicy::Append => values.push(value),
             }
+        }
+    }
 
-            // Sanity: warn if signature is missing (it should be present)
-            if !hdrs.contains_key("x-hub-signature-256") {
-                warn!("SQS message missing X-Hub-Signature-256 attribute; signature verification will fail");
-            }
+    let mut headers: Vec<_> = grouped
+        .into_iter()
+        .flat_map(|(name, values)| values.into_iter().map(move |v| (name.clone(), v)))
+        .collect();
+    if headers.len() > options.max_headers {
+        warn!(
+            "Message has {} attribute headers; keeping the first {} by name (MAX_HEADERS)",
+            headers.len(),
+            options.max_headers
+        );
+        headers.truncate(options.max_headers);
+    }
+    headers
+}
+
+/// Converts an attribute header into a typed header, or says why it can't be
+/// sent. With `sanitize`, characters not allowed in a header name become `-`
+/// and control characters in the value become spaces.
+fn header_pair(name: &str, value: &str, sanitize: bool) -> Result<(HeaderName, HeaderValue), &'static str> {
+    let (name, value) = if sanitize {
+        let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        let name: String = name.chars().map(|c| if is_token(c) { c } else { '-' }).collect();
+        let value: String = value.chars().map(|c| if c.is_control() && c != '\t' { ' ' } else { c }).collect();
+        (name, value)
+    } else {
+        (name.to_string(), value.to_string())
+    };
+    Ok((
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| "name is not a valid header name")?,
+        HeaderValue::from_str(&value).map_err(|_| "value has control characters")?,
+    ))
+}
 
-            // Add/extend X-Forwarded-For from attributes or JSON body (best-effort)
-            if source_ip.is_none() {
-                source_ip = extract_ip_from_json_bytes(&raw_bytes);
+/// JSON envelope sent instead of the raw body when `ENVELOPE_MODE` is on.
+fn envelope_body(m: &Message, body: &[u8], receive_count: u32, queue_url: &str) -> Vec<u8> {
+    serde_json::json!({
+        "message_id": m.message_id(),
+        "attributes": attributes_json(m),
+        "body_base64": general_purpose::STANDARD.encode(body),
+        "receive_count": receive_count,
+        "queue_url": queue_url,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Message attributes by name, with their data type and string or base64 binary value.
+fn attributes_json(m: &Message) -> serde_json::Map<String, Value> {
+    m.message_attributes()
+        .into_iter()
+        .flatten()
+        .map(|(name, attr)| {
+            let mut value = serde_json::json!({ "data_type": attr.data_type() });
+            if let Some(s) = attr.string_value() {
+                value["string_value"] = s.into();
             }
-            if let Some(ref ip) = source_ip {
-                if let Ok(xff_value) = HeaderValue::from_str(ip) {
-                    if let Some(existing_xff) = hdrs.get("x-forwarded-for") {
-                        if let Ok(existing_str) = existing_xff.to_str() {
-                            if let Ok(new_xff) = HeaderValue::from_str(&format!("{}, {}", existing_str, ip)) {
-                                hdrs.insert("x-forwarded-for", new_xff);
-                            }
-                        }
-                    } else {
-                        hdrs.insert("x-forwarded-for", xff_value);
-                    }
-                    debug!("Added X-Forwarded-For header: {}", ip);
-                }
+            if let Some(b) = attr.binary_value() {
+                value["binary_value_base64"] = general_purpose::STANDARD.encode(b.as_ref()).into();
             }
+            (name.clone(), value)
+        })
+        .collect()
+}
 
-            // Summary for logs (decode to UTF-8 lossily for display only)
-            let webhook_summary = extract_webhook_summary_from_bytes(&raw_bytes);
+/// `X-SQS-Attributes` value: the attribute JSON with non-ASCII characters
+/// `\u`-escaped, since header values must be visible ASCII.
+fn attributes_header(m: &Message) -> Option<HeaderValue> {
+    let json = Value::Object(attributes_json(m)).to_string();
+    let mut ascii = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+        } else {
+            // Non-ASCII only appears inside JSON strings, where escapes are valid
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                let _ = write!(ascii, "\\u{unit:04x}");
+            }
+        }
+    }
+    HeaderValue::from_str(&ascii).ok()
+}
 
-            // Receive count to track retries
-            let receive_count: u32 = m.attributes()
-                .and_then(|attrs| attrs.get(&aws_sdk_sqs::types::MessageSystemAttributeName::ApproximateReceiveCount))
-                .and_then(|count_str| count_str.parse().ok())
-                .unwrap_or(1);
+/// An attribute's header value by its data type (ignoring any custom `.suffix`):
+/// `Number` bare, without quotes or whitespace, and `Binary` as text when printable,
+/// or always base64 with `ATTR_TYPE_HEADERS` so consumers can decode it by the hint.
+fn attr_header_value(v: &MessageAttributeValue, type_headers: bool) -> Option<String> {
+    let base_type = v.data_type().split('.').next().unwrap_or_default();
+    if let Some(b) = v.binary_value() {
+        return Some(if type_headers { general_purpose::STANDARD.encode(b.as_ref()) } else { binary_attr_to_header_value(b.as_ref()) });
+    }
+    let s = v.string_value()?;
+    Some(match base_type {
+        "Number" => s.trim().trim_matches('"').trim().to_string(),
+        _ => s.to_string(),
+    })
+}
 
-            info!(
-                "{} SQS → Local: {}{}",
-                "📨".cyan(),
-                webhook_summary.bright_white(),
-                if let Some(ip) = &source_ip {
-                    format!(" [IP: {}]", ip.bright_blue())
-                } else {
-                    String::new()
-                }
-            );
+fn binary_attr_to_header_value(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(char::is_control) => text.to_string(),
+        _ => general_purpose::STANDARD.encode(bytes),
+    }
+}
 
-            debug!("🚀 Forwarding message {} to {}", message_id, local_url);
-            debug!("Request headers: {:?}", hdrs.keys().collect::<Vec<_>>());
-            for (k, v) in hdrs.iter() {
-                debug!("  {}: {:?}", k, v);
-            }
-            debug!("Sending {} bytes to local service", raw_bytes.len());
-
-            // POST to local server with the EXACT BYTES (this is the critical part)
-            // Use Vec<u8> directly instead of cloning
-            let res = http
-                .post(local_url)
-                .headers(hdrs)
-                .body(raw_bytes)
-                .send()
-                .await;
-
-            match res {
-                Ok(rsp) if rsp.status().is_success() => {
-                    let status_code = rsp.status().as_u16();
-                    info!("{} Local → Response: {} (attempt {})", "📤".green(), colorize_status(status_code), receive_count);
-
-                    debug!("Response headers: {:?}", rsp.headers().keys().collect::<Vec<_>>());
-                    match rsp.text().await {
-                        Ok(response_body) => {
-                            let response_preview = preview_str(&response_body, 200);
-                            if !response_preview.is_empty() {
-                                debug!("Response body: {}", response_preview);
-                            }
-                        }
-                        Err(e) => debug!("Could not read response body: {}", e)
-                    }
+/// The lowercased `type/subtype` of a Content-Type, without parameters.
+fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
 
-                    if let Err(e) = sqs.delete_message().queue_url(queue_url).receipt_handle(receipt).send().await {
-                        error!("Failed to delete SQS message {}: {}", message_id, e);
-                    } else {
-                        debug!("Message {} deleted from queue", message_id);
-                    }
-                }
-                Ok(rsp) => {
-                    let status_code = rsp.status().as_u16();
-                    info!("{} Local → Response: {} (attempt {})", "📤".red(), colorize_status(status_code), receive_count);
-
-                    debug!("Error response headers: {:?}", rsp.headers().keys().collect::<Vec<_>>());
-                    let _ = match rsp.text().await {
-                        Ok(response_body) => {
-                            let response_preview = preview_str(&response_body, 200);
-                            if !response_preview.is_empty() {
-                                debug!("Error response: {}", response_preview);
-                            }
-                            response_body
-                        }
-                        Err(e) => {
-                            debug!("Could not read error response body: {}", e);
-                            String::new()
-                        }
-                    };
-
-                    match status_code {
-                        404 => {
-                            // Endpoint missing; safe to drop
-                            warn!("{} 404 → Deleting message (endpoint not found)", "🗑️".yellow());
-                            if let Err(e) = sqs.delete_message().queue_url(queue_url).receipt_handle(receipt).send().await {
-                                error!("Failed to delete SQS message: {}", e);
-                            } else {
-                                debug!("Message {} deleted due to 404", message_id);
-                            }
-                        }
-                        400..=499 => {
-                            // Retry once for 4xx (e.g., signature mismatch on first try)
-                            if receive_count == 1 {
-                                warn!("{} {} → Will retry once (attempt {})", "🔄".yellow(), colorize_status(status_code), receive_count);
-                                debug!("Message {} left in queue for single retry", message_id);
-                            } else {
-                                warn!("{} {} → Deleting after retry (attempt {})", "🗑️".red(), colorize_status(status_code), receive_count);
-                                if let Err(e) = sqs.delete_message().queue_url(queue_url).receipt_handle(receipt).send().await {
-                                    error!("Failed to delete SQS message: {}", e);
-                                } else {
-                                    info!("Message {} deleted after failed retry", message_id);
-                                }
-                            }
-                        }
-                        500..=599 => {
-                            // 5xx errors - server issues; let SQS retry
-                            warn!("{} {} → Will retry (server error, attempt {})", "🔄".red(), colorize_status(status_code), receive_count);
-                            debug!("Message {} left in queue for retry", message_id);
-                        }
-                        _ => {
-                            warn!("{} {} → Will retry (unexpected status, attempt {})", "🔄".white(), colorize_status(status_code), receive_count);
-                            debug!("Message {} left in queue for retry", message_id);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("{} Network error → Will retry (attempt {}): {}", "🌐".red(), receive_count, e);
-                    debug!("Message {} left in queue for retry", message_id);
-                }
-            }
-        }
+/// Content-Type for a body without a `content-type` attribute, from its shape.
+fn sniff_content_type(bytes: &[u8], is_json: bool) -> HeaderValue {
+    if is_json {
+        HeaderValue::from_static("application/json")
+    } else if looks_form_encoded(bytes) {
+        HeaderValue::from_static("application/x-www-form-urlencoded")
+    } else {
+        HeaderValue::from_static("application/octet-stream")
     }
 }
 
-fn attrs_to_headers(
-    attrs: Option<&std::collections::HashMap<String, MessageAttributeValue>>,
-) -> Vec<(String, String)> {
-    let mut out = Vec::new();
-    if let Some(map) = attrs {
-        for (k, v) in map {
-            if let Some(s) = v.string_value() {
-                // Send as header; HTTP is case-insensitive, we normalize to lowercase.
-                out.push((k.to_ascii_lowercase(), s.to_string()));
-            }
-        }
+/// `key=value&key2=value2` with only URL-encoding-safe characters.
+fn looks_form_encoded(bytes: &[u8]) -> bool {
+    let allowed = |b: &u8| b.is_ascii_alphanumeric() || b"%+-_.~*=&".contains(b);
+    !bytes.is_empty()
+        && bytes.iter().all(allowed)
+        && bytes.split(|b| *b == b'&').all(|pair| pair.first().is_some_and(|b| *b != b'=') && pair.contains(&b'='))
+}
+
+/// `s3:ObjectCreated:Put key:foo/bar.json` for an S3 event notification
+/// (`Records[].s3`), counting any further records.
+fn s3_event_summary(json: &Value, limits: &PreviewLimits) -> Option<String> {
+    let records = json.get("Records")?.as_array()?;
+    let first = records.iter().find(|r| r.get("s3").is_some())?;
+    let event = first.get("eventName").and_then(Value::as_str).unwrap_or("event");
+    let mut summary = format!("s3:{event}");
+    if let Some(key) = json_path(first, "s3.object.key").and_then(Value::as_str) {
+        let _ = write!(summary, " key:{}", preview_str(key, limits.summary_len));
     }
-    out
+    if records.len() > 1 {
+        let _ = write!(summary, " (+{} more)", records.len() - 1);
+    }
+    Some(summary)
 }
 
-fn extract_webhook_summary_from_bytes(bytes: &[u8]) -> String {
-    // Try to parse JSON first for a meaningful summary
+/// One-line description of a body for logs. `json` is the body already
+/// parsed as JSON, if it is JSON.
+fn extract_webhook_summary_from_bytes(bytes: &[u8], json: Option<&Value>, limits: &PreviewLimits) -> String {
+    if bytes.is_empty() {
+        return "(empty body)".into();
+    }
+    // Use JSON fields first for a meaningful summary
     if let Ok(text) = std::str::from_utf8(bytes) {
-        if let Ok(json) = serde_json::from_str::<Value>(text) {
+        if let Some(json) = json {
+            if let Some(summary) = s3_event_summary(json, limits) {
+                return summary;
+            }
             let mut parts = Vec::new();
 
             if let Some(event_type) = json.get("type").and_then(|v| v.as_str()) {
@@ -323,8 +2583,8 @@ fn extract_webhook_summary_from_bytes(bytes: &[u8]) -> String {
             }
 
             if let Some(id) = json.get("id").and_then(|v| v.as_str()) {
-                if id.len() > 12 {
-                    parts.push(format!("id:{}...", &id[..8]));
+                if id.chars().count() > 12 {
+                    parts.push(format!("id:{}...", id.chars().take(8).collect::<String>()));
                 } else {
                     parts.push(format!("id:{}", id));
                 }
@@ -335,56 +2595,120 @@ fn extract_webhook_summary_from_bytes(bytes: &[u8]) -> String {
             }
         }
         // JSON parse failed or no interesting fields; show a preview
-        preview_str(text, 40)
+        preview_str(text, limits.summary_len)
     } else {
         // Non-UTF8 payload; show hex preview
-        preview_hex(bytes, 24)
+        preview_hex(bytes, limits.hex_bytes)
     }
 }
 
-fn extract_ip_from_json_bytes(bytes: &[u8]) -> Option<String> {
+/// Finds the source IP in a JSON body, trying `extra_paths` (`IP_JSON_PATHS`)
+/// before the built-in fields. Values that are not an IP address are skipped;
+/// for comma-separated lists (`X-Forwarded-For` style) the first entry is used.
+fn extract_ip_from_json_bytes(bytes: &[u8], extra_paths: &[String]) -> Option<String> {
     let Ok(text) = std::str::from_utf8(bytes) else { return None; };
     let Ok(json) = serde_json::from_str::<Value>(text) else { return None; };
 
     let ip_fields = [
         "sourceIp", "source_ip", "clientIp", "client_ip",
         "originatingIp", "originating_ip", "remoteAddr", "remote_addr",
-        "requestContext.identity.sourceIp",
+        "requestContext.identity.sourceIp", "requestContext.http.sourceIp",
         "headers.x-forwarded-for", "headers.x-real-ip",
-        "requestInfo.remoteIp", "request.ip", "ip"
+        "requestInfo.remoteIp", "request.ip", "ip",
+        "Records.0.sourceIPAddress", "Records.*.requestParameters.sourceIPAddress",
     ];
 
-    for field in &ip_fields {
-        if field.contains('.') {
-            let parts: Vec<&str> = field.split('.').collect();
-            let mut current = &json;
-            let mut found = true;
-
-            for part in &parts {
-                if let Some(next) = current.get(part) {
-                    current = next;
-                } else {
-                    found = false;
-                    break;
-                }
-            }
-
-            if found {
-                if let Some(ip_str) = current.as_str() {
-                    debug!("Found source IP in JSON body field '{}': {}", field, ip_str);
-                    return Some(ip_str.to_string());
-                }
-            }
-        } else if let Some(ip_value) = json.get(*field) {
-            if let Some(ip_str) = ip_value.as_str() {
-                debug!("Found source IP in JSON body field '{}': {}", field, ip_str);
-                return Some(ip_str.to_string());
+    for field in extra_paths.iter().map(String::as_str).chain(ip_fields) {
+        let Some(value) = json_path(&json, field).and_then(Value::as_str) else { continue; };
+        match value.split(',').next().and_then(|ip| ip.trim().parse::<IpAddr>().ok()) {
+            Some(ip) => {
+                debug!("Found source IP in JSON body field '{}': {}", field, ip);
+                return Some(ip.to_string());
             }
+            None => debug!("Ignoring JSON body field '{}': {:?} is not an IP address", field, value),
         }
     }
     None
 }
 
+/// User agent of the original caller from an API Gateway event body.
+fn extract_user_agent_from_json_bytes(bytes: &[u8]) -> Option<String> {
+    let json = serde_json::from_slice::<Value>(bytes).ok()?;
+    ["requestContext.http.userAgent", "requestContext.identity.userAgent"]
+        .iter()
+        .find_map(|field| json_path(&json, field).and_then(Value::as_str))
+        .map(String::from)
+}
+
+/// Original scheme and host (`X-Forwarded-Proto`/`X-Forwarded-Host`) from an
+/// API Gateway style event body.
+fn extract_forwarded_from_json_bytes(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    let Ok(json) = serde_json::from_slice::<Value>(bytes) else { return (None, None); };
+    let first = |fields: &[&str]| {
+        fields
+            .iter()
+            .find_map(|field| json_path(&json, field).and_then(Value::as_str))
+            .map(String::from)
+    };
+    let proto = first(&["headers.x-forwarded-proto", "headers.X-Forwarded-Proto"]);
+    let host = first(&[
+        "headers.x-forwarded-host", "headers.X-Forwarded-Host",
+        "headers.host", "headers.Host",
+        "requestContext.domainName",
+    ]);
+    (proto, host)
+}
+
+/// Normalizes a scheme to `http`/`https`; anything else is rejected. For a
+/// comma-separated list (set by chained proxies) the first entry is used.
+fn forwarded_proto_value(proto: &str) -> Option<&'static str> {
+    let proto = proto.split(',').next()?.trim();
+    if proto.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if proto.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+/// A bare `host[:port]` (including bracketed IPv6), without path or userinfo.
+fn is_valid_forwarded_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 255
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']'))
+}
+
+/// Follows a dotted path; numeric segments index into arrays (`Records.0.x`)
+/// and `*` matches any element or member, taking the first that resolves.
+fn json_path<'a>(json: &'a Value, path: &str) -> Option<&'a Value> {
+    let (part, rest) = match path.split_once('.') {
+        Some((part, rest)) => (part, Some(rest)),
+        None => (path, None),
+    };
+    let resolve = |next: &'a Value| match rest {
+        Some(rest) => json_path(next, rest),
+        None => Some(next),
+    };
+    match (json, part) {
+        (Value::Array(items), "*") => items.iter().find_map(resolve),
+        (Value::Object(members), "*") => members.values().find_map(resolve),
+        (Value::Array(items), _) => resolve(items.get(part.parse::<usize>().ok()?)?),
+        _ => resolve(json.get(part)?),
+    }
+}
+
+/// Parses a `Retry-After` value in either delta-seconds or HTTP-date form.
+fn parse_retry_after(value: &HeaderValue, clock: &dyn Clock) -> Option<Duration> {
+    let s = value.to_str().ok()?.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(s).ok()?;
+    // Dates in the past mean "retry now"
+    Some(when.duration_since(clock.now()).unwrap_or_default())
+}
+
 fn colorize_status(status: u16) -> String {
     match status {
         200..=299 => format!("{}", status).green().bold().to_string(),
@@ -396,7 +2720,9 @@ fn colorize_status(status: u16) -> String {
 
 fn preview_str(s: &str, max: usize) -> String {
     if s.len() > max {
-        format!("{}... ({} chars)", &s[..max], s.len())
+        // Cut at a char boundary; slicing inside a multibyte char would panic
+        let end = (0..=max).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0);
+        format!("{}... ({} bytes)", &s[..end], s.len())
     } else {
         s.to_string()
     }
@@ -410,3 +2736,450 @@ fn preview_hex(bytes: &[u8], max_bytes: usize) -> String {
         format!("hex:{} ({} bytes)", shown, bytes.len())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn string_attr(value: &str) -> MessageAttributeValue {
+        MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(value)
+            .build()
+            .unwrap()
+    }
+
+
+    fn header_options(duplicate_policy: DuplicateHeaderPolicy) -> AttrHeaderOptions {
+        AttrHeaderOptions { duplicate_policy, strip_prefix: None, add_prefix: None, max_headers: 100, type_headers: false }
+    }
+
+    #[test]
+    fn attrs_to_headers_is_deterministic() {
+        let names = ["X-Event", "x-event", "X-EVENT", "content-type", "User-Agent"];
+        let expected = {
+            let map: HashMap<_, _> = names.iter().map(|n| (n.to_string(), string_attr(n))).collect();
+            attrs_to_headers(Some(&map), &header_options(DuplicateHeaderPolicy::LastWins))
+        };
+        // Different insertion orders (and fresh hashers) must yield identical headers
+        for i in 0..50 {
+            let mut rotated = names.to_vec();
+            rotated.rotate_left(i % names.len());
+            let map: HashMap<_, _> = rotated.iter().map(|n| (n.to_string(), string_attr(n))).collect();
+            assert_eq!(attrs_to_headers(Some(&map), &header_options(DuplicateHeaderPolicy::LastWins)), expected);
+        }
+        assert_eq!(
+            expected,
+            vec![
+                ("content-type".to_string(), "content-type".to_string()),
+                ("user-agent".to_string(), "User-Agent".to_string()),
+                ("x-event".to_string(), "x-event".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn attrs_to_headers_duplicate_policies() {
+        let map: HashMap<_, _> = [("X-Event", "a"), ("x-event", "b")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), string_attr(v)))
+            .collect();
+        let values = |policy| {
+            attrs_to_headers(Some(&map), &header_options(policy))
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(DuplicateHeaderPolicy::LastWins), ["b"]);
+        assert_eq!(values(DuplicateHeaderPolicy::FirstWins), ["a"]);
+        assert_eq!(values(DuplicateHeaderPolicy::Append), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn panics_are_caught_and_left_for_redelivery() {
+        let outcome = catch_panic(async { panic!("bad attribute") }, |panic| Some(MessageOutcome::panicked(&panic)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.disposition, Disposition::Retried);
+        assert_eq!(outcome.error.as_deref(), Some("panicked: bad attribute"));
+        assert_eq!(catch_panic(async { 1 }, |_| 2).await, 1);
+    }
+
+    #[test]
+    fn invalid_attribute_headers_are_dropped_or_sanitized() {
+        assert!(header_pair("x-event", "push", false).is_ok());
+        assert_eq!(header_pair("my event", "push", false).unwrap_err(), "name is not a valid header name");
+        assert_eq!(header_pair("x-note", "a\nb", false).unwrap_err(), "value has control characters");
+
+        let (name, value) = header_pair("my event(1)", "a\r\nb\tcafé", true).unwrap();
+        assert_eq!(name, "my-event-1-");
+        assert_eq!(value.as_bytes(), "a  b\tcafé".as_bytes());
+    }
+
+    #[test]
+    fn attributes_header_is_ascii_json() {
+        let m = Message::builder().message_attributes("note", string_attr("café ☕")).build();
+        let value = attributes_header(&m).unwrap();
+        let json: Value = serde_json::from_slice(value.as_bytes()).unwrap();
+        assert_eq!(json["note"]["data_type"], "String");
+        assert_eq!(json["note"]["string_value"], "café ☕");
+    }
+
+    #[test]
+    fn attrs_to_headers_encodes_by_data_type() {
+        let attr = |data_type: &str| MessageAttributeValue::builder().data_type(data_type);
+        let map: HashMap<_, _> = [
+            ("count", attr("Number").string_value(" \"42\" ").build().unwrap()),
+            ("price", attr("Number.float").string_value("1.5").build().unwrap()),
+            ("note", attr("String").string_value(" hi ").build().unwrap()),
+            ("blob", attr("Binary").binary_value(aws_sdk_sqs::primitives::Blob::new("text")).build().unwrap()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let headers = |type_headers| {
+            let options = AttrHeaderOptions { type_headers, ..header_options(DuplicateHeaderPolicy::LastWins) };
+            attrs_to_headers(Some(&map), &options).into_iter().collect::<HashMap<_, _>>()
+        };
+
+        let plain = headers(false);
+        assert_eq!((plain["count"].as_str(), plain["price"].as_str()), ("42", "1.5"));
+        assert_eq!((plain["note"].as_str(), plain["blob"].as_str()), (" hi ", "text"));
+        assert_eq!(plain.len(), 4);
+
+        let typed = headers(true);
+        assert_eq!(typed["blob"], general_purpose::STANDARD.encode("text"));
+        assert_eq!(typed["x-attr-type-blob"], "Binary");
+        assert_eq!(typed["x-attr-type-price"], "Number.float");
+        assert_eq!(typed["x-attr-type-note"], "String");
+        assert_eq!(typed.len(), 8);
+    }
+
+    #[test]
+    fn attrs_to_headers_caps_header_count() {
+        let map: HashMap<_, _> = ["x-c", "x-a", "x-d", "x-b"].iter().map(|n| (n.to_string(), string_attr(n))).collect();
+        let options = AttrHeaderOptions { max_headers: 2, ..header_options(DuplicateHeaderPolicy::LastWins) };
+        let names: Vec<_> = attrs_to_headers(Some(&map), &options).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(names, ["x-a", "x-b"]);
+    }
+
+    #[test]
+    fn extracts_ip_and_user_agent_from_event_shapes() {
+        let v2 = br#"{"requestContext":{"http":{"sourceIp":"198.51.100.1","userAgent":"curl/8"}}}"#;
+        assert_eq!(extract_ip_from_json_bytes(v2, &[]).as_deref(), Some("198.51.100.1"));
+        assert_eq!(extract_user_agent_from_json_bytes(v2).as_deref(), Some("curl/8"));
+
+        let s3 = br#"{"Records":[{"sourceIPAddress":"198.51.100.2"}]}"#;
+        assert_eq!(extract_ip_from_json_bytes(s3, &[]).as_deref(), Some("198.51.100.2"));
+        assert_eq!(extract_user_agent_from_json_bytes(s3), None);
+
+        let s3 = br#"{"Records":[{"eventName":"ObjectCreated:Put","requestParameters":{"sourceIPAddress":"198.51.100.3"},"s3":{"object":{"key":"foo/bar.json"}}},{"s3":{}}]}"#;
+        assert_eq!(extract_ip_from_json_bytes(s3, &[]).as_deref(), Some("198.51.100.3"));
+        let limits = PreviewLimits { summary_len: 40, response_len: 200, hex_bytes: 24 };
+        let json = serde_json::from_slice(s3).unwrap();
+        assert_eq!(
+            extract_webhook_summary_from_bytes(s3, Some(&json), &limits),
+            "s3:ObjectCreated:Put key:foo/bar.json (+1 more)"
+        );
+
+        let json: Value = serde_json::from_str(r#"{"a":[{"b":1}]}"#).unwrap();
+        assert_eq!(json_path(&json, "a.0.b"), Some(&Value::from(1)));
+        assert_eq!(json_path(&json, "a.1.b"), None);
+        assert_eq!(json_path(&json, "a.x"), None);
+    }
+
+    #[test]
+    fn ip_json_paths_are_tried_first_and_validated() {
+        let body = br#"{"meta":{"client":{"addr":"2001:db8::1"}},"ip":"192.0.2.9","bad":"nope","hops":[{},{"ip":"192.0.2.1, 10.0.0.1"}]}"#;
+        let paths = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(extract_ip_from_json_bytes(body, &paths(&["meta.client.addr"])).as_deref(), Some("2001:db8::1"));
+        assert_eq!(extract_ip_from_json_bytes(body, &paths(&["bad"])).as_deref(), Some("192.0.2.9"));
+        assert_eq!(extract_ip_from_json_bytes(body, &paths(&["hops.*.ip"])).as_deref(), Some("192.0.2.1"));
+        assert_eq!(extract_ip_from_json_bytes(br#"{"ip":"localhost"}"#, &[]), None);
+    }
+
+    #[test]
+    fn forwarded_proto_and_host_are_validated() {
+        assert_eq!(forwarded_proto_value("HTTPS"), Some("https"));
+        assert_eq!(forwarded_proto_value("http, https"), Some("http"));
+        assert_eq!(forwarded_proto_value("ftp"), None);
+        assert!(is_valid_forwarded_host("example.com:8443"));
+        assert!(is_valid_forwarded_host("[2001:db8::1]"));
+        assert!(!is_valid_forwarded_host("evil.com/path"));
+        assert!(!is_valid_forwarded_host("user@example.com"));
+
+        let body = br#"{"headers":{"X-Forwarded-Proto":"https","Host":"hooks.example.com"}}"#;
+        assert_eq!(
+            extract_forwarded_from_json_bytes(body),
+            (Some("https".into()), Some("hooks.example.com".into()))
+        );
+    }
+
+    #[test]
+    fn credential_errors_are_recognized() {
+        use aws_sdk_sqs::{error::ErrorMetadata, operation::receive_message::ReceiveMessageError};
+        let service = |code: &str| {
+            SdkError::service_error(ReceiveMessageError::generic(ErrorMetadata::builder().code(code).build()), ())
+        };
+        assert!(is_credential_error(&service("ExpiredToken")));
+        assert!(!is_credential_error(&service("AWS.SimpleQueueService.NonExistentQueue")));
+
+        let not_loaded = SdkError::<ReceiveMessageError, ()>::construction_failure(CredentialsError::not_loaded("no profile"));
+        assert!(is_credential_error(&not_loaded));
+        let other = SdkError::<ReceiveMessageError, ()>::construction_failure("bad input");
+        assert!(!is_credential_error(&other));
+
+        assert!(is_throttling_error(&service("RequestThrottled")));
+        assert!(is_throttling_error(&service("ThrottlingException")));
+        assert!(!is_throttling_error(&service("ExpiredToken")));
+        assert!(!is_throttling_error(&other));
+        assert!(is_missing_queue_error(&service("AWS.SimpleQueueService.NonExistentQueue")));
+        assert!(!is_missing_queue_error(&service("RequestThrottled")));
+    }
+
+    #[test]
+    fn throttling_shrinks_batches_and_recovers() {
+        let (base, max) = (Duration::from_secs(5), Duration::from_secs(120));
+        let mut throttle = ReceiveThrottle::default();
+        assert_eq!(throttle.batch_size(), 10);
+        assert_eq!(throttle.throttled(base, max), Duration::from_secs(5));
+        assert_eq!(throttle.batch_size(), 5);
+        assert_eq!(throttle.throttled(base, max), Duration::from_secs(10));
+        assert_eq!(throttle.batch_size(), 2);
+        for _ in 0..5 {
+            throttle.throttled(base, max);
+        }
+        assert_eq!(throttle.batch_size(), 1);
+        assert_eq!(throttle.throttled(base, max), Duration::from_secs(40));
+
+        throttle.received();
+        assert_eq!(throttle.batch_size(), 1);
+        assert!(throttle.is_active());
+        (0..3).for_each(|_| throttle.received());
+        assert!(!throttle.is_active());
+        assert_eq!(throttle.batch_size(), 10);
+    }
+
+    #[test]
+    fn envelope_wraps_message_context() {
+        let m = Message::builder()
+            .message_id("m-1")
+            .message_attributes("X-Event", string_attr("push"))
+            .build();
+        let envelope: Value = serde_json::from_slice(&envelope_body(&m, b"\xffraw", 2, "https://q")).unwrap();
+        assert_eq!(envelope["message_id"], "m-1");
+        assert_eq!(envelope["attributes"]["X-Event"]["string_value"], "push");
+        assert_eq!(envelope["body_base64"], general_purpose::STANDARD.encode(b"\xffraw"));
+        assert_eq!(envelope["receive_count"], 2);
+        assert_eq!(envelope["queue_url"], "https://q");
+    }
+
+    #[test]
+    fn relay_target_must_be_allowlisted() {
+        let config = Config::from_pairs(&[
+            ("QUEUE_URL", "https://q"),
+            ("TARGET_URL_ALLOWLIST", "http://localhost:3000/hooks, https://api.internal/"),
+        ])
+        .unwrap();
+        let target = |url: &str| {
+            let attr = MessageAttributeValue::builder().data_type("String").string_value(url).build().unwrap();
+            relay_target(&Message::builder().message_attributes("x-relay-target", attr).build(), &config)
+        };
+        for allowed in ["http://localhost:3000/hooks", "http://localhost:3000/hooks/github?x=1", "https://api.internal:443/any"] {
+            assert_eq!(target(allowed).unwrap().unwrap().as_str(), reqwest::Url::parse(allowed).unwrap().as_str());
+        }
+        for refused in [
+            "http://localhost:3000/hooksmith",
+            "http://localhost:3000/hooks/../admin",
+            "http://localhost:3001/hooks",
+            "https://localhost:3000/hooks",
+            "http://169.254.169.254/latest/meta-data",
+            "http://user@localhost:3000/hooks",
+            "not a url",
+        ] {
+            assert!(matches!(target(refused), Err(RelayError::TargetNotAllowed(_))), "{refused}");
+        }
+        assert_eq!(relay_target(&Message::builder().build(), &config).unwrap(), None);
+        let unset = Config::from_pairs(&[("QUEUE_URL", "https://q")]).unwrap();
+        let attr = MessageAttributeValue::builder().data_type("String").string_value("http://evil").build().unwrap();
+        assert_eq!(relay_target(&Message::builder().message_attributes(X_RELAY_TARGET, attr).build(), &unset).unwrap(), None);
+    }
+
+    #[test]
+    fn query_attributes_extend_existing_query() {
+        let attr = |v: &str| MessageAttributeValue::builder().data_type("String").string_value(v).build().unwrap();
+        let m = Message::builder()
+            .message_attributes("Tenant", attr("acme & co"))
+            .message_attributes("X-Region", attr("eu"))
+            .build();
+        let mappings: Vec<QueryAttribute> = ["tenant", "x-region=region", "missing"].iter().map(|e| e.parse().unwrap()).collect();
+        assert_eq!(
+            with_query_attributes("http://localhost:3000/hook?v=1", &m, &mappings),
+            "http://localhost:3000/hook?v=1&tenant=acme+%26+co&region=eu"
+        );
+        assert_eq!(with_query_attributes("http://localhost:3000/hook", &m, &mappings[2..]), "http://localhost:3000/hook");
+        assert!("=x".parse::<QueryAttribute>().is_err());
+    }
+
+    #[test]
+    fn previews_never_split_multibyte_chars() {
+        // "é" is 2 bytes and "🚀" 4, so byte 5 falls inside the rocket
+        assert_eq!(preview_str("abcé🚀xyz", 5), "abcé... (12 bytes)");
+        assert_eq!(preview_str("🚀🚀", 3), "... (8 bytes)");
+        assert_eq!(preview_str("héllo", 10), "héllo");
+
+        let limits = PreviewLimits { summary_len: 40, response_len: 200, hex_bytes: 24 };
+        let json = serde_json::json!({ "action": "opened", "id": "日本語のイベント識別子です" });
+        assert_eq!(
+            extract_webhook_summary_from_bytes(b"{}", Some(&json), &limits),
+            "action:opened id:日本語のイベント..."
+        );
+    }
+
+    #[test]
+    fn detects_only_unmistakable_base64_bodies() {
+        let b64 = |bytes: &[u8]| general_purpose::STANDARD.encode(bytes);
+        assert_eq!(detect_base64(&b64(br#"{"action":"opened"}"#)).unwrap(), br#"{"action":"opened"}"#);
+        assert_eq!(detect_base64(&b64(b"\x1f\x8b\x08\x00gz")).unwrap(), b"\x1f\x8b\x08\x00gz");
+        // Decodes fine, but to nothing recognizable
+        assert_eq!(detect_base64(&b64(b"just some text")), None);
+        assert_eq!(detect_base64("helloworld12"), None);
+        assert_eq!(detect_base64("hello world!"), None);
+        assert_eq!(detect_base64("12345678"), None);
+        assert_eq!(detect_base64(r#"{"a":1}"#), None);
+    }
+
+    #[test]
+    fn sniffs_content_type_of_unlabelled_bodies() {
+        assert_eq!(sniff_content_type(b"{}", true), "application/json");
+        assert_eq!(sniff_content_type(b"payload=%7B%7D&x=1", false), "application/x-www-form-urlencoded");
+        assert_eq!(sniff_content_type(b"hello world", false), "application/octet-stream");
+        assert_eq!(sniff_content_type(b"=x", false), "application/octet-stream");
+        assert_eq!(sniff_content_type(b"\xff\x00", false), "application/octet-stream");
+    }
+
+    #[test]
+    fn message_age_and_retry_after_dates_follow_the_clock() {
+        let clock = clock::MockClock::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let m = Message::builder()
+            .attributes(MessageSystemAttributeName::SentTimestamp, "1699999990000")
+            .build();
+        assert_eq!(queue_latency(&m, &clock), Some(Duration::from_secs(10)));
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(queue_latency(&m, &clock), Some(Duration::from_millis(12_500)));
+
+        let date = HeaderValue::from_static("Tue, 14 Nov 2023 22:14:00 GMT");
+        assert_eq!(parse_retry_after(&date, &clock), Some(Duration::from_millis(37_500)));
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(parse_retry_after(&date, &clock), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after(&HeaderValue::from_static("30"), &clock), Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn token_bucket_refills_from_the_clock() {
+        let clock = Arc::new(clock::MockClock::at(UNIX_EPOCH));
+        let mut bucket = TokenBucket::new(2.0, clock.clone());
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert_eq!(bucket.tokens, 0.0);
+
+        clock.advance(Duration::from_millis(250));
+        bucket.refill();
+        assert_eq!(bucket.tokens, 0.5);
+        // Idle time never banks more than a second's worth
+        clock.advance(Duration::from_secs(10));
+        bucket.refill();
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn circuit_failures_reset_after_consecutive_successes() {
+        let mut breaker = CircuitBreaker::new(3, 2);
+        breaker.record_failure();
+        breaker.record_failure();
+        // One success between blips isn't enough to forget them
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        breaker.half_open();
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        // Still on probation after recovering: the next failure re-trips
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        breaker.half_open();
+        breaker.record_success();
+        breaker.record_success();
+        assert_eq!(breaker.consecutive_failures, 0);
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn relay_signature_is_hmac_sha256_of_body() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            relay_signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signature_policy_checks_x_hub_signature_256() {
+        let signed = |signature: Option<&str>| {
+            let mut m = Message::builder().message_id("m-1").body("{}");
+            if let Some(signature) = signature {
+                let attr = MessageAttributeValue::builder().data_type("String").string_value(signature).build().unwrap();
+                m = m.message_attributes("X-Hub-Signature-256", attr);
+            }
+            m.build()
+        };
+        let valid = relay_signature("Jefe", b"{}");
+        let valid = signed(Some(valid.to_str().unwrap()));
+        let forged = signed(Some("sha256=00"));
+        let unsigned = signed(None);
+        let config = |policy: &str| {
+            Config::from_pairs(&[("QUEUE_URL", "https://q"), ("WEBHOOK_SECRET", "Jefe"), ("SIGNATURE_POLICY", policy)]).unwrap()
+        };
+
+        let required = config("required");
+        assert!(check_signature(&valid, b"{}", &required).is_ok());
+        assert!(matches!(check_signature(&forged, b"{}", &required), Err(RelayError::Signature(_))));
+        assert!(matches!(check_signature(&unsigned, b"{}", &required), Err(RelayError::Signature(_))));
+        assert!(matches!(check_signature(&valid, b"{\"x\":1}", &required), Err(RelayError::Signature(_))));
+
+        let optional = config("optional");
+        assert!(check_signature(&unsigned, b"{}", &optional).is_ok());
+        assert!(check_signature(&forged, b"{}", &optional).is_err());
+
+        assert!(check_signature(&forged, b"{}", &config("off")).is_ok());
+        assert!(Config::from_pairs(&[("QUEUE_URL", "https://q"), ("SIGNATURE_POLICY", "required")]).is_err());
+    }
+
+    #[test]
+    fn error_backoff_doubles_up_to_cap() {
+        let backoff = |n| error_backoff(Duration::from_secs(2), Duration::from_secs(60), n);
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(6), Duration::from_secs(60));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn header_prefixes_strip_then_add() {
+        let options = AttrHeaderOptions {
+            duplicate_policy: DuplicateHeaderPolicy::LastWins,
+            strip_prefix: Some("webhook-".into()),
+            add_prefix: Some("x-relay-".into()),
+            max_headers: 100,
+            type_headers: false,
+        };
+        assert_eq!(options.header_name("Webhook-X-Event"), "x-relay-x-event");
+        assert_eq!(options.header_name("other"), "x-relay-other");
+        assert_eq!(options.header_name("webhook-"), "x-relay-webhook-");
+    }
+}
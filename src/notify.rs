@@ -0,0 +1,114 @@
+//! Out-of-band alerts for messages that failed for good (dead-lettered or
+//! dropped), enabled by `NOTIFY_URL`.
+
+use serde_json::Value;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::clock::Clock;
+
+/// Alerts are best-effort; a slow webhook must not hold up anything.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct Notifier {
+    url: reqwest::Url,
+    /// Least time between two alerts; failures in between are only counted.
+    min_interval: Duration,
+    http: reqwest::Client,
+    state: Mutex<NotifyState>,
+}
+
+#[derive(Debug, Default)]
+struct NotifyState {
+    last_sent: Option<Instant>,
+    /// Failures not alerted on since the last alert.
+    suppressed: u64,
+}
+
+impl Notifier {
+    pub fn new(url: reqwest::Url, min_interval: Duration) -> Self {
+        let http = reqwest::Client::builder().timeout(NOTIFY_TIMEOUT).build().unwrap_or_default();
+        Self { url, min_interval, http, state: Mutex::default() }
+    }
+
+    /// The alert for a failed message's `/recent`-style entry, or `None` while
+    /// rate-limited. Suppressed failures are reported with the next alert.
+    pub fn alert(&self, entry: &Value, queue_url: &str, clock: &dyn Clock) -> Option<Value> {
+        let now = clock.instant();
+        let mut state = self.state.lock().unwrap();
+        if state.last_sent.is_some_and(|at| now.duration_since(at) < self.min_interval) {
+            state.suppressed += 1;
+            return None;
+        }
+        state.last_sent = Some(now);
+        let suppressed = std::mem::take(&mut state.suppressed);
+
+        let mut text = format!(
+            "sqs-webhook-relay: message {} {} (status {}, attempt {}): {}",
+            entry["message_id"].as_str().unwrap_or("unknown"),
+            entry["disposition"].as_str().unwrap_or("failed"),
+            entry["status"].as_u64().map_or("none".into(), |s| s.to_string()),
+            entry["attempt"],
+            entry["summary"].as_str().unwrap_or_default(),
+        );
+        if suppressed > 0 {
+            text.push_str(&format!(" (+{suppressed} more since the last alert)"));
+        }
+        let mut alert = entry.clone();
+        alert["queue_url"] = queue_url.into();
+        alert["suppressed"] = suppressed.into();
+        // Slack reads `text` and Discord `content`; generic receivers get the fields
+        alert["text"] = text.clone().into();
+        alert["content"] = text.into();
+        Some(alert)
+    }
+
+    /// POSTs an alert in the background.
+    pub fn send(&self, alert: Value) {
+        let (http, url) = (self.http.clone(), self.url.clone());
+        tokio::spawn(async move {
+            match http.post(url).json(&alert).send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!("Sent terminal failure alert for message {}", alert["message_id"]),
+                Err(e) => warn!("Failed to send terminal failure alert to NOTIFY_URL: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use serde_json::json;
+
+    #[test]
+    fn alerts_are_rate_limited_and_count_suppressed_failures() {
+        let clock = MockClock::at(std::time::SystemTime::now());
+        let notifier = Notifier::new("http://127.0.0.1:9/alerts".parse().unwrap(), Duration::from_secs(60));
+        let entry = json!({
+            "message_id": "m-1",
+            "summary": "push",
+            "status": 500,
+            "disposition": "deadlettered",
+            "attempt": 5,
+            "error": null,
+        });
+
+        let alert = notifier.alert(&entry, "https://sqs.test/queue", &clock).unwrap();
+        assert_eq!(alert["text"], "sqs-webhook-relay: message m-1 deadlettered (status 500, attempt 5): push");
+        assert_eq!(alert["queue_url"], "https://sqs.test/queue");
+
+        clock.advance(Duration::from_secs(30));
+        assert!(notifier.alert(&entry, "https://sqs.test/queue", &clock).is_none());
+        assert!(notifier.alert(&entry, "https://sqs.test/queue", &clock).is_none());
+
+        clock.advance(Duration::from_secs(30));
+        let alert = notifier.alert(&entry, "https://sqs.test/queue", &clock).unwrap();
+        assert_eq!(alert["suppressed"], 2);
+        assert!(alert["content"].as_str().unwrap().ends_with("(+2 more since the last alert)"));
+    }
+}
@@ -0,0 +1,36 @@
+//! Optional OpenTelemetry trace export, enabled by `OTEL_EXPORTER_OTLP_ENDPOINT`.
+
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+/// Builds the OTLP tracing layer when an endpoint is configured.
+///
+/// The exporter reads the standard `OTEL_EXPORTER_OTLP_*` env vars itself. Only
+/// this crate's spans are exported so AWS SDK internals don't flood the backend.
+/// The returned provider must be shut down on exit to flush pending spans.
+pub fn layer<S>() -> Result<Option<(impl Layer<S>, SdkTracerProvider)>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").map_or(true, |v| v.is_empty()) {
+        return Ok(None);
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("failed to build OTLP span exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(env!("CARGO_PKG_NAME")).build())
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), tracing::Level::INFO));
+    Ok(Some((layer, provider)))
+}
@@ -0,0 +1,165 @@
+//! Status-code policy: what happens to a message given the local response.
+
+use std::{ops::RangeInclusive, str::FromStr, time::Duration};
+
+use crate::Outcome;
+
+/// What a `STATUS_POLICY` rule does with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusAction {
+    /// Delivered; delete it (`ignore` is accepted as an alias).
+    Delete,
+    /// Leave it for redelivery.
+    Retry,
+    /// Retry on the first receive, drop after that.
+    RetryOnce,
+    /// Hide it for `Retry-After`, or an exponential delay when there is none.
+    Backoff,
+    /// Move it to `DLQ_URL`.
+    Dlq,
+    /// Undeliverable; delete it without retrying.
+    Drop,
+}
+
+impl FromStr for StatusAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "delete" | "ignore" => Ok(Self::Delete),
+            "retry" => Ok(Self::Retry),
+            "retry_once" | "retry-once" => Ok(Self::RetryOnce),
+            "backoff" => Ok(Self::Backoff),
+            "dlq" => Ok(Self::Dlq),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!("unknown action '{}': expected delete, retry, retry_once, backoff, dlq or drop", s.trim())),
+        }
+    }
+}
+
+/// Status ranges mapped to actions; the first matching rule wins, and
+/// statuses no rule covers are retried. `retry` and `retry_once` honor a
+/// `Retry-After` on 429 and 503 by holding the message for that long.
+#[derive(Debug, Clone)]
+pub struct StatusPolicy {
+    rules: Vec<(RangeInclusive<u16>, StatusAction)>,
+    /// Base and cap of the `backoff` delay without `Retry-After`.
+    backoff: (Duration, Duration),
+}
+
+impl StatusPolicy {
+    /// `STATUS_POLICY` rules (`422=drop,429=backoff,5xx=dlq,500-502=retry`)
+    /// ahead of the built-in ones, which `delete_on_404` is part of.
+    pub fn new(overrides: &[String], delete_on_404: bool, backoff: (Duration, Duration)) -> Result<Self, String> {
+        let mut rules = overrides.iter().map(|rule| parse_rule(rule)).collect::<Result<Vec<_>, _>>()?;
+        rules.extend([
+            (200..=299, StatusAction::Delete),
+            // Endpoint missing; safe to drop unless it may just be mid-deploy
+            (404..=404, if delete_on_404 { StatusAction::Drop } else { StatusAction::Retry }),
+            // Retry once for 4xx (e.g., signature mismatch on first try)
+            (400..=499, StatusAction::RetryOnce),
+        ]);
+        Ok(Self { rules, backoff })
+    }
+
+    /// Whether any rule sends messages to the DLQ.
+    pub fn uses_dlq(&self) -> bool {
+        self.rules.iter().any(|(_, action)| *action == StatusAction::Dlq)
+    }
+
+    pub fn action(&self, status: u16) -> StatusAction {
+        self.rules
+            .iter()
+            .find(|(range, _)| range.contains(&status))
+            .map_or(StatusAction::Retry, |(_, action)| *action)
+    }
+
+    /// Decides what happens to a message given the local response status (`None`
+    /// when no response arrived), its `Retry-After` delay and the SQS receive count.
+    pub fn decide(&self, status: Option<u16>, retry_after: Option<Duration>, receive_count: u32) -> Outcome {
+        // Network errors: let SQS redeliver
+        let Some(status) = status else {
+            return Outcome::RetrySoon;
+        };
+        match self.action(status) {
+            StatusAction::Delete => Outcome::Delete,
+            // Local service asked for a specific delay; hold the message in SQS for it
+            StatusAction::Retry | StatusAction::RetryOnce if matches!(status, 429 | 503) && retry_after.is_some() => {
+                Outcome::RetryBackoff(retry_after.unwrap_or_default())
+            }
+            StatusAction::Retry => Outcome::RetrySoon,
+            StatusAction::RetryOnce if receive_count <= 1 => Outcome::RetrySoon,
+            StatusAction::RetryOnce | StatusAction::Drop => Outcome::Drop,
+            StatusAction::Backoff => Outcome::RetryBackoff(retry_after.unwrap_or_else(|| {
+                let (base, max) = self.backoff;
+                crate::error_backoff(base, max, receive_count)
+            })),
+            StatusAction::Dlq => Outcome::DeadLetter,
+        }
+    }
+}
+
+/// `404=drop`, `500-504=retry` or `4xx=dlq`.
+fn parse_rule(rule: &str) -> Result<(RangeInclusive<u16>, StatusAction), String> {
+    let (codes, action) = rule.split_once('=').ok_or_else(|| format!("expected STATUS=action, got '{rule}'"))?;
+    let codes = codes.trim().to_ascii_lowercase();
+    let code = |s: &str| s.trim().parse::<u16>().ok().filter(|c| (100..=599).contains(c));
+    let range = if let Some(class) = codes.strip_suffix("xx").and_then(|c| c.parse::<u16>().ok()).filter(|c| (1..=5).contains(c)) {
+        class * 100..=class * 100 + 99
+    } else if let Some((from, to)) = codes.split_once('-') {
+        code(from).zip(code(to)).filter(|(from, to)| from <= to).map(|(from, to)| from..=to)
+            .ok_or_else(|| format!("invalid status range '{codes}'"))?
+    } else {
+        code(&codes).map(|c| c..=c).ok_or_else(|| format!("invalid status code '{codes}'"))?
+    };
+    Ok((range, action.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKOFF: (Duration, Duration) = (Duration::from_secs(2), Duration::from_secs(60));
+
+    #[test]
+    fn defaults_match_built_in_behavior() {
+        let policy = StatusPolicy::new(&[], true, BACKOFF).unwrap();
+        let decide = |status, retry_after, receive_count| policy.decide(status, retry_after, receive_count);
+        assert_eq!(decide(Some(200), None, 1), Outcome::Delete);
+        assert_eq!(decide(Some(204), None, 3), Outcome::Delete);
+        assert_eq!(decide(Some(404), None, 1), Outcome::Drop);
+        assert_eq!(decide(Some(401), None, 1), Outcome::RetrySoon);
+        assert_eq!(decide(Some(401), None, 2), Outcome::Drop);
+        assert_eq!(decide(Some(503), None, 5), Outcome::RetrySoon);
+        assert_eq!(decide(Some(503), Some(Duration::from_secs(30)), 1), Outcome::RetryBackoff(Duration::from_secs(30)));
+        assert_eq!(decide(Some(429), None, 1), Outcome::RetrySoon);
+        assert_eq!(decide(Some(429), None, 2), Outcome::Drop);
+        assert_eq!(decide(Some(429), Some(Duration::from_secs(5)), 3), Outcome::RetryBackoff(Duration::from_secs(5)));
+        assert_eq!(decide(Some(500), Some(Duration::from_secs(5)), 1), Outcome::RetrySoon);
+        assert_eq!(decide(Some(302), None, 1), Outcome::RetrySoon);
+        assert_eq!(decide(None, None, 1), Outcome::RetrySoon);
+
+        let keep_404 = StatusPolicy::new(&[], false, BACKOFF).unwrap();
+        assert_eq!(keep_404.decide(Some(404), None, 5), Outcome::RetrySoon);
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let rules: Vec<String> = ["422=drop", "429=backoff", "418=ignore", "5xx=dlq", "500-502=retry"]
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        let policy = StatusPolicy::new(&rules, true, BACKOFF).unwrap();
+        assert_eq!(policy.decide(Some(422), None, 1), Outcome::Drop);
+        assert_eq!(policy.decide(Some(429), None, 3), Outcome::RetryBackoff(Duration::from_secs(8)));
+        assert_eq!(policy.decide(Some(418), None, 1), Outcome::Delete);
+        // Earlier rules win, so 5xx=dlq shadows the narrower 500-502 rule
+        assert_eq!(policy.decide(Some(500), None, 1), Outcome::DeadLetter);
+        assert_eq!(policy.decide(Some(404), None, 1), Outcome::Drop);
+        assert!(policy.uses_dlq());
+
+        for bad in ["422", "99=drop", "6xx=drop", "500-400=retry", "404=explode"] {
+            assert!(StatusPolicy::new(&[bad.to_string()], true, BACKOFF).is_err(), "{bad}");
+        }
+    }
+}
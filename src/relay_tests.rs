@@ -0,0 +1,829 @@
+//! End-to-end tests of a message through `process_message` and `apply_outcome`,
+//! against a wiremock endpoint and a mocked SQS client.
+
+use super::*;
+use aws_sdk_sqs::operation::change_message_visibility::ChangeMessageVisibilityOutput;
+use aws_sdk_sqs::operation::delete_message::DeleteMessageOutput;
+use aws_sdk_sqs::operation::get_queue_attributes::GetQueueAttributesOutput;
+use aws_sdk_sqs::operation::get_queue_url::GetQueueUrlOutput;
+use aws_sdk_sqs::operation::list_queues::ListQueuesOutput;
+use aws_sdk_sqs::operation::receive_message::ReceiveMessageOutput;
+use aws_sdk_sqs::operation::send_message::SendMessageOutput;
+use aws_smithy_mocks::{create_mock_http_client, mock, MockResponseInterceptor, Rule};
+use wiremock::matchers::{body_bytes, body_json, header, header_exists, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// Not valid UTF-8, so any lossy conversion on the way would show.
+const BODY: &[u8] = b"{\"ref\":\"refs/heads/main\"}\xff\x00";
+
+fn string_attr(value: &str) -> MessageAttributeValue {
+    MessageAttributeValue::builder().data_type("String").string_value(value).build().unwrap()
+}
+
+fn message() -> Message {
+    Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body(general_purpose::STANDARD.encode(BODY))
+        .message_attributes("BodyIsBase64", string_attr("true"))
+        .message_attributes("X-GitHub-Event", string_attr("push"))
+        .message_attributes("X-Hub-Signature-256", string_attr("sha256=abc"))
+        .message_attributes("sourceIp", string_attr("203.0.113.7"))
+        .attributes(MessageSystemAttributeName::ApproximateReceiveCount, "1")
+        .build()
+}
+
+fn delete_rule() -> Rule {
+    mock!(aws_sdk_sqs::Client::delete_message)
+        .match_requests(|req| req.receipt_handle() == Some("receipt-1"))
+        .then_output(|| DeleteMessageOutput::builder().build())
+}
+
+/// Equivalent of `mock_client!`, which needs a newer SDK than the one pinned here.
+fn sqs_client(rules: &[&Rule]) -> aws_sdk_sqs::Client {
+    let interceptor = rules.iter().fold(MockResponseInterceptor::new(), |i, rule| i.with_rule(rule));
+    let conf = aws_sdk_sqs::Config::builder()
+        .with_test_defaults()
+        .http_client(create_mock_http_client())
+        .interceptor(interceptor)
+        .build();
+    aws_sdk_sqs::Client::from_conf(conf)
+}
+
+async fn relay_once(server: &MockServer, delete: &Rule) -> Option<MessageOutcome> {
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue")]).unwrap();
+    let sqs = sqs_client(&[delete]);
+    let (http, local_url) = build_http_client(&config.client, &format!("{}/webhook", server.uri())).unwrap();
+    let m = message();
+    let result = process_message(&http, &local_url, &config, None, &m).await;
+    apply_outcome(&sqs, &config.queue_url, &config, &m, result).await
+}
+
+#[tokio::test]
+async fn forwards_exact_bytes_and_headers_then_deletes() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/webhook"))
+        .and(body_bytes(BODY))
+        .and(header("x-github-event", "push"))
+        .and(header("x-hub-signature-256", "sha256=abc"))
+        .and(header("x-forwarded-for", "203.0.113.7"))
+        .and(header("user-agent", concat!("sqs-webhook-relay/", env!("CARGO_PKG_VERSION"))))
+        .and(header("content-length", BODY.len().to_string().as_str()))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let delete = delete_rule();
+    let outcome = relay_once(&server, &delete).await.unwrap();
+    assert!(outcome.success);
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn preserve_header_case_sends_attribute_spellings() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // wiremock normalizes names, so read the raw request instead
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_url = format!("http://{}/webhook", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !request.windows(BODY.len()).any(|w| w == BODY) {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+        String::from_utf8_lossy(&request).into_owned()
+    });
+
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("PRESERVE_HEADER_CASE", "true")]).unwrap();
+    let (http, local_url) = build_http_client(&config.client, &local_url).unwrap();
+    let decision = process_message(&http, &local_url, &config, None, &message()).await.unwrap();
+    let request = server.await.unwrap();
+    assert_eq!(decision.status, Some(200));
+    assert!(request.contains("\r\nX-GitHub-Event: push\r\n"), "{request}");
+    assert!(request.contains("\r\nX-Hub-Signature-256: sha256=abc\r\n"), "{request}");
+    // Headers the relay adds itself have no attribute spelling
+    assert!(request.contains("\r\nx-forwarded-for: 203.0.113.7\r\n"), "{request}");
+}
+
+#[tokio::test]
+async fn server_error_leaves_message_in_queue() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let delete = delete_rule();
+    let outcome = relay_once(&server, &delete).await.unwrap();
+    assert!(!outcome.success);
+    assert_eq!(outcome.local_down, Some(true));
+    assert_eq!(outcome.disposition, Disposition::Retried);
+    assert_eq!(delete.num_calls(), 0);
+}
+
+#[tokio::test]
+async fn expose_receipt_sends_handle_and_leaves_deletion_to_consumer() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("x-sqs-receipt-handle", "receipt-1"))
+        .and(header("x-sqs-queue-url", "https://sqs.test/queue"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("EXPOSE_RECEIPT", "true")]).unwrap();
+    let delete = delete_rule();
+    let sqs = sqs_client(&[&delete]);
+    let (http, local_url) = build_http_client(&config.client, &format!("{}/webhook", server.uri())).unwrap();
+    let m = message();
+    let result = process_message(&http, &local_url, &config, None, &m).await;
+    let outcome = apply_outcome(&sqs, &config.queue_url, &config, &m, result).await.unwrap();
+    assert!(outcome.success);
+    assert_eq!(outcome.disposition, Disposition::HandedOff);
+    assert_eq!(delete.num_calls(), 0);
+}
+
+#[tokio::test]
+async fn batch_forward_posts_one_array_then_deletes_all() {
+    let server = MockServer::start().await;
+    let expected = serde_json::json!([
+        general_purpose::STANDARD.encode(BODY),
+        {"action": "opened"},
+    ]);
+    Mock::given(method("POST"))
+        .and(path("/webhook"))
+        .and(header("x-sqs-batch-size", "2"))
+        .and(body_json(&expected))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let delete = mock!(aws_sdk_sqs::Client::delete_message)
+        .then_output(|| DeleteMessageOutput::builder().build());
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("BATCH_FORWARD", "true")]).unwrap();
+    let json_message = Message::builder()
+        .message_id("m-2")
+        .receipt_handle("receipt-2")
+        .body(r#"{"action":"opened"}"#)
+        .build();
+    let local_url = format!("{}/webhook", server.uri());
+    let msgs = [message(), json_message];
+    let outcomes = relay_batch(
+        &sqs_client(&[&delete]),
+        &reqwest::Client::new(),
+        &config.queue_url,
+        &local_url,
+        &config,
+        None,
+        &msgs,
+    )
+    .await;
+
+    assert_eq!(outcomes.len(), 2);
+    assert!(outcomes.iter().all(|(_, o)| o.success && o.disposition == Disposition::Deleted));
+    assert_eq!(delete.num_calls(), 2);
+}
+
+#[tokio::test]
+async fn batch_forward_records_each_message_in_recent() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+
+    let receive = mock!(aws_sdk_sqs::Client::receive_message)
+        .sequence()
+        .output(|| ReceiveMessageOutput::builder().messages(message()).build())
+        .output(|| ReceiveMessageOutput::builder().build())
+        .build();
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("DRAIN_AND_EXIT", "true"),
+        ("BATCH_FORWARD", "true"),
+    ])
+    .unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let status = RelayStatus::new(10, Arc::new(clock::SystemClock));
+    let queue_url = config.queue_url.clone();
+    let live = LiveConfig::new(config);
+    relay_loop(&sqs_client(&[&receive, &delete]), &reqwest::Client::new(), &queue_url, &local_url, &live, &mut RelayStats::default(), &status).await;
+
+    let recent = status.recent();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0]["message_id"], "m-1");
+    assert_eq!(recent[0]["disposition"], "deleted");
+}
+
+#[tokio::test]
+async fn empty_body_is_dropped_without_forwarding() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(0).mount(&server).await;
+
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("EMPTY_BODY_ACTION", "drop")]).unwrap();
+    let m = Message::builder().message_id("m-1").receipt_handle("receipt-1").body("").build();
+    let local_url = format!("{}/webhook", server.uri());
+    let result = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await;
+    let outcome = apply_outcome(&sqs_client(&[&delete]), &config.queue_url, &config, &m, result).await.unwrap();
+
+    assert_eq!(outcome.local_down, None);
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn disable_xff_forwards_x_forwarded_for_as_received() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("x-forwarded-for", "198.51.100.1"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("DISABLE_XFF", "true")]).unwrap();
+    let m = Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body(r#"{"sourceIp":"203.0.113.9"}"#)
+        .message_attributes("X-Forwarded-For", string_attr("198.51.100.1"))
+        .message_attributes("sourceIp", string_attr("203.0.113.7"))
+        .build();
+    let local_url = format!("{}/webhook", server.uri());
+    let decision = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await.unwrap();
+    assert_eq!(decision.status, Some(200));
+}
+
+#[tokio::test]
+async fn drain_mode_stops_at_first_empty_receive() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+
+    let receive = mock!(aws_sdk_sqs::Client::receive_message)
+        .sequence()
+        .output(|| ReceiveMessageOutput::builder().messages(message()).build())
+        .output(|| ReceiveMessageOutput::builder().build())
+        .build();
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("DRAIN_AND_EXIT", "true")]).unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let mut stats = RelayStats::default();
+    let sqs = sqs_client(&[&receive, &delete]);
+    let queue_url = config.queue_url.clone();
+    relay_loop(&sqs, &reqwest::Client::new(), &queue_url, &local_url, &LiveConfig::new(config), &mut stats, &RelayStatus::default()).await;
+
+    assert_eq!(receive.num_calls(), 2);
+    assert_eq!((stats.processed, stats.deleted), (1, 1));
+}
+
+#[tokio::test]
+async fn max_messages_total_stops_mid_batch_and_clears_batch_size() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+
+    let second = Message::builder().message_id("m-2").receipt_handle("receipt-2").body("{}").build();
+    let receive = mock!(aws_sdk_sqs::Client::receive_message)
+        .then_output(move || ReceiveMessageOutput::builder().messages(message()).messages(second.clone()).build());
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("MAX_MESSAGES_TOTAL", "1")]).unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let (mut stats, status) = (RelayStats::default(), RelayStatus::default());
+    let queue_url = config.queue_url.clone();
+    relay_loop(&sqs_client(&[&receive, &delete]), &reqwest::Client::new(), &queue_url, &local_url, &LiveConfig::new(config), &mut stats, &status).await;
+
+    assert_eq!(stats.processed, 1);
+    assert_eq!(status.batch_size.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn attempt_cap_requeues_with_count_then_dead_letters() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)).expect(1).mount(&server).await;
+
+    let requeue = mock!(aws_sdk_sqs::Client::send_message)
+        .match_requests(|req| {
+            req.queue_url() == Some("https://sqs.test/queue")
+                && req.message_attributes().and_then(|a| a.get(X_RELAY_ATTEMPTS)?.string_value()) == Some("2")
+        })
+        .then_output(|| SendMessageOutput::builder().build());
+    let dead_letter = mock!(aws_sdk_sqs::Client::send_message)
+        .match_requests(|req| req.queue_url() == Some("https://sqs.test/dlq"))
+        .then_output(|| SendMessageOutput::builder().build());
+    let delete = mock!(aws_sdk_sqs::Client::delete_message).then_output(|| DeleteMessageOutput::builder().build());
+    let sqs = sqs_client(&[&requeue, &dead_letter, &delete]);
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("DLQ_URL", "https://sqs.test/dlq"),
+        ("MAX_TOTAL_ATTEMPTS", "2"),
+    ])
+    .unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let attempt = |n: &str| {
+        Message::builder()
+            .message_id("m-1")
+            .receipt_handle("receipt-1")
+            .body("{}")
+            .message_attributes(X_RELAY_ATTEMPTS, string_attr(n))
+            .build()
+    };
+
+    for (attempts, disposition) in [("1", Disposition::Retried), ("2", Disposition::DeadLettered)] {
+        let m = attempt(attempts);
+        let result = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await;
+        let outcome = apply_outcome(&sqs, &config.queue_url, &config, &m, result).await.unwrap();
+        assert_eq!(outcome.disposition, disposition);
+    }
+    assert_eq!((requeue.num_calls(), dead_letter.num_calls(), delete.num_calls()), (1, 1, 2));
+}
+
+#[tokio::test]
+async fn requeued_copy_counts_carried_attempts_and_keeps_them_out_of_headers() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(422)).expect(1).mount(&server).await;
+    let delete = mock!(aws_sdk_sqs::Client::delete_message).then_output(|| DeleteMessageOutput::builder().build());
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("DLQ_URL", "https://sqs.test/dlq"),
+        ("MAX_TOTAL_ATTEMPTS", "5"),
+    ])
+    .unwrap();
+    // A fresh copy (receive count 1) of a message that already failed once
+    let m = Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body("{}")
+        .attributes(MessageSystemAttributeName::ApproximateReceiveCount, "1")
+        .message_attributes("x-relay-attempts", string_attr("1"))
+        .message_attributes("x-relay-target", string_attr("/other"))
+        .build();
+
+    let result = process_message(&reqwest::Client::new(), &format!("{}/webhook", server.uri()), &config, None, &m).await;
+    let outcome = apply_outcome(&sqs_client(&[&delete]), &config.queue_url, &config, &m, result).await.unwrap();
+
+    // retry_once already had its retry, so the 4xx drops
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+    let received = server.received_requests().await.unwrap();
+    assert!(received[0].headers.get(X_RELAY_ATTEMPTS).is_none());
+    assert!(received[0].headers.get(X_RELAY_TARGET).is_none());
+}
+
+#[tokio::test]
+async fn timeouts_back_off_without_counting_as_down_and_refusals_are_classified() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+        .mount(&server)
+        .await;
+    let hide = mock!(aws_sdk_sqs::Client::change_message_visibility)
+        .match_requests(|req| req.visibility_timeout() == Some(2))
+        .then_output(|| ChangeMessageVisibilityOutput::builder().build());
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("FORWARD_TIMEOUT_SECS", "1"),
+        ("TIMEOUT_ACTION", "backoff"),
+    ])
+    .unwrap();
+    let m = message();
+    let result = process_message(&reqwest::Client::new(), &format!("{}/webhook", server.uri()), &config, None, &m).await;
+    assert_eq!(result.as_ref().err().and_then(RelayError::forward_error_kind), Some(ForwardErrorKind::Timeout));
+    let outcome = apply_outcome(&sqs_client(&[&hide]), &config.queue_url, &config, &m, result).await.unwrap();
+    assert_eq!(outcome.local_down, Some(false));
+    assert_eq!(outcome.disposition, Disposition::Retried);
+    assert_eq!(hide.num_calls(), 1);
+
+    // Nothing listens on the discard port
+    let result = process_message(&reqwest::Client::new(), "http://127.0.0.1:9/webhook", &config, None, &m).await;
+    let outcome = MessageOutcome::failed(&result.err().unwrap());
+    assert_eq!((outcome.error_kind, outcome.local_down), (Some(ForwardErrorKind::Connect), Some(true)));
+}
+
+#[tokio::test]
+async fn queue_name_resolves_to_url() {
+    let lookup = mock!(aws_sdk_sqs::Client::get_queue_url)
+        .match_requests(|req| req.queue_name() == Some("hooks") && req.queue_owner_aws_account_id() == Some("123456789012"))
+        .then_output(|| GetQueueUrlOutput::builder().queue_url("https://sqs.test/123456789012/hooks").build());
+    let url = resolve_queue_url(&sqs_client(&[&lookup]), "hooks", Some("123456789012")).await.unwrap();
+    assert_eq!(url, "https://sqs.test/123456789012/hooks");
+}
+
+#[tokio::test]
+async fn queue_prefix_lists_every_page() {
+    let first = mock!(aws_sdk_sqs::Client::list_queues)
+        .match_requests(|req| req.queue_name_prefix() == Some("hooks-") && req.next_token().is_none())
+        .then_output(|| {
+            ListQueuesOutput::builder()
+                .queue_urls("https://sqs.test/1/hooks-a")
+                .queue_urls("https://sqs.test/1/hooks-b")
+                .next_token("page-2")
+                .build()
+        });
+    let second = mock!(aws_sdk_sqs::Client::list_queues)
+        .match_requests(|req| req.next_token() == Some("page-2"))
+        .then_output(|| ListQueuesOutput::builder().queue_urls("https://sqs.test/1/hooks-c").build());
+    let urls = list_queue_urls(&sqs_client(&[&first, &second]), "hooks-").await.unwrap();
+    assert_eq!(urls, ["https://sqs.test/1/hooks-a", "https://sqs.test/1/hooks-b", "https://sqs.test/1/hooks-c"]);
+}
+
+#[tokio::test]
+async fn route_overrides_headers_and_status_policy() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("x-team", "billing"))
+        .and(header("x-github-event", "replaced"))
+        .respond_with(ResponseTemplate::new(409))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let routes = serde_json::json!([{
+        "url": format!("{}/webhook", server.uri()),
+        "status_policy": ["409=delete"],
+        "headers": {"X-Team": "billing", "X-GitHub-Event": "replaced"},
+    }]);
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("ROUTES", &routes.to_string())]).unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let decision = process_message(&reqwest::Client::new(), &local_url, &config, None, &message()).await.unwrap();
+    assert_eq!((decision.outcome, decision.status), (Outcome::Delete, Some(409)));
+}
+
+#[tokio::test]
+async fn non_utf8_bodies_are_wrapped_or_dropped() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("content-type", "application/json"))
+        .and(body_json(serde_json::json!({ "data_base64": general_purpose::STANDARD.encode(BODY) })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let local_url = format!("{}/webhook", server.uri());
+    let http = reqwest::Client::new();
+
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("NON_UTF8_ACTION", "base64-wrap")]).unwrap();
+    let decision = process_message(&http, &local_url, &config, None, &message()).await.unwrap();
+    assert_eq!(decision.status, Some(200));
+
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("NON_UTF8_ACTION", "drop")]).unwrap();
+    let result = process_message(&http, &local_url, &config, None, &message()).await;
+    assert!(matches!(result, Err(RelayError::NonUtf8Body)));
+    let outcome = apply_outcome(&sqs_client(&[&delete]), &config.queue_url, &config, &message(), result).await.unwrap();
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn unexpected_content_type_is_dropped_without_forwarding() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(0).mount(&server).await;
+
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("EXPECTED_CONTENT_TYPES", "application/json, application/x-www-form-urlencoded"),
+    ])
+    .unwrap();
+    let m = Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body("<xml/>")
+        .message_attributes("content-type", string_attr("text/xml; charset=utf-8"))
+        .build();
+    let local_url = format!("{}/webhook", server.uri());
+    let result = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await;
+    assert!(matches!(result, Err(RelayError::UnexpectedContentType(_))));
+    let outcome = apply_outcome(&sqs_client(&[&delete]), &config.queue_url, &config, &m, result).await.unwrap();
+
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn large_bodies_are_gzipped_and_signed_uncompressed() {
+    let body = br#"{"action":"opened","pull_request":{"title":"Add gzip"}}"#.repeat(40);
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(header("content-encoding", "gzip"))
+        .and(header("content-length", gzip::compress(&body).len().to_string().as_str()))
+        .and(header("x-relay-signature", relay_signature("s3cret", &body)))
+        .and(body_bytes(gzip::compress(&body)))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(body_bytes(&b"{}"[..])).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("FORWARD_COMPRESSION", "gzip"),
+        ("RELAY_SIGNING_SECRET", "s3cret"),
+    ])
+    .unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    for body in [body.as_slice(), b"{}"] {
+        let m = Message::builder()
+            .message_id("m-1")
+            .receipt_handle("receipt-1")
+            .body(String::from_utf8(body.to_vec()).unwrap())
+            .build();
+        let decision = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await.unwrap();
+        assert_eq!(decision.status, Some(200));
+    }
+}
+
+#[tokio::test]
+async fn extended_client_payload_is_fetched_from_s3_then_deleted() {
+    let s3 = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/payloads/events/big%20push.json"))
+        .and(header_exists("authorization"))
+        .and(header("x-amz-date", "20231114T221320Z"))
+        .and(header_exists("x-amz-content-sha256"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"ref":"refs/heads/main"}"#))
+        .expect(1)
+        .mount(&s3)
+        .await;
+    Mock::given(method("DELETE")).and(path("/payloads/events/big%20push.json")).respond_with(ResponseTemplate::new(204)).expect(1).mount(&s3).await;
+    let local = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_bytes(&br#"{"ref":"refs/heads/main"}"#[..]))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&local)
+        .await;
+
+    let mut config = Config::from_pairs(&[("QUEUE_URL", "https://sqs.test/queue"), ("EXTENDED_CLIENT", "true")]).unwrap();
+    let credentials = aws_credential_types::Credentials::new("AKIDEXAMPLE", "secret", None, None, "test");
+    config.payload_store = Some(PayloadStore::new(SharedCredentialsProvider::new(credentials), "us-east-1", Some(s3.uri()), true));
+    config.clock = Arc::new(clock::MockClock::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+    let m = Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body(r#"["software.amazon.payloadoffloading.PayloadS3Pointer",{"s3BucketName":"payloads","s3Key":"events/big push.json"}]"#)
+        .message_attributes("ExtendedPayloadSize", string_attr("300000"))
+        .build();
+    let delete = delete_rule();
+    let local_url = format!("{}/webhook", local.uri());
+    let result = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await;
+    let outcome = apply_outcome(&sqs_client(&[&delete]), &config.queue_url, &config, &m, result).await.unwrap();
+
+    assert_eq!(outcome.disposition, Disposition::Deleted);
+    assert_eq!(delete.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn json_missing_required_keys_is_dead_lettered() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(2).mount(&server).await;
+
+    let dead_letter = mock!(aws_sdk_sqs::Client::send_message)
+        .match_requests(|req| req.queue_url() == Some("https://sqs.test/dlq"))
+        .then_output(|| SendMessageOutput::builder().build());
+    let delete = delete_rule();
+    let sqs = sqs_client(&[&dead_letter, &delete]);
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("DLQ_URL", "https://sqs.test/dlq"),
+        ("REQUIRED_JSON_KEYS", "repository,sender"),
+        ("SCHEMA_MISMATCH_ACTION", "dlq"),
+    ])
+    .unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let cases = [
+        (r#"{"repository":{"id":1},"sender":null}"#, Disposition::DeadLettered),
+        (r#"{"repository":{"id":1},"sender":{"login":"octocat"}}"#, Disposition::Deleted),
+        // Not JSON, so not subject to the check
+        ("payload=1", Disposition::Deleted),
+    ];
+    for (body, disposition) in cases {
+        let m = Message::builder().message_id("m-1").receipt_handle("receipt-1").body(body).build();
+        let result = process_message(&reqwest::Client::new(), &local_url, &config, None, &m).await;
+        let outcome = apply_outcome(&sqs, &config.queue_url, &config, &m, result).await.unwrap();
+        assert_eq!(outcome.disposition, disposition, "{body}");
+    }
+    assert_eq!(dead_letter.num_calls(), 1);
+}
+
+#[tokio::test]
+async fn recently_delivered_duplicate_is_deleted_without_forwarding() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+
+    let receive = mock!(aws_sdk_sqs::Client::receive_message)
+        .sequence()
+        .output(|| ReceiveMessageOutput::builder().messages(message()).build())
+        .output(|| ReceiveMessageOutput::builder().messages(message()).build())
+        .output(|| ReceiveMessageOutput::builder().build())
+        .build();
+    let delete = delete_rule();
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("DRAIN_AND_EXIT", "true"),
+        ("DEDUP_TTL_SECS", "300"),
+    ])
+    .unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let mut stats = RelayStats::default();
+    let status = RelayStatus::default();
+    let queue_url = config.queue_url.clone();
+    let live = LiveConfig::new(config);
+    relay_loop(&sqs_client(&[&receive, &delete]), &reqwest::Client::new(), &queue_url, &local_url, &live, &mut stats, &status).await;
+
+    assert_eq!((stats.forwarded, stats.deleted, stats.duplicates), (1, 1, 1));
+    assert_eq!(delete.num_calls(), 2);
+    assert_eq!(status.duplicates_suppressed.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn startup_wait_returns_once_local_answers() {
+    let server = MockServer::start().await;
+    Mock::given(method("HEAD")).respond_with(ResponseTemplate::new(404)).mount(&server).await;
+    let http = reqwest::Client::new();
+    assert!(wait_for_local(&http, &server.uri(), Duration::from_secs(5)).await);
+
+    // Nothing listens on the discard port
+    let started = std::time::Instant::now();
+    assert!(!wait_for_local(&http, "http://127.0.0.1:9", Duration::from_millis(600)).await);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn queue_depth_poller_publishes_gauges() {
+    let attributes = mock!(aws_sdk_sqs::Client::get_queue_attributes).then_output(|| {
+        GetQueueAttributesOutput::builder()
+            .attributes(QueueAttributeName::ApproximateNumberOfMessages, "42")
+            .attributes(QueueAttributeName::ApproximateNumberOfMessagesNotVisible, "7")
+            .build()
+    });
+    let status = Arc::new(RelayStatus::default());
+    let poller = tokio::spawn(poll_queue_depth(
+        sqs_client(&[&attributes]),
+        "https://sqs.test/queue".into(),
+        Duration::from_secs(60),
+        status.clone(),
+    ));
+    // The first tick fires immediately
+    while attributes.num_calls() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    poller.abort();
+    assert_eq!(status.queue_depth(), Some(QueueDepth { visible: 42, in_flight: 7, delayed: 0 }));
+}
+
+#[tokio::test]
+async fn scheduled_message_is_deferred_until_its_delivery_time() {
+    let clock = clock::MockClock::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let m = Message::builder()
+        .message_id("m-1")
+        .receipt_handle("receipt-1")
+        .body("{}")
+        .message_attributes("DeliverAfter", string_attr("1700000089.5"))
+        .build();
+    let hide = mock!(aws_sdk_sqs::Client::change_message_visibility)
+        .match_requests(|req| req.visibility_timeout() == Some(90))
+        .then_output(|| ChangeMessageVisibilityOutput::builder().build());
+    let sqs = sqs_client(&[&hide]);
+
+    let deliver_at = scheduled_delivery(&m, "deliverafter", &clock).unwrap();
+    let outcome = defer_scheduled(&sqs, "https://sqs.test/queue", &m, deliver_at, &clock).await;
+    assert_eq!(outcome.disposition, Disposition::Deferred);
+    assert_eq!(hide.num_calls(), 1);
+
+    // Due (or past) times forward normally
+    clock.advance(Duration::from_secs(90));
+    assert_eq!(scheduled_delivery(&m, "DeliverAfter", &clock), None);
+}
+
+/// Relays `message()` to an endpoint answering 404 (dropped) and returns the
+/// alerts that reached `NOTIFY_URL`.
+async fn drop_alerts(extra: &[(&str, &str)]) -> Vec<Value> {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(404)).expect(1).mount(&server).await;
+    let alerts = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/alerts")).respond_with(ResponseTemplate::new(200)).mount(&alerts).await;
+
+    let receive = mock!(aws_sdk_sqs::Client::receive_message)
+        .sequence()
+        .output(|| ReceiveMessageOutput::builder().messages(message()).build())
+        .output(|| ReceiveMessageOutput::builder().build())
+        .build();
+    let delete = delete_rule();
+    let notify_url = format!("{}/alerts", alerts.uri());
+    let mut pairs = vec![("QUEUE_URL", "https://sqs.test/queue"), ("DRAIN_AND_EXIT", "true"), ("NOTIFY_URL", notify_url.as_str())];
+    pairs.extend_from_slice(extra);
+    let config = Config::from_pairs(&pairs).unwrap();
+    let local_url = format!("{}/webhook", server.uri());
+    let queue_url = config.queue_url.clone();
+    let live = LiveConfig::new(config);
+    relay_loop(&sqs_client(&[&receive, &delete]), &reqwest::Client::new(), &queue_url, &local_url, &live, &mut RelayStats::default(), &RelayStatus::default()).await;
+    assert_eq!(delete.num_calls(), 1);
+
+    // Alerts are sent in the background
+    for _ in 0..50 {
+        let received = alerts.received_requests().await.unwrap_or_default();
+        if !received.is_empty() {
+            return received.iter().map(|r| serde_json::from_slice(&r.body).unwrap()).collect();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Vec::new()
+}
+
+#[tokio::test]
+async fn dropped_message_is_reported_to_notify_url() {
+    let alerts = drop_alerts(&[]).await;
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["message_id"], "m-1");
+    assert_eq!(alerts[0]["status"], 404);
+    assert_eq!(alerts[0]["queue_url"], "https://sqs.test/queue");
+}
+
+#[tokio::test]
+async fn batch_dropped_message_is_reported_to_notify_url() {
+    let alerts = drop_alerts(&[("BATCH_FORWARD", "true")]).await;
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["message_id"], "m-1");
+    assert_eq!(alerts[0]["disposition"], "deleted");
+}
+
+#[tokio::test]
+async fn fanout_takes_a_rate_limit_token_per_url() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(2).mount(&server).await;
+    let (first, second) = (format!("{}/a", server.uri()), format!("{}/b", server.uri()));
+    let config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("LOCAL_URL", &format!("{first},{second}")),
+        ("MAX_FORWARDS_PER_SEC", "2"),
+    ])
+    .unwrap();
+    // The clock never moves, so nothing refills what the POSTs take
+    let mut limiter = TokenBucket::new(2.0, Arc::new(clock::MockClock::at(UNIX_EPOCH)));
+
+    process_message(&reqwest::Client::new(), &first, &config, Some(&mut limiter), &message()).await.unwrap();
+    assert_eq!(limiter.tokens, 0.0);
+}
+
+#[cfg(feature = "kafka")]
+#[tokio::test]
+async fn kafka_sink_deletes_only_after_the_produce_ack() {
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::message::{Headers, Message as _};
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+
+    let cluster = MockCluster::new(1).unwrap();
+    cluster.create_topic("hooks", 1, 1).unwrap();
+    let brokers = cluster.bootstrap_servers();
+    let mut config = Config::from_pairs(&[
+        ("QUEUE_URL", "https://sqs.test/queue"),
+        ("SINK", "kafka"),
+        ("KAFKA_BROKERS", &brokers),
+        ("KAFKA_TOPIC", "hooks"),
+    ])
+    .unwrap();
+    config.kafka = Some(KafkaSink::new(&brokers, "hooks", config.kafka_ack_timeout).unwrap());
+    let delete = delete_rule();
+    let sqs = sqs_client(&[&delete]);
+    let m = message();
+
+    // A rejected produce leaves the message in the queue
+    cluster.request_errors(RDKafkaApiKey::Produce, &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE]);
+    let result = process_message(&reqwest::Client::new(), &config.local_url, &config, None, &m).await;
+    assert!(matches!(result, Err(RelayError::Produce(_))), "{result:?}");
+    let outcome = apply_outcome(&sqs, &config.queue_url, &config, &m, result).await.unwrap();
+    assert_eq!((outcome.disposition, delete.num_calls()), (Disposition::Retried, 0));
+
+    let result = process_message(&reqwest::Client::new(), &config.local_url, &config, None, &m).await;
+    let outcome = apply_outcome(&sqs, &config.queue_url, &config, &m, result).await.unwrap();
+    assert_eq!((outcome.disposition, delete.num_calls()), (Disposition::Deleted, 1));
+
+    let consumer: BaseConsumer = rdkafka::ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", "relay-test")
+        .create()
+        .unwrap();
+    let mut partitions = rdkafka::TopicPartitionList::new();
+    partitions.add_partition_offset("hooks", 0, rdkafka::Offset::Beginning).unwrap();
+    consumer.assign(&partitions).unwrap();
+    let record = std::iter::repeat_with(|| consumer.poll(Duration::from_millis(100)))
+        .take(100)
+        .find_map(|polled| polled.map(Result::unwrap))
+        .expect("no record produced");
+    assert_eq!(record.payload(), Some(BODY));
+    let headers = record.headers().unwrap();
+    let header = |name: &str| headers.iter().find(|h| h.key == name).and_then(|h| h.value);
+    assert_eq!(header("x-github-event"), Some(&b"push"[..]));
+    assert_eq!(header("x-sqs-receive-count"), Some(&b"1"[..]));
+}
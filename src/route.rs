@@ -0,0 +1,113 @@
+//! Per-destination overrides (`ROUTES`): each route names a URL prefix and the
+//! forward timeout, status policy and static headers used for URLs under it.
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::policy::StatusPolicy;
+
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    /// Forward URLs at or below this one use the route.
+    pub url: reqwest::Url,
+    /// Replaces `FORWARD_TIMEOUT_SECS` (and the large-body timeout).
+    pub timeout: Option<Duration>,
+    /// `status_policy` rules ahead of the built-in ones, replacing `STATUS_POLICY`.
+    pub status_policy: Option<StatusPolicy>,
+    /// Sent on every forward, replacing headers of the same name.
+    pub headers: HeaderMap,
+}
+
+impl RouteConfig {
+    /// One `ROUTES` entry:
+    /// `{"url": "...", "timeout_secs": 30, "status_policy": ["409=delete"], "headers": {"X-Team": "billing"}}`.
+    /// Only `url` is required; `delete_on_404` and `backoff` complete the status policy
+    /// as they do the global one.
+    pub fn parse(value: &Value, delete_on_404: bool, backoff: (Duration, Duration)) -> Result<Self, String> {
+        let entry = value.as_object().ok_or("expected an object")?;
+        if let Some(key) = entry.keys().find(|k| !["url", "timeout_secs", "status_policy", "headers"].contains(&k.as_str())) {
+            return Err(format!("unknown key '{key}'"));
+        }
+        let url = entry.get("url").and_then(Value::as_str).ok_or("missing url")?;
+        let url = reqwest::Url::parse(url).map_err(|e| format!("invalid url '{url}': {e}"))?;
+        let timeout = match entry.get("timeout_secs") {
+            None => None,
+            Some(secs) => Some(
+                secs.as_f64()
+                    .filter(|secs| *secs > 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or_else(|| format!("invalid timeout_secs {secs}"))?,
+            ),
+        };
+        let status_policy = match entry.get("status_policy") {
+            None => None,
+            Some(rules) => {
+                let rules = match rules {
+                    Value::String(s) => s.split(',').map(str::trim).filter(|r| !r.is_empty()).map(String::from).collect(),
+                    Value::Array(items) => items
+                        .iter()
+                        .map(|r| r.as_str().map(String::from).ok_or("status_policy rules must be strings"))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    _ => return Err("status_policy must be a string or a list of strings".into()),
+                };
+                Some(StatusPolicy::new(&rules, delete_on_404, backoff).map_err(|e| format!("invalid status_policy: {e}"))?)
+            }
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(map) = entry.get("headers") {
+            for (name, value) in map.as_object().ok_or("headers must be an object")? {
+                let value = value.as_str().ok_or_else(|| format!("header '{name}' must be a string"))?;
+                headers.insert(
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name '{name}'"))?,
+                    HeaderValue::from_str(value).map_err(|_| format!("invalid value for header '{name}'"))?,
+                );
+            }
+        }
+        Ok(Self { url, timeout, status_policy, headers })
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        reqwest::Url::parse(url).is_ok_and(|url| crate::target_allowed(&url, &self.url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Outcome;
+    use serde_json::json;
+
+    const BACKOFF: (Duration, Duration) = (Duration::from_secs(2), Duration::from_secs(60));
+
+    #[test]
+    fn parses_route_entries() {
+        let entry = json!({
+            "url": "http://127.0.0.1:4000/stripe",
+            "timeout_secs": 45,
+            "status_policy": "409=delete, 5xx=backoff",
+            "headers": {"X-Team": "billing"},
+        });
+        let route = RouteConfig::parse(&entry, true, BACKOFF).unwrap();
+        assert_eq!(route.timeout, Some(Duration::from_secs(45)));
+        assert_eq!(route.headers["x-team"], "billing");
+        let policy = route.status_policy.as_ref().unwrap();
+        assert_eq!(policy.decide(Some(409), None, 1), Outcome::Delete);
+        assert_eq!(policy.decide(Some(500), None, 1), Outcome::RetryBackoff(Duration::from_secs(2)));
+        assert!(route.matches("http://127.0.0.1:4000/stripe/events"));
+        assert!(!route.matches("http://127.0.0.1:4000/internal"));
+
+        let bare = RouteConfig::parse(&json!({"url": "http://internal/"}), true, BACKOFF).unwrap();
+        assert!(bare.timeout.is_none() && bare.status_policy.is_none() && bare.headers.is_empty());
+
+        for bad in [
+            json!({"timeout_secs": 5}),
+            json!({"url": "http://x/", "timeout_secs": 0}),
+            json!({"url": "http://x/", "status_policy": ["409=explode"]}),
+            json!({"url": "http://x/", "headers": {"bad name": "v"}}),
+            json!({"url": "http://x/", "retries": 3}),
+        ] {
+            assert!(RouteConfig::parse(&bad, true, BACKOFF).is_err(), "{bad}");
+        }
+    }
+}
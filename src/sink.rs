@@ -0,0 +1,316 @@
+//! Where relayed messages go (`SINK`): POSTed to `LOCAL_URL`, or produced to a
+//! Kafka topic with the `kafka` feature.
+
+use aws_sdk_sqs::types::Message;
+use colored::*;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::capture::CapturedRequest;
+use crate::config::{Config, FanoutPolicy, TimeoutAction};
+use crate::error::{ForwardErrorKind, RelayError};
+use crate::header_case;
+use crate::{colorize_status, log_decision, parse_retry_after, preview_str, queue_latency, with_query_attributes, Decision, Outcome, TokenBucket};
+
+/// A message ready to send: headers and body are final.
+pub struct Delivery<'a> {
+    pub message: &'a Message,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Which attempt this is, for status policies and logs.
+    pub attempt: u32,
+    /// Allowed `X-Relay-Target`, replacing the configured destination.
+    pub target: Option<reqwest::Url>,
+    /// `MAX_FORWARDS_PER_SEC`; a fan-out takes one token per URL it posts to.
+    pub limiter: Option<&'a mut TokenBucket>,
+}
+
+/// An output for relayed messages. It delivers one message and decides what
+/// should happen to it in SQS; [`crate::apply_outcome`] acts on that.
+pub trait Sink {
+    async fn deliver(&self, opts: &Config, delivery: Delivery<'_>) -> Result<Decision, RelayError>;
+}
+
+/// POSTs to `LOCAL_URL`, and to every fan-out URL with `FANOUT_POLICY` deciding.
+pub struct HttpSink<'a> {
+    pub http: &'a reqwest::Client,
+    pub local_url: &'a str,
+}
+
+impl Sink for HttpSink<'_> {
+    async fn deliver(&self, opts: &Config, delivery: Delivery<'_>) -> Result<Decision, RelayError> {
+        let Delivery { message: m, headers: hdrs, body: raw_bytes, attempt: receive_count, target, mut limiter } = delivery;
+        if let Some(target) = &target {
+            info!("🎯 Forwarding to X-Relay-Target {}", target);
+            take_token(&mut limiter).await;
+            return self.forward_to(target.as_str(), opts, m, hdrs, raw_bytes, receive_count).await;
+        }
+        if opts.fanout_urls.is_empty() {
+            take_token(&mut limiter).await;
+            return self.forward_to(self.local_url, opts, m, hdrs, raw_bytes, receive_count).await;
+        }
+        let mut results = Vec::new();
+        for url in std::iter::once(self.local_url).chain(opts.fanout_urls.iter().map(String::as_str)) {
+            take_token(&mut limiter).await;
+            let result = self.forward_to(url, opts, m, hdrs.clone(), raw_bytes.clone(), receive_count).await;
+            results.push((url, result));
+        }
+        let summary: Vec<String> = results
+            .iter()
+            .map(|(url, result)| match result {
+                Ok(decision) => format!("{} → {}", url, colorize_status(decision.status.unwrap_or_default())),
+                Err(e) => format!("{} → {}", url, e.to_string().red()),
+            })
+            .collect();
+        info!("{} Fan-out ({:?}): {}", "🔀".cyan(), opts.fanout_policy, summary.join(", "));
+        combine_fanout(opts.fanout_policy, results.into_iter().map(|(_, result)| result).collect())
+    }
+}
+
+/// Waits for a `MAX_FORWARDS_PER_SEC` token before a POST.
+async fn take_token(limiter: &mut Option<&mut TokenBucket>) {
+    if let Some(limiter) = limiter {
+        limiter.acquire().await;
+    }
+}
+
+impl HttpSink<'_> {
+    /// POSTs the prepared request to one target and decides what its answer means for the message.
+    async fn forward_to(
+        &self,
+        local_url: &str,
+        opts: &Config,
+        m: &Message,
+        mut hdrs: HeaderMap,
+        raw_bytes: Vec<u8>,
+        receive_count: u32,
+    ) -> Result<Decision, RelayError> {
+        let message_id = m.message_id().unwrap_or("unknown");
+        let route = opts.route_for(local_url);
+        if let Some(route) = route {
+            debug!("Forwarding with ROUTES entry for {}", route.url);
+            for (name, value) in &route.headers {
+                hdrs.insert(name, value.clone());
+            }
+        }
+        let target = with_query_attributes(local_url, m, &opts.query_attributes);
+        let local_url = target.as_ref();
+        let mut captured = opts.capture.as_ref()
+            .map(|_| CapturedRequest::new(message_id, local_url, &hdrs, &raw_bytes, &opts.redact_headers));
+        let timeout = route.and_then(|r| r.timeout).or_else(|| opts.forward_timeout_for(raw_bytes.len()));
+        if let Some(timeout) = timeout {
+            debug!("Using forward timeout of {:?}", timeout);
+        }
+        let span = info_span!("http.forward", url = local_url);
+        let started = opts.clock.instant();
+        let res = if opts.client.preserve_header_case {
+            let spellings = header_case::spellings(m, &opts.attr_headers);
+            let timeout = timeout.map_or(opts.client.timeout, |t| t.min(opts.client.timeout));
+            header_case::post(local_url, hdrs, raw_bytes, spellings, &opts.client.user_agent, timeout).instrument(span).await
+        } else {
+            let mut req = self.http.post(local_url).headers(hdrs);
+            if let Some(timeout) = timeout {
+                req = req.timeout(timeout);
+            }
+            req.body(raw_bytes).send().instrument(span).await.map_err(RelayError::from)
+        };
+        if let Ok(rsp) = &res {
+            tracing::Span::current().record("status", rsp.status().as_u16());
+        }
+        let elapsed = opts.clock.instant().duration_since(started);
+        if let (Err(e), Some(capture), Some(request)) = (&res, &opts.capture, captured.take()) {
+            capture.record(request, None, None, Some(&e.to_string()), opts.clock.as_ref());
+        }
+
+        match res {
+            Ok(rsp) => {
+                let status = rsp.status();
+                let status_code = status.as_u16();
+                let icon = if status.is_success() { "📤".green() } else { "📤".red() };
+                info!(
+                    queue_latency_ms = queue_latency(m, opts.clock.as_ref()).map(|d| d.as_millis() as u64),
+                    forward_latency_ms = elapsed.as_millis() as u64,
+                    "{} Local → Response: {} (attempt {})",
+                    icon,
+                    colorize_status(status_code),
+                    receive_count
+                );
+
+                debug!("Response headers: {:?}", rsp.headers().keys().collect::<Vec<_>>());
+                let retry_after = rsp.headers().get(RETRY_AFTER).and_then(|v| parse_retry_after(v, opts.clock.as_ref()));
+                let response_body = match rsp.text().await {
+                    Ok(response_body) => {
+                        let response_preview = preview_str(&response_body, opts.previews.response_len);
+                        if !response_preview.is_empty() {
+                            debug!("Response body: {}", response_preview);
+                        }
+                        response_body
+                    }
+                    Err(e) => {
+                        debug!("Could not read response body: {}", e);
+                        String::new()
+                    }
+                };
+                if let (Some(capture), Some(request)) = (&opts.capture, captured.take()) {
+                    capture.record(request, Some(status_code), Some(&preview_str(&response_body, 1024)), None, opts.clock.as_ref());
+                }
+
+                let policy = route.and_then(|r| r.status_policy.as_ref()).unwrap_or(&opts.status_policy);
+                let outcome = policy.decide(Some(status_code), retry_after, receive_count);
+                // Some handlers answer 2xx even when they failed; only their body tells
+                if let Some(rule) = opts.success_body.as_ref().filter(|_| outcome == Outcome::Delete) {
+                    if !rule.matches(response_body.as_bytes()) {
+                        warn!("{} {} but body fails SUCCESS_BODY_JSONPATH {} → Will retry (attempt {})", "🔄".yellow(), colorize_status(status_code), rule, receive_count);
+                        log_decision(message_id, Some(status_code), receive_count, Outcome::RetrySoon, "success body mismatch");
+                        return Ok(Decision { outcome: Outcome::RetrySoon, status: Some(status_code) });
+                    }
+                }
+                let status_str = colorize_status(status_code);
+                let reason = match (outcome, status_code) {
+                    (Outcome::Delete, _) => "delivered",
+                    (Outcome::RetryBackoff(delay), _) => {
+                        warn!("{} {} → Will retry after {}s (Retry-After, attempt {})", "⏳".yellow(), status_str, delay.as_secs(), receive_count);
+                        if retry_after.is_some() { "retry-after" } else { "backoff" }
+                    }
+                    (Outcome::DeadLetter, _) => {
+                        warn!("{} {} → Moving to DLQ (STATUS_POLICY, attempt {})", "🪦".red(), status_str, receive_count);
+                        "status policy"
+                    }
+                    (Outcome::Drop, 404) => {
+                        warn!("{} 404 → Deleting message (endpoint not found)", "🗑️".yellow());
+                        "endpoint not found"
+                    }
+                    (Outcome::Drop, _) => {
+                        warn!("{} {} → Deleting after retry (attempt {})", "🗑️".red(), status_str, receive_count);
+                        "retries exhausted"
+                    }
+                    (Outcome::RetrySoon, 404) => {
+                        warn!("{} 404 → Will retry (DELETE_ON_404=false, attempt {})", "🔄".yellow(), receive_count);
+                        "endpoint not found"
+                    }
+                    (Outcome::RetrySoon, 400..=499) => {
+                        warn!("{} {} → Will retry once (attempt {})", "🔄".yellow(), status_str, receive_count);
+                        "client error"
+                    }
+                    (Outcome::RetrySoon, 500..=599) => {
+                        warn!("{} {} → Will retry (server error, attempt {})", "🔄".red(), status_str, receive_count);
+                        "server error"
+                    }
+                    (Outcome::RetrySoon, _) => {
+                        warn!("{} {} → Will retry (unexpected status, attempt {})", "🔄".white(), status_str, receive_count);
+                        "unexpected status"
+                    }
+                };
+                log_decision(message_id, Some(status_code), receive_count, outcome, reason);
+                if status.is_server_error() && outcome == Outcome::RetrySoon {
+                    return Err(RelayError::Status(status));
+                }
+                Ok(Decision { outcome, status: Some(status_code) })
+            }
+            Err(e) => {
+                let kind = e.forward_error_kind().unwrap_or(ForwardErrorKind::Other);
+                match kind {
+                    ForwardErrorKind::Timeout if opts.timeout_action == TimeoutAction::Backoff => {
+                        warn!(error_kind = kind.label(), "{} Timed out after {}ms → Will retry after backoff (TIMEOUT_ACTION, attempt {}): {}", "⏱️".yellow(), elapsed.as_millis(), receive_count, e);
+                    }
+                    ForwardErrorKind::Timeout => {
+                        error!(error_kind = kind.label(), "{} Timed out after {}ms → Will retry (attempt {}): {}", "⏱️".red(), elapsed.as_millis(), receive_count, e);
+                    }
+                    ForwardErrorKind::Connect => {
+                        error!(error_kind = kind.label(), "{} Connection failed → Will retry (attempt {}): {}", "🔌".red(), receive_count, e);
+                    }
+                    _ => error!(error_kind = kind.label(), "{} Network error ({}) → Will retry (attempt {}): {}", "🌐".red(), kind.label(), receive_count, e),
+                }
+                log_decision(message_id, None, receive_count, Outcome::RetrySoon, kind.label());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Produces each message's body to `KAFKA_TOPIC`, with its headers (attributes
+/// and relay metadata) as Kafka headers. Only a produce-ack counts as delivered.
+#[cfg(feature = "kafka")]
+#[derive(Clone)]
+pub struct KafkaSink {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str, ack_timeout: std::time::Duration) -> anyhow::Result<Self> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            // Acked by every in-sync replica, so a deleted message can't be lost with its leader
+            .set("acks", "all")
+            .set("message.timeout.ms", ack_timeout.as_millis().to_string())
+            .create()?;
+        Ok(Self { producer, topic: topic.to_string() })
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl Sink for KafkaSink {
+    async fn deliver(&self, _opts: &Config, delivery: Delivery<'_>) -> Result<Decision, RelayError> {
+        use rdkafka::message::{Header, OwnedHeaders};
+
+        let Delivery { message: m, headers: hdrs, body, attempt, mut limiter, .. } = delivery;
+        let message_id = m.message_id().unwrap_or("unknown");
+        let headers = hdrs.iter().fold(OwnedHeaders::new_with_capacity(hdrs.len()), |headers, (name, value)| {
+            headers.insert(Header { key: name.as_str(), value: Some(value.as_bytes()) })
+        });
+        take_token(&mut limiter).await;
+        let record = rdkafka::producer::FutureRecord::<(), _>::to(&self.topic).payload(&body).headers(headers);
+        // The queue timeout only covers a full local queue; `message.timeout.ms` bounds the ack
+        match self.producer.send(record, rdkafka::util::Timeout::Never).await {
+            Ok(acked) => {
+                info!("{} Kafka → Acked: {}[{}]@{} (attempt {})", "📤".green(), self.topic, acked.partition, acked.offset, attempt);
+                log_decision(message_id, None, attempt, Outcome::Delete, "delivered");
+                Ok(Decision { outcome: Outcome::Delete, status: None })
+            }
+            Err((e, _)) => {
+                error!("{} Kafka produce to {} failed → Will retry (attempt {}): {}", "📤".red(), self.topic, attempt, e);
+                log_decision(message_id, None, attempt, Outcome::RetrySoon, "produce failed");
+                Err(RelayError::Produce(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Which fan-out results count as delivered when `LOCAL_URL` lists several targets.
+fn combine_fanout(policy: FanoutPolicy, mut results: Vec<Result<Decision, RelayError>>) -> Result<Decision, RelayError> {
+    let delivered = |r: &Result<Decision, RelayError>| matches!(r, Ok(d) if d.outcome == Outcome::Delete);
+    if policy == FanoutPolicy::Any {
+        if let Some(i) = results.iter().position(delivered) {
+            return results.swap_remove(i);
+        }
+    }
+    // Otherwise the least-delivered target decides: a failed forward, then a retry, then a drop
+    let rank = |r: &Result<Decision, RelayError>| match r {
+        Err(_) => 3,
+        Ok(Decision { outcome: Outcome::RetrySoon | Outcome::RetryBackoff(_), .. }) => 2,
+        Ok(Decision { outcome: Outcome::Drop | Outcome::DeadLetter, .. }) => 1,
+        Ok(Decision { outcome: Outcome::Delete, .. }) => 0,
+    };
+    let worst = results.iter().enumerate().max_by_key(|(_, r)| rank(r)).map(|(i, _)| i).unwrap_or_default();
+    results.swap_remove(worst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fanout_policies_combine_results() {
+        let ok = |outcome, status| Ok(Decision { outcome, status: Some(status) });
+        let results = || vec![ok(Outcome::Delete, 200), Err(RelayError::Status(reqwest::StatusCode::BAD_GATEWAY)), ok(Outcome::Drop, 400)];
+        assert!(combine_fanout(FanoutPolicy::All, results()).is_err());
+        assert_eq!(combine_fanout(FanoutPolicy::Any, results()).unwrap().status, Some(200));
+
+        let results = vec![ok(Outcome::Delete, 200), ok(Outcome::RetrySoon, 409), ok(Outcome::Drop, 400)];
+        assert_eq!(combine_fanout(FanoutPolicy::All, results).unwrap().outcome, Outcome::RetrySoon);
+        let results = vec![ok(Outcome::Delete, 200), ok(Outcome::Delete, 204)];
+        assert_eq!(combine_fanout(FanoutPolicy::All, results).unwrap().outcome, Outcome::Delete);
+    }
+}
@@ -0,0 +1,84 @@
+//! Optional check of 2xx response bodies before a message counts as delivered.
+
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::json_path;
+
+/// A `SUCCESS_BODY_JSONPATH` rule: `path==value`, `path!=value`, or a bare
+/// `path` that must be present and not `null`/`false`. Paths are dotted like
+/// `IP_JSON_PATHS`; values are JSON literals (`true`, `1`, `"ok"`) or bare strings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuccessBodyRule {
+    path: String,
+    check: Check,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Check {
+    Truthy,
+    Equals(Value),
+    NotEquals(Value),
+}
+
+impl FromStr for SuccessBodyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let literal = |v: &str| serde_json::from_str(v.trim()).unwrap_or_else(|_| Value::String(v.trim().into()));
+        let (path, check) = if let Some((path, value)) = s.split_once("!=") {
+            (path, Check::NotEquals(literal(value)))
+        } else if let Some((path, value)) = s.split_once("==") {
+            (path, Check::Equals(literal(value)))
+        } else {
+            (s, Check::Truthy)
+        };
+        let path = path.trim();
+        if path.is_empty() {
+            return Err("expected path, path==value or path!=value".into());
+        }
+        Ok(Self { path: path.into(), check })
+    }
+}
+
+impl std::fmt::Display for SuccessBodyRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.check {
+            Check::Truthy => write!(f, "{}", self.path),
+            Check::Equals(v) => write!(f, "{}=={}", self.path, v),
+            Check::NotEquals(v) => write!(f, "{}!={}", self.path, v),
+        }
+    }
+}
+
+impl SuccessBodyRule {
+    /// Whether the response body satisfies the rule; non-JSON bodies never do.
+    pub fn matches(&self, body: &[u8]) -> bool {
+        let Ok(json) = serde_json::from_slice::<Value>(body) else { return false; };
+        let value = json_path(&json, &self.path);
+        match &self.check {
+            Check::Truthy => !matches!(value, None | Some(Value::Null) | Some(Value::Bool(false))),
+            Check::Equals(expected) => value == Some(expected),
+            Check::NotEquals(expected) => value.is_some_and(|v| v != expected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_check_response_bodies() {
+        let rule = |s: &str| s.parse::<SuccessBodyRule>().unwrap();
+        assert!(rule("ok==true").matches(br#"{"ok":true}"#));
+        assert!(!rule("ok==true").matches(br#"{"ok":false}"#));
+        assert!(!rule("ok==true").matches(b"OK"));
+        assert!(rule("result.status==done").matches(br#"{"result":{"status":"done"}}"#));
+        assert!(rule("errors.0").matches(br#"{"errors":["x"]}"#));
+        assert!(!rule("ok").matches(br#"{"ok":null}"#));
+        assert!(rule("error!=true").matches(br#"{"error":false}"#));
+        assert!(!rule("error!=true").matches(b"{}"));
+        assert!("==true".parse::<SuccessBodyRule>().is_err());
+    }
+}
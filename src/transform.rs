@@ -0,0 +1,62 @@
+//! Optional jq-style reshaping of JSON bodies before they are forwarded.
+
+use anyhow::{anyhow, Result};
+use jaq_core::{
+    load::{Arena, File, Loader},
+    Compiler, Ctx, Filter, Native, RcIter,
+};
+use jaq_json::Val;
+
+/// A compiled `BODY_TRANSFORM` expression, e.g. `del(.secret) | .event = .action`.
+pub struct BodyTransform {
+    filter: Filter<Native<Val>>,
+}
+
+impl BodyTransform {
+    /// Parses and compiles the expression; invalid expressions are a startup error.
+    pub fn compile(expr: &str) -> Result<Self> {
+        let arena = Arena::default();
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let modules = loader
+            .load(&arena, File { code: expr, path: () })
+            .map_err(|errs| anyhow!("invalid BODY_TRANSFORM expression: {errs:?}"))?;
+        let filter = Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|errs| anyhow!("invalid BODY_TRANSFORM expression: {errs:?}"))?;
+        Ok(Self { filter })
+    }
+
+    /// Applies the transform to a body. Returns `None` when the body is not
+    /// UTF-8 JSON (it should pass through untouched), and an error when the
+    /// filter fails or does not produce exactly one value.
+    pub fn apply(&self, bytes: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        let input: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+        let inputs = RcIter::new(core::iter::empty());
+        let mut out = self.filter.run((Ctx::new([], &inputs), Val::from(input)));
+
+        let result = match (out.next(), out.next()) {
+            (Some(Ok(v)), None) => serde_json::to_vec(&serde_json::Value::from(v)).map_err(|e| e.to_string()),
+            (Some(Err(e)), _) => Err(e.to_string()),
+            (None, _) => Err("transform produced no output".into()),
+            (Some(Ok(_)), Some(_)) => Err("transform produced more than one output".into()),
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_json_and_passes_through_other_bodies() {
+        let t = BodyTransform::compile("del(.secret) | .renamed = .old | del(.old)").unwrap();
+        let out = t.apply(br#"{"secret":"x","old":1}"#).unwrap().unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&out).unwrap(), serde_json::json!({"renamed": 1}));
+
+        assert!(t.apply(b"not json").is_none());
+        assert!(BodyTransform::compile(".[]").unwrap().apply(b"[1,2]").unwrap().is_err());
+        assert!(BodyTransform::compile("del(").is_err());
+    }
+}